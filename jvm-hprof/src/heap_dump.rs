@@ -324,10 +324,10 @@ pub struct Class<'a> {
     protection_domain_obj_id: Option<Id>,
     #[get_copy = "pub"]
     instance_size_bytes: u32,
-    num_static_fields: u16,
-    static_fields: &'a [u8],
-    num_instance_fields: u16,
-    instance_fields: &'a [u8],
+    pub(crate) num_static_fields: u16,
+    pub(crate) static_fields: &'a [u8],
+    pub(crate) num_instance_fields: u16,
+    pub(crate) instance_fields: &'a [u8],
 }
 
 impl<'a> Class<'a> {
@@ -532,8 +532,9 @@ pub struct ObjectArray<'a> {
     /// The obj id of the class that this is an array of
     #[get_copy = "pub"]
     array_class_obj_id: Id,
+    #[get_copy = "pub"]
     num_elements: u32,
-    contents: &'a [u8],
+    pub(crate) contents: &'a [u8],
 }
 
 impl<'a> ObjectArray<'a> {
@@ -614,6 +615,23 @@ pub enum FieldValue {
     Long(i64),
 }
 
+impl FieldValue {
+    /// The type of the value, e.g. to describe a static field
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            FieldValue::ObjectId(_) => FieldType::ObjectId,
+            FieldValue::Boolean(_) => FieldType::Boolean,
+            FieldValue::Char(_) => FieldType::Char,
+            FieldValue::Float(_) => FieldType::Float,
+            FieldValue::Double(_) => FieldType::Double,
+            FieldValue::Byte(_) => FieldType::Byte,
+            FieldValue::Short(_) => FieldType::Short,
+            FieldValue::Int(_) => FieldType::Int,
+            FieldValue::Long(_) => FieldType::Long,
+        }
+    }
+}
+
 /// The name and type of an instance field.
 #[derive(CopyGetters, Clone, Copy, Debug)]
 pub struct FieldDescriptor {
@@ -697,6 +715,23 @@ impl FieldType {
         }
     }
 
+    /// Returns the hprof type code for the field type
+    ///
+    /// See https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L709
+    pub fn type_code(&self) -> u8 {
+        match self {
+            FieldType::ObjectId => 0x02,
+            FieldType::Boolean => 0x04,
+            FieldType::Char => 0x05,
+            FieldType::Float => 0x06,
+            FieldType::Double => 0x07,
+            FieldType::Byte => 0x08,
+            FieldType::Short => 0x09,
+            FieldType::Int => 0x0A,
+            FieldType::Long => 0x0B,
+        }
+    }
+
     pub fn java_type_name(&self) -> &'static str {
         match self {
             FieldType::ObjectId => "Object",
@@ -19,8 +19,9 @@ pub struct PrimitiveArray<'a> {
     /// [PrimitiveArray::floats()] will return `Some` and all other accessors will return `None`.
     #[get_copy = "pub"]
     primitive_type: PrimitiveArrayType,
+    #[get_copy = "pub"]
     num_elements: u32,
-    contents: &'a [u8],
+    pub(crate) contents: &'a [u8],
 }
 
 macro_rules! iterator_method {
@@ -50,18 +51,7 @@ impl<'a> PrimitiveArray<'a> {
             None => panic!("Unexpected primitive array type {:#X}", type_byte),
         };
 
-        let size: u32 = match array_type {
-            PrimitiveArrayType::Boolean => 1,
-            PrimitiveArrayType::Char => 2,
-            PrimitiveArrayType::Float => 4,
-            PrimitiveArrayType::Double => 8,
-            PrimitiveArrayType::Byte => 1,
-            PrimitiveArrayType::Short => 2,
-            PrimitiveArrayType::Int => 4,
-            PrimitiveArrayType::Long => 8,
-        };
-
-        let (input, contents) = bytes::take(num_elements * size)(input)?;
+        let (input, contents) = bytes::take(num_elements * array_type.size_in_bytes())(input)?;
 
         Ok((
             input,
@@ -160,6 +150,20 @@ impl PrimitiveArrayType {
         }
     }
 
+    /// Size of an element in bytes
+    pub fn size_in_bytes(&self) -> u32 {
+        match self {
+            PrimitiveArrayType::Boolean => 1,
+            PrimitiveArrayType::Char => 2,
+            PrimitiveArrayType::Float => 4,
+            PrimitiveArrayType::Double => 8,
+            PrimitiveArrayType::Byte => 1,
+            PrimitiveArrayType::Short => 2,
+            PrimitiveArrayType::Int => 4,
+            PrimitiveArrayType::Long => 8,
+        }
+    }
+
     /// Returns the hprof type code for the array type
     ///
     /// See https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L279
@@ -53,8 +53,10 @@ use strum_macros::EnumIter;
 
 pub mod heap_dump;
 mod parsing_iterator;
+mod writer;
 
 use parsing_iterator::*;
+pub use writer::{HeapDumpSegmentWriter, HprofWriter};
 
 /// Ids are used to identify many things in an hprof file: objects, classes, utf8 blobs, etc.
 ///
@@ -137,7 +139,7 @@ pub enum IdSize {
 }
 
 impl IdSize {
-    fn size_in_bytes(&self) -> usize {
+    pub(crate) fn size_in_bytes(&self) -> usize {
         match self {
             IdSize::U32 => 4,
             IdSize::U64 => 8,
@@ -320,6 +322,12 @@ pub struct Record<'a> {
 }
 
 impl<'a> Record<'a> {
+    /// The unparsed body of the record, e.g. to copy it unchanged with
+    /// [HprofWriter::write_record].
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+
     /// Returns `Some` if the tag is [RecordTag::Utf8] and `None` otherwise.
     pub fn as_utf_8(&self) -> Option<ParseResult<Utf8<'a>>> {
         match self.tag {
@@ -367,22 +375,9 @@ impl<'a> Record<'a> {
         // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L76
         let (input, tag_byte) = bytes::take(1_usize)(input)?;
 
-        let tag = match tag_byte[0] {
-            0x01 => RecordTag::Utf8,
-            0x02 => RecordTag::LoadClass,
-            0x03 => RecordTag::UnloadClass,
-            0x04 => RecordTag::StackFrame,
-            0x05 => RecordTag::StackTrace,
-            0x06 => RecordTag::AllocSites,
-            0x07 => RecordTag::HeapSummary,
-            0x0A => RecordTag::StartThread,
-            0x0B => RecordTag::EndThread,
-            0x0C => RecordTag::HeapDump,
-            0x0D => RecordTag::CpuSamples,
-            0x0E => RecordTag::ControlSettings,
-            0x1C => RecordTag::HeapDumpSegment,
-            0x2C => RecordTag::HeapDumpEnd,
-            _ => panic!("unexpected tag: {:#X?}", tag_byte[0]),
+        let tag = match RecordTag::from_tag_byte(tag_byte[0]) {
+            Some(tag) => tag,
+            None => panic!("unexpected tag: {:#X?}", tag_byte[0]),
         };
 
         let (input, micros) = number::be_u32(input)?;
@@ -449,7 +444,30 @@ pub enum RecordTag {
 }
 
 impl RecordTag {
-    fn tag_byte(&self) -> u8 {
+    /// The tag with the given byte in the record header, `None` for bytes that are not a tag,
+    /// e.g. in a corrupted file
+    pub fn from_tag_byte(tag_byte: u8) -> Option<RecordTag> {
+        let tag = match tag_byte {
+            0x01 => RecordTag::Utf8,
+            0x02 => RecordTag::LoadClass,
+            0x03 => RecordTag::UnloadClass,
+            0x04 => RecordTag::StackFrame,
+            0x05 => RecordTag::StackTrace,
+            0x06 => RecordTag::AllocSites,
+            0x07 => RecordTag::HeapSummary,
+            0x0A => RecordTag::StartThread,
+            0x0B => RecordTag::EndThread,
+            0x0C => RecordTag::HeapDump,
+            0x0D => RecordTag::CpuSamples,
+            0x0E => RecordTag::ControlSettings,
+            0x1C => RecordTag::HeapDumpSegment,
+            0x2C => RecordTag::HeapDumpEnd,
+            _ => return None,
+        };
+        Some(tag)
+    }
+
+    pub(crate) fn tag_byte(&self) -> u8 {
         match self {
             RecordTag::Utf8 => 0x01,
             RecordTag::LoadClass => 0x02,
@@ -584,8 +602,8 @@ pub struct StackTrace<'a> {
     stack_trace_serial: Serial,
     #[get_copy = "pub"]
     thread_serial: Serial,
-    num_frame_ids: u32,
-    frame_ids: &'a [u8],
+    pub(crate) num_frame_ids: u32,
+    pub(crate) frame_ids: &'a [u8],
 }
 
 impl<'a> StackTrace<'a> {
@@ -685,6 +703,14 @@ pub struct SubRecords<'a> {
     remaining: &'a [u8],
 }
 
+impl<'a> SubRecords<'a> {
+    /// Pairs each sub record with the bytes it was parsed from, tag included, e.g. to copy it
+    /// unchanged with [HeapDumpSegmentWriter::write_raw].
+    pub fn with_bytes(self) -> SubRecordsWithBytes<'a> {
+        SubRecordsWithBytes { sub_records: self }
+    }
+}
+
 impl<'a> Iterator for SubRecords<'a> {
     type Item = ParseResult<'a, heap_dump::SubRecord<'a>>;
 
@@ -708,6 +734,25 @@ impl<'a> Iterator for SubRecords<'a> {
     }
 }
 
+/// Iterator over [heap_dump::SubRecord] data along with the bytes of each sub record.
+pub struct SubRecordsWithBytes<'a> {
+    sub_records: SubRecords<'a>,
+}
+
+impl<'a> Iterator for SubRecordsWithBytes<'a> {
+    type Item = ParseResult<'a, (heap_dump::SubRecord<'a>, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let before = self.sub_records.remaining;
+        self.sub_records.next().map(|res| {
+            res.map(|record| {
+                let len = before.len() - self.sub_records.remaining.len();
+                (record, &before[..len])
+            })
+        })
+    }
+}
+
 // TODO referenced in heapDumper.cpp, but not actually written?
 #[allow(unused)]
 struct CpuSamples {
@@ -0,0 +1,1111 @@
+//! Serialization of hprof data, the counterpart of [crate::parse_hprof].
+//!
+//! Records are written as they come, nothing is buffered beyond what the underlying writer does.
+//! Since records are length prefixed, heap dump segments are written through a
+//! [HeapDumpSegmentWriter] that is either given the body length upfront and checks it when
+//! finished, or buffers the sub records until finished.
+//!
+//! Everything the parser reads can be written back: records parsed with the `as_*` methods of
+//! [Record] have matching `write_*` methods, and [HeapDumpSegmentWriter::write_sub_record] writes
+//! any parsed [heap_dump::SubRecord].
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::heap_dump::{FieldType, FieldValue, PrimitiveArrayType, SubRecord};
+use crate::*;
+
+/// Writes an hprof file: the header on creation, then records.
+///
+/// # Examples
+///
+/// Copying an hprof, replacing the text of every [Utf8] record:
+///
+/// ```
+/// use jvm_hprof::{parse_hprof, HprofWriter, RecordTag};
+///
+/// fn copy(input: &[u8]) -> std::io::Result<Vec<u8>> {
+///     let hprof = parse_hprof(input).unwrap();
+///     let header = hprof.header();
+///     let mut writer = HprofWriter::new(
+///         Vec::new(),
+///         header.label().unwrap(),
+///         header.id_size(),
+///         header.timestamp_millis(),
+///     )?;
+///     for record in hprof.records_iter() {
+///         let record = record.unwrap();
+///         match record.as_utf_8() {
+///             Some(utf8) => writer.write_utf8(
+///                 record.micros_since_header_ts(),
+///                 utf8.unwrap().name_id(),
+///                 b"?",
+///             )?,
+///             None => writer.write_record(
+///                 record.tag(),
+///                 record.micros_since_header_ts(),
+///                 record.body(),
+///             )?,
+///         }
+///     }
+///     writer.into_inner()
+/// }
+/// ```
+///
+/// Building a small heap, e.g. as a test fixture:
+///
+/// ```
+/// use jvm_hprof::heap_dump::{FieldType, FieldValue};
+/// use jvm_hprof::{HprofWriter, IdSize, LineNum, RecordTag};
+///
+/// fn fixture() -> std::io::Result<Vec<u8>> {
+///     let mut writer = HprofWriter::new(Vec::new(), "JAVA PROFILE 1.0.2", IdSize::U64, 0)?;
+///     writer.write_utf8(0, 1.into(), b"Point")?;
+///     writer.write_utf8(0, 2.into(), b"x")?;
+///     writer.write_load_class(0, 1.into(), 100.into(), 0.into(), 1.into())?;
+///     writer.write_stack_trace(0, 0.into(), 0.into(), &[])?;
+///
+///     let mut segment = writer.buffered_heap_dump_segment(RecordTag::HeapDumpSegment, 0)?;
+///     let fields = [(2.into(), FieldType::Int)];
+///     segment.write_class(100.into(), 0.into(), None, None, None, None, 4, &[], &fields)?;
+///     segment.write_instance(200.into(), 0.into(), 100.into(), &[FieldValue::Int(7)])?;
+///     segment.write_gc_root_system_class(100.into())?;
+///     segment.finish()?;
+///
+///     writer.write_heap_dump_end(0)?;
+///     writer.into_inner()
+/// }
+/// ```
+pub struct HprofWriter<W: Write> {
+    out: W,
+    id_size: IdSize,
+}
+
+impl<W: Write> HprofWriter<W> {
+    /// Writes the header, `label` is usually `JAVA PROFILE 1.0.2`
+    pub fn new(
+        mut out: W,
+        label: &str,
+        id_size: IdSize,
+        timestamp_millis: u64,
+    ) -> io::Result<Self> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L63
+        out.write_all(label.as_bytes())?;
+        out.write_all(&[0])?;
+        out.write_all(&(id_size.size_in_bytes() as u32).to_be_bytes())?;
+        out.write_all(&((timestamp_millis >> 32) as u32).to_be_bytes())?;
+        out.write_all(&(timestamp_millis as u32).to_be_bytes())?;
+
+        Ok(HprofWriter { out, id_size })
+    }
+
+    pub fn id_size(&self) -> IdSize {
+        self.id_size
+    }
+
+    /// Writes a record with an already serialized body, e.g. one read with [Record::body]
+    pub fn write_record(&mut self, tag: RecordTag, micros: u32, body: &[u8]) -> io::Result<()> {
+        self.write_record_header(tag, micros, body_len(body.len())?)?;
+        self.out.write_all(body)
+    }
+
+    /// Writes a [RecordTag::Utf8] record
+    pub fn write_utf8(&mut self, micros: u32, name_id: Id, text: &[u8]) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L88
+        let len = body_len(self.id_size.size_in_bytes() + text.len())?;
+        self.write_record_header(RecordTag::Utf8, micros, len)?;
+        write_id(&mut self.out, self.id_size, Some(name_id))?;
+        self.out.write_all(text)
+    }
+
+    /// Writes a [RecordTag::LoadClass] record
+    pub fn write_load_class(
+        &mut self,
+        micros: u32,
+        class_serial: Serial,
+        class_obj_id: Id,
+        stack_trace_serial: Serial,
+        class_name_id: Id,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L93
+        let mut body = Encoder::new(self.id_size);
+        body.u32(class_serial.num());
+        body.id(Some(class_obj_id));
+        body.u32(stack_trace_serial.num());
+        body.id(Some(class_name_id));
+        self.write_record(RecordTag::LoadClass, micros, &body.bytes)
+    }
+
+    /// Writes a [RecordTag::StackFrame] record
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_stack_frame(
+        &mut self,
+        micros: u32,
+        id: Id,
+        method_name_id: Id,
+        method_signature_id: Id,
+        source_file_name_id: Id,
+        class_serial: Serial,
+        line_num: LineNum,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L104
+        let mut body = Encoder::new(self.id_size);
+        body.id(Some(id));
+        body.id(Some(method_name_id));
+        body.id(Some(method_signature_id));
+        body.id(Some(source_file_name_id));
+        body.u32(class_serial.num());
+        body.bytes.extend_from_slice(
+            &match line_num {
+                LineNum::Normal(num) => num as i32,
+                LineNum::Unknown => -1,
+                LineNum::CompiledMethod => -2,
+                LineNum::NativeMethod => -3,
+            }
+            .to_be_bytes(),
+        );
+        self.write_record(RecordTag::StackFrame, micros, &body.bytes)
+    }
+
+    /// Writes a [RecordTag::StackTrace] record, `frame_ids` refer to [StackFrame] records
+    pub fn write_stack_trace(
+        &mut self,
+        micros: u32,
+        stack_trace_serial: Serial,
+        thread_serial: Serial,
+        frame_ids: &[Id],
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L116
+        let mut body = Encoder::new(self.id_size);
+        body.u32(stack_trace_serial.num());
+        body.u32(thread_serial.num());
+        body.u32(body_len(frame_ids.len())?);
+        for id in frame_ids {
+            body.id(Some(*id));
+        }
+        self.write_record(RecordTag::StackTrace, micros, &body.bytes)
+    }
+
+    /// Writes the [RecordTag::HeapDumpEnd] record following the last heap dump segment
+    pub fn write_heap_dump_end(&mut self, micros: u32) -> io::Result<()> {
+        self.write_record(RecordTag::HeapDumpEnd, micros, &[])
+    }
+
+    /// Starts a [RecordTag::HeapDump] or [RecordTag::HeapDumpSegment] record whose sub records
+    /// add up to `body_len` bytes.
+    pub fn heap_dump_segment(
+        &mut self,
+        tag: RecordTag,
+        micros: u32,
+        body_len: u32,
+    ) -> io::Result<HeapDumpSegmentWriter<'_, W>> {
+        check_heap_dump_tag(tag)?;
+        self.write_record_header(tag, micros, body_len)?;
+
+        Ok(HeapDumpSegmentWriter {
+            writer: self,
+            body: SegmentBody::Sized {
+                remaining: body_len,
+            },
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Starts a [RecordTag::HeapDump] or [RecordTag::HeapDumpSegment] record whose length is not
+    /// known upfront. Sub records are kept in memory and written when the segment is finished.
+    pub fn buffered_heap_dump_segment(
+        &mut self,
+        tag: RecordTag,
+        micros: u32,
+    ) -> io::Result<HeapDumpSegmentWriter<'_, W>> {
+        check_heap_dump_tag(tag)?;
+
+        Ok(HeapDumpSegmentWriter {
+            writer: self,
+            body: SegmentBody::Buffered {
+                tag,
+                micros,
+                buffer: Vec::new(),
+            },
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Flushes and returns the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_record_header(&mut self, tag: RecordTag, micros: u32, len: u32) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L76
+        self.out.write_all(&[tag.tag_byte()])?;
+        self.out.write_all(&micros.to_be_bytes())?;
+        self.out.write_all(&len.to_be_bytes())
+    }
+}
+
+/// Writes the sub records of a heap dump record started with [HprofWriter::heap_dump_segment] or
+/// [HprofWriter::buffered_heap_dump_segment].
+///
+/// The record is incomplete until [HeapDumpSegmentWriter::finish] is called.
+pub struct HeapDumpSegmentWriter<'w, W: Write> {
+    writer: &'w mut HprofWriter<W>,
+    body: SegmentBody,
+    /// Reused to serialize one sub record at a time
+    scratch: Vec<u8>,
+}
+
+enum SegmentBody {
+    /// The record header is written, the sub records must add up to its length
+    Sized { remaining: u32 },
+    /// The record header is written along with the sub records when finished
+    Buffered {
+        tag: RecordTag,
+        micros: u32,
+        buffer: Vec<u8>,
+    },
+}
+
+impl<'w, W: Write> HeapDumpSegmentWriter<'w, W> {
+    /// Writes an already serialized sub record, tag included, e.g. one read with
+    /// [SubRecords::with_bytes]
+    pub fn write_raw(&mut self, sub_record: &[u8]) -> io::Result<()> {
+        match &mut self.body {
+            SegmentBody::Sized { remaining } => {
+                *remaining = u32::try_from(sub_record.len())
+                    .ok()
+                    .and_then(|len| remaining.checked_sub(len))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Sub record of {} bytes exceeds the {} bytes left in the heap dump record",
+                                sub_record.len(),
+                                remaining
+                            ),
+                        )
+                    })?;
+                self.writer.out.write_all(sub_record)
+            }
+            SegmentBody::Buffered { buffer, .. } => {
+                buffer.extend_from_slice(sub_record);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes a parsed sub record
+    pub fn write_sub_record(&mut self, sub_record: &SubRecord) -> io::Result<()> {
+        match sub_record {
+            SubRecord::GcRootUnknown(root) => self.write_gc_root_unknown(root.obj_id()),
+            SubRecord::GcRootThreadObj(root) => self.write_gc_root_thread_obj(
+                root.thread_obj_id(),
+                root.thread_serial(),
+                root.stack_trace_serial(),
+            ),
+            SubRecord::GcRootJniGlobal(root) => {
+                self.write_gc_root_jni_global(root.obj_id(), root.jni_global_ref_id())
+            }
+            SubRecord::GcRootJniLocalRef(root) => self.write_gc_root_jni_local_ref(
+                root.obj_id(),
+                root.thread_serial(),
+                root.frame_index(),
+            ),
+            SubRecord::GcRootJavaStackFrame(root) => self.write_gc_root_java_stack_frame(
+                root.obj_id(),
+                root.thread_serial(),
+                root.frame_index(),
+            ),
+            SubRecord::GcRootNativeStack(root) => {
+                self.write_gc_root_native_stack(root.obj_id(), root.thread_serial())
+            }
+            SubRecord::GcRootSystemClass(root) => self.write_gc_root_system_class(root.obj_id()),
+            SubRecord::GcRootThreadBlock(root) => {
+                self.write_gc_root_thread_block(root.obj_id(), root.thread_serial())
+            }
+            SubRecord::GcRootBusyMonitor(root) => self.write_gc_root_busy_monitor(root.obj_id()),
+            SubRecord::Class(class) => self.write_serialized(|out| {
+                out.class_header(
+                    class.obj_id(),
+                    class.stack_trace_serial(),
+                    [
+                        class.super_class_obj_id(),
+                        class.class_loader_obj_id(),
+                        class.signers_obj_id(),
+                        class.protection_domain_obj_id(),
+                    ],
+                    class.instance_size_bytes(),
+                );
+                out.u16(class.num_static_fields);
+                out.bytes.extend_from_slice(class.static_fields);
+                out.u16(class.num_instance_fields);
+                out.bytes.extend_from_slice(class.instance_fields);
+            }),
+            SubRecord::Instance(instance) => self.write_instance_fields(
+                instance.obj_id(),
+                instance.stack_trace_serial(),
+                instance.class_obj_id(),
+                instance.fields(),
+            ),
+            SubRecord::ObjectArray(array) => self.write_serialized(|out| {
+                out.object_array_header(
+                    array.obj_id(),
+                    array.stack_trace_serial(),
+                    array.num_elements(),
+                    array.array_class_obj_id(),
+                );
+                out.bytes.extend_from_slice(array.contents);
+            }),
+            SubRecord::PrimitiveArray(array) => self.write_primitive_array(
+                array.obj_id(),
+                array.stack_trace_serial(),
+                array.primitive_type(),
+                array.contents,
+            ),
+        }
+    }
+
+    /// Writes a [heap_dump::GcRootUnknown] sub record
+    pub fn write_gc_root_unknown(&mut self, obj_id: Id) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L180
+        self.write_serialized(|out| {
+            out.u8(0xFF);
+            out.id(Some(obj_id));
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootThreadObj] sub record
+    pub fn write_gc_root_thread_obj(
+        &mut self,
+        thread_obj_id: Option<Id>,
+        thread_serial: Serial,
+        stack_trace_serial: Serial,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L184
+        self.write_serialized(|out| {
+            out.u8(0x08);
+            out.id(thread_obj_id);
+            out.u32(thread_serial.num());
+            out.u32(stack_trace_serial.num());
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootJniGlobal] sub record
+    pub fn write_gc_root_jni_global(
+        &mut self,
+        obj_id: Id,
+        jni_global_ref_id: Id,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L191
+        self.write_serialized(|out| {
+            out.u8(0x01);
+            out.id(Some(obj_id));
+            out.id(Some(jni_global_ref_id));
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootJniLocalRef] sub record, a missing `frame_index` is written as
+    /// `-1`
+    pub fn write_gc_root_jni_local_ref(
+        &mut self,
+        obj_id: Id,
+        thread_serial: Serial,
+        frame_index: Option<u32>,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L196
+        self.write_serialized(|out| {
+            out.u8(0x02);
+            out.id(Some(obj_id));
+            out.u32(thread_serial.num());
+            out.u32(frame_index.unwrap_or(u32::MAX));
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootJavaStackFrame] sub record, a missing `frame_index` is written
+    /// as `-1`
+    pub fn write_gc_root_java_stack_frame(
+        &mut self,
+        obj_id: Id,
+        thread_serial: Serial,
+        frame_index: Option<u32>,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L202
+        self.write_serialized(|out| {
+            out.u8(0x03);
+            out.id(Some(obj_id));
+            out.u32(thread_serial.num());
+            out.u32(frame_index.unwrap_or(u32::MAX));
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootNativeStack] sub record
+    pub fn write_gc_root_native_stack(
+        &mut self,
+        obj_id: Id,
+        thread_serial: Serial,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L208
+        self.write_serialized(|out| {
+            out.u8(0x04);
+            out.id(Some(obj_id));
+            out.u32(thread_serial.num());
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootSystemClass] sub record
+    pub fn write_gc_root_system_class(&mut self, obj_id: Id) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L213
+        self.write_serialized(|out| {
+            out.u8(0x05);
+            out.id(Some(obj_id));
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootThreadBlock] sub record
+    pub fn write_gc_root_thread_block(
+        &mut self,
+        obj_id: Id,
+        thread_serial: Serial,
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L217
+        self.write_serialized(|out| {
+            out.u8(0x06);
+            out.id(Some(obj_id));
+            out.u32(thread_serial.num());
+        })
+    }
+
+    /// Writes a [heap_dump::GcRootBusyMonitor] sub record
+    pub fn write_gc_root_busy_monitor(&mut self, obj_id: Id) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L222
+        self.write_serialized(|out| {
+            out.u8(0x07);
+            out.id(Some(obj_id));
+        })
+    }
+
+    /// Writes a [heap_dump::Class] sub record.
+    ///
+    /// `static_fields` are name ids with values, `instance_fields` name ids with types of the
+    /// fields declared by the class itself, superclass fields belong to the superclass.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_class(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        super_class_obj_id: Option<Id>,
+        class_loader_obj_id: Option<Id>,
+        signers_obj_id: Option<Id>,
+        protection_domain_obj_id: Option<Id>,
+        instance_size_bytes: u32,
+        static_fields: &[(Id, FieldValue)],
+        instance_fields: &[(Id, FieldType)],
+    ) -> io::Result<()> {
+        let num_static_fields = field_count(static_fields.len())?;
+        let num_instance_fields = field_count(instance_fields.len())?;
+        self.write_serialized(|out| {
+            out.class_header(
+                obj_id,
+                stack_trace_serial,
+                [
+                    super_class_obj_id,
+                    class_loader_obj_id,
+                    signers_obj_id,
+                    protection_domain_obj_id,
+                ],
+                instance_size_bytes,
+            );
+            out.u16(num_static_fields);
+            for (name_id, value) in static_fields {
+                out.id(Some(*name_id));
+                out.u8(value.field_type().type_code());
+                out.field_value(value);
+            }
+            // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L964
+            out.u16(num_instance_fields);
+            for (name_id, field_type) in instance_fields {
+                out.id(Some(*name_id));
+                out.u8(field_type.type_code());
+            }
+        })
+    }
+
+    /// Writes a [heap_dump::Instance] sub record, `fields` are the values of the class's instance
+    /// fields followed by those of its superclasses
+    pub fn write_instance(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        class_obj_id: Id,
+        fields: &[FieldValue],
+    ) -> io::Result<()> {
+        let mut encoded = Encoder::new(self.writer.id_size);
+        for value in fields {
+            encoded.field_value(value);
+        }
+        self.write_instance_fields(obj_id, stack_trace_serial, class_obj_id, &encoded.bytes)
+    }
+
+    /// Writes a [heap_dump::Instance] sub record with already serialized field values, e.g. from
+    /// [heap_dump::Instance::fields]
+    pub fn write_instance_fields(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        class_obj_id: Id,
+        fields: &[u8],
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L262
+        let fields_len = body_len(fields.len())?;
+        self.write_serialized(|out| {
+            out.u8(0x21);
+            out.id(Some(obj_id));
+            out.u32(stack_trace_serial.num());
+            out.id(Some(class_obj_id));
+            out.u32(fields_len);
+            out.bytes.extend_from_slice(fields);
+        })
+    }
+
+    /// Writes a [heap_dump::ObjectArray] sub record, `None` elements are null
+    pub fn write_object_array(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        array_class_obj_id: Id,
+        elements: &[Option<Id>],
+    ) -> io::Result<()> {
+        let num_elements = body_len(elements.len())?;
+        self.write_serialized(|out| {
+            out.object_array_header(obj_id, stack_trace_serial, num_elements, array_class_obj_id);
+            for element in elements {
+                out.id(*element);
+            }
+        })
+    }
+
+    /// Writes a [heap_dump::PrimitiveArray] sub record, `contents` holds the big endian elements
+    pub fn write_primitive_array(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        primitive_type: PrimitiveArrayType,
+        contents: &[u8],
+    ) -> io::Result<()> {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L279
+        let element_size = primitive_type.size_in_bytes() as usize;
+        if contents.len() % element_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes are not a whole number of {} elements",
+                    contents.len(),
+                    primitive_type.java_type_name()
+                ),
+            ));
+        }
+        let num_elements = body_len(contents.len() / element_size)?;
+        self.write_serialized(|out| {
+            out.u8(0x23);
+            out.id(Some(obj_id));
+            out.u32(stack_trace_serial.num());
+            out.u32(num_elements);
+            out.u8(primitive_type.type_code());
+            out.bytes.extend_from_slice(contents);
+        })
+    }
+
+    /// Ends the record: checks that the sub records added up to the length the record was started
+    /// with, or writes the buffered record
+    pub fn finish(self) -> io::Result<()> {
+        match self.body {
+            SegmentBody::Sized { remaining: 0 } => Ok(()),
+            SegmentBody::Sized { remaining } => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Heap dump record is {} bytes short", remaining),
+            )),
+            SegmentBody::Buffered {
+                tag,
+                micros,
+                buffer,
+            } => self.writer.write_record(tag, micros, &buffer),
+        }
+    }
+
+    /// Serializes a sub record into the scratch buffer, then writes it
+    fn write_serialized<F: FnOnce(&mut Encoder)>(&mut self, serialize: F) -> io::Result<()> {
+        let mut encoder = Encoder {
+            id_size: self.writer.id_size,
+            bytes: std::mem::take(&mut self.scratch),
+        };
+        encoder.bytes.clear();
+        serialize(&mut encoder);
+        let res = self.write_raw(&encoder.bytes);
+        self.scratch = encoder.bytes;
+        res
+    }
+}
+
+/// Big endian serialization into memory, which cannot fail
+struct Encoder {
+    id_size: IdSize,
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(id_size: IdSize) -> Self {
+        Encoder {
+            id_size,
+            bytes: Vec::new(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn id(&mut self, id: Option<Id>) {
+        // writing into a Vec does not fail
+        let _ = write_id(&mut self.bytes, self.id_size, id);
+    }
+
+    fn field_value(&mut self, value: &FieldValue) {
+        // dump_field_value https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L769
+        match value {
+            FieldValue::ObjectId(id) => self.id(*id),
+            FieldValue::Boolean(b) => self.u8(*b as u8),
+            FieldValue::Char(c) => self.u16(*c),
+            FieldValue::Float(f) => self.bytes.extend_from_slice(&f.to_be_bytes()),
+            FieldValue::Double(d) => self.bytes.extend_from_slice(&d.to_be_bytes()),
+            FieldValue::Byte(b) => self.bytes.extend_from_slice(&b.to_be_bytes()),
+            FieldValue::Short(s) => self.bytes.extend_from_slice(&s.to_be_bytes()),
+            FieldValue::Int(i) => self.bytes.extend_from_slice(&i.to_be_bytes()),
+            FieldValue::Long(l) => self.bytes.extend_from_slice(&l.to_be_bytes()),
+        }
+    }
+
+    /// Everything before the static fields, `optional_ids` are the superclass, class loader,
+    /// signers and protection domain
+    fn class_header(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        optional_ids: [Option<Id>; 4],
+        instance_size_bytes: u32,
+    ) {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L226
+        self.u8(0x20);
+        self.id(Some(obj_id));
+        self.u32(stack_trace_serial.num());
+        for id in optional_ids.iter() {
+            self.id(*id);
+        }
+        // 2x Id reserved
+        self.id(None);
+        self.id(None);
+        self.u32(instance_size_bytes);
+        // constant pool is always empty
+        self.u16(0);
+    }
+
+    fn object_array_header(
+        &mut self,
+        obj_id: Id,
+        stack_trace_serial: Serial,
+        num_elements: u32,
+        array_class_obj_id: Id,
+    ) {
+        // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L271
+        self.u8(0x22);
+        self.id(Some(obj_id));
+        self.u32(stack_trace_serial.num());
+        self.u32(num_elements);
+        self.id(Some(array_class_obj_id));
+    }
+}
+
+fn check_heap_dump_tag(tag: RecordTag) -> io::Result<()> {
+    match tag {
+        RecordTag::HeapDump | RecordTag::HeapDumpSegment => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a heap dump record", tag),
+        )),
+    }
+}
+
+/// Record lengths are 32 bit
+fn body_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes do not fit in a record", len),
+        )
+    })
+}
+
+/// Field counts of classes are 16 bit
+fn field_count(len: usize) -> io::Result<u16> {
+    u16::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} fields do not fit in a class", len),
+        )
+    })
+}
+
+/// Null ids are written as 0
+fn write_id<W: Write>(out: &mut W, id_size: IdSize, id: Option<Id>) -> io::Result<()> {
+    let id = id.map(|id| id.id()).unwrap_or(0);
+    match id_size {
+        IdSize::U32 => out.write_all(&(id as u32).to_be_bytes()),
+        IdSize::U64 => out.write_all(&id.to_be_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap_dump::FieldDescriptor;
+
+    const TIMESTAMP: u64 = 0x0000_0175_0102_0304;
+
+    /// A heap with every kind of record the parser reads and every sub record
+    fn fixture(id_size: IdSize) -> Vec<u8> {
+        let mut writer =
+            HprofWriter::new(Vec::new(), "JAVA PROFILE 1.0.2", id_size, TIMESTAMP).unwrap();
+        writer
+            .write_utf8(1, 1.into(), b"com/example/Point")
+            .unwrap();
+        writer.write_utf8(1, 2.into(), b"x").unwrap();
+        writer.write_utf8(1, 3.into(), b"ORIGIN").unwrap();
+        writer.write_utf8(1, 4.into(), b"run").unwrap();
+        writer
+            .write_stack_frame(
+                2,
+                10.into(),
+                4.into(),
+                4.into(),
+                1.into(),
+                7.into(),
+                LineNum::Normal(42),
+            )
+            .unwrap();
+        writer
+            .write_stack_frame(
+                2,
+                11.into(),
+                4.into(),
+                4.into(),
+                1.into(),
+                7.into(),
+                LineNum::NativeMethod,
+            )
+            .unwrap();
+        writer
+            .write_stack_trace(3, 5.into(), 6.into(), &[10.into(), 11.into()])
+            .unwrap();
+        writer
+            .write_load_class(4, 7.into(), 100.into(), 5.into(), 1.into())
+            .unwrap();
+
+        let mut segment = writer
+            .buffered_heap_dump_segment(RecordTag::HeapDumpSegment, 5)
+            .unwrap();
+        segment.write_gc_root_unknown(200.into()).unwrap();
+        segment
+            .write_gc_root_thread_obj(None, 6.into(), 5.into())
+            .unwrap();
+        segment
+            .write_gc_root_jni_global(200.into(), 300.into())
+            .unwrap();
+        segment
+            .write_gc_root_jni_local_ref(200.into(), 6.into(), Some(1))
+            .unwrap();
+        segment
+            .write_gc_root_java_stack_frame(200.into(), 6.into(), None)
+            .unwrap();
+        segment
+            .write_gc_root_native_stack(200.into(), 6.into())
+            .unwrap();
+        segment.write_gc_root_system_class(100.into()).unwrap();
+        segment
+            .write_gc_root_thread_block(200.into(), 6.into())
+            .unwrap();
+        segment.write_gc_root_busy_monitor(200.into()).unwrap();
+        segment
+            .write_class(
+                100.into(),
+                5.into(),
+                Some(101.into()),
+                None,
+                None,
+                Some(102.into()),
+                12,
+                &[
+                    (3.into(), FieldValue::ObjectId(Some(200.into()))),
+                    (2.into(), FieldValue::Double(-0.5)),
+                ],
+                &[(2.into(), FieldType::Int), (3.into(), FieldType::ObjectId)],
+            )
+            .unwrap();
+        segment
+            .write_instance(
+                200.into(),
+                5.into(),
+                100.into(),
+                &[FieldValue::Int(-7), FieldValue::ObjectId(None)],
+            )
+            .unwrap();
+        segment
+            .write_object_array(201.into(), 5.into(), 103.into(), &[Some(200.into()), None])
+            .unwrap();
+        segment
+            .write_primitive_array(
+                202.into(),
+                5.into(),
+                PrimitiveArrayType::Char,
+                &[0, b'h', 0, b'i'],
+            )
+            .unwrap();
+        segment
+            .write_primitive_array(203.into(), 5.into(), PrimitiveArrayType::Long, &[])
+            .unwrap();
+        segment.finish().unwrap();
+
+        writer.write_heap_dump_end(6).unwrap();
+        writer.into_inner().unwrap()
+    }
+
+    /// Rewrites a dump from parsed values only, never copying record bodies
+    fn rewrite(input: &[u8]) -> Vec<u8> {
+        let hprof = parse_hprof(input).unwrap();
+        let header = hprof.header();
+        let mut writer = HprofWriter::new(
+            Vec::new(),
+            header.label().unwrap(),
+            header.id_size(),
+            header.timestamp_millis(),
+        )
+        .unwrap();
+        for record in hprof.records_iter() {
+            let record = record.unwrap();
+            let micros = record.micros_since_header_ts();
+            match record.tag() {
+                RecordTag::Utf8 => {
+                    let utf8 = record.as_utf_8().unwrap().unwrap();
+                    writer
+                        .write_utf8(micros, utf8.name_id(), utf8.text())
+                        .unwrap();
+                }
+                RecordTag::LoadClass => {
+                    let class = record.as_load_class().unwrap().unwrap();
+                    writer
+                        .write_load_class(
+                            micros,
+                            class.class_serial(),
+                            class.class_obj_id(),
+                            class.stack_trace_serial(),
+                            class.class_name_id(),
+                        )
+                        .unwrap();
+                }
+                RecordTag::StackFrame => {
+                    let frame = record.as_stack_frame().unwrap().unwrap();
+                    writer
+                        .write_stack_frame(
+                            micros,
+                            frame.id(),
+                            frame.method_name_id(),
+                            frame.method_signature_id(),
+                            frame.source_file_name_id(),
+                            frame.class_serial(),
+                            frame.line_num(),
+                        )
+                        .unwrap();
+                }
+                RecordTag::StackTrace => {
+                    let trace = record.as_stack_trace().unwrap().unwrap();
+                    let frame_ids = trace.frame_ids().map(Result::unwrap).collect::<Vec<_>>();
+                    writer
+                        .write_stack_trace(
+                            micros,
+                            trace.stack_trace_serial(),
+                            trace.thread_serial(),
+                            &frame_ids,
+                        )
+                        .unwrap();
+                }
+                RecordTag::HeapDump | RecordTag::HeapDumpSegment => {
+                    let segment = record.as_heap_dump_segment().unwrap().unwrap();
+                    let mut segment_writer = writer
+                        .heap_dump_segment(record.tag(), micros, record.body().len() as u32)
+                        .unwrap();
+                    for sub_record in segment.sub_records() {
+                        segment_writer
+                            .write_sub_record(&sub_record.unwrap())
+                            .unwrap();
+                    }
+                    segment_writer.finish().unwrap();
+                }
+                RecordTag::HeapDumpEnd => writer.write_heap_dump_end(micros).unwrap(),
+                tag => panic!("Unexpected record {:?}", tag),
+            }
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn round_trips_parsed_records() {
+        for id_size in [IdSize::U32, IdSize::U64].iter() {
+            let written = fixture(*id_size);
+            assert_eq!(written, rewrite(&written));
+        }
+    }
+
+    #[test]
+    fn writes_what_the_parser_reads() {
+        let written = fixture(IdSize::U32);
+        let hprof = parse_hprof(&written).unwrap();
+        assert_eq!("JAVA PROFILE 1.0.2", hprof.header().label().unwrap());
+        assert_eq!(TIMESTAMP, hprof.header().timestamp_millis());
+
+        let records = hprof.records_iter().map(Result::unwrap).collect::<Vec<_>>();
+        let tags = records.iter().map(|r| r.tag()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                RecordTag::Utf8,
+                RecordTag::Utf8,
+                RecordTag::Utf8,
+                RecordTag::Utf8,
+                RecordTag::StackFrame,
+                RecordTag::StackFrame,
+                RecordTag::StackTrace,
+                RecordTag::LoadClass,
+                RecordTag::HeapDumpSegment,
+                RecordTag::HeapDumpEnd,
+            ],
+            tags
+        );
+
+        let utf8 = records[0].as_utf_8().unwrap().unwrap();
+        assert_eq!(Id::from(1), utf8.name_id());
+        assert_eq!("com/example/Point", utf8.text_as_str().unwrap());
+
+        let frame = records[5].as_stack_frame().unwrap().unwrap();
+        assert_eq!(Id::from(11), frame.id());
+        assert_eq!(Serial::from(7), frame.class_serial());
+        assert!(matches!(frame.line_num(), LineNum::NativeMethod));
+
+        let trace = records[6].as_stack_trace().unwrap().unwrap();
+        assert_eq!(Serial::from(5), trace.stack_trace_serial());
+        assert_eq!(
+            vec![Id::from(10), Id::from(11)],
+            trace.frame_ids().map(Result::unwrap).collect::<Vec<_>>()
+        );
+
+        let class = records[7].as_load_class().unwrap().unwrap();
+        assert_eq!(Serial::from(7), class.class_serial());
+        assert_eq!(Id::from(100), class.class_obj_id());
+        assert_eq!(Id::from(1), class.class_name_id());
+
+        let segment = records[8].as_heap_dump_segment().unwrap().unwrap();
+        let sub_records = segment
+            .sub_records()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(14, sub_records.len());
+        match &sub_records[1] {
+            SubRecord::GcRootThreadObj(root) => {
+                assert_eq!(None, root.thread_obj_id());
+                assert_eq!(Serial::from(6), root.thread_serial());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match &sub_records[4] {
+            SubRecord::GcRootJavaStackFrame(root) => assert_eq!(None, root.frame_index()),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match &sub_records[9] {
+            SubRecord::Class(class) => {
+                assert_eq!(Some(Id::from(101)), class.super_class_obj_id());
+                assert_eq!(None, class.class_loader_obj_id());
+                assert_eq!(Some(Id::from(102)), class.protection_domain_obj_id());
+                assert_eq!(12, class.instance_size_bytes());
+                let statics = class
+                    .static_fields()
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>();
+                assert_eq!(2, statics.len());
+                assert!(matches!(statics[1].value(), FieldValue::Double(d) if d == -0.5));
+                let descriptors = class
+                    .instance_field_descriptors()
+                    .map(Result::unwrap)
+                    .collect::<Vec<FieldDescriptor>>();
+                assert!(matches!(descriptors[0].field_type(), FieldType::Int));
+                assert!(matches!(descriptors[1].field_type(), FieldType::ObjectId));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match &sub_records[10] {
+            SubRecord::Instance(instance) => {
+                let (rest, value) = FieldType::Int
+                    .parse_value(instance.fields(), IdSize::U32)
+                    .unwrap();
+                assert!(matches!(value, FieldValue::Int(-7)));
+                let (rest, value) = FieldType::ObjectId.parse_value(rest, IdSize::U32).unwrap();
+                assert!(matches!(value, FieldValue::ObjectId(None)));
+                assert!(rest.is_empty());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match &sub_records[11] {
+            SubRecord::ObjectArray(array) => assert_eq!(
+                vec![Some(Id::from(200)), None],
+                array
+                    .elements(IdSize::U32)
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+            ),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match &sub_records[12] {
+            SubRecord::PrimitiveArray(array) => assert_eq!(
+                "hi",
+                String::from_utf16(
+                    &array
+                        .chars()
+                        .unwrap()
+                        .map(Result::unwrap)
+                        .collect::<Vec<_>>()
+                )
+                .unwrap()
+            ),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checks_segment_length() {
+        let mut writer =
+            HprofWriter::new(Vec::new(), "JAVA PROFILE 1.0.2", IdSize::U64, 0).unwrap();
+        let mut segment = writer
+            .heap_dump_segment(RecordTag::HeapDumpSegment, 0, 10)
+            .unwrap();
+        segment.write_gc_root_system_class(1.into()).unwrap();
+        assert!(segment.write_gc_root_system_class(2.into()).is_err());
+        assert!(segment.finish().is_err());
+
+        assert!(writer
+            .buffered_heap_dump_segment(RecordTag::Utf8, 0)
+            .is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Rewrites a heap dump without its customer data.
+//!
+//! Contents of `byte[]` and `char[]` arrays, which hold the values of all `String`s as well as
+//! documents, queries and responses, are replaced with placeholders of the same length. Sizes and
+//! the object graph are left untouched so the rewritten dump still shows where memory went.
+//! Class, field and method names are replaced as well unless kept, metadata arrays can be kept
+//! by passing their ids.
+
+use std::io::Write;
+
+use ahash::AHashSet;
+use anyhow::*;
+use jvm_hprof::heap_dump::{PrimitiveArrayType, SubRecord};
+use jvm_hprof::{parse_hprof, HprofWriter, RecordTag};
+
+use crate::hprof::ObjectId;
+
+/// Byte placeholder, also used for the low byte of `char` placeholders
+const PLACEHOLDER: u8 = b'x';
+
+pub struct AnonymizeOptions {
+    /// Keeps names of classes, fields and methods
+    pub keep_names: bool,
+    /// Arrays left as they are, e.g. Elasticsearch metadata
+    pub keep_arrays: AHashSet<ObjectId>,
+}
+
+#[derive(Default)]
+pub struct AnonymizeStats {
+    pub records: u64,
+    pub arrays: u64,
+    pub array_bytes: u64,
+    pub kept_arrays: u64,
+    pub names: u64,
+}
+
+/// Writes an anonymized copy of the dump in `input`
+pub fn anonymize<W: Write>(
+    input: &[u8],
+    output: W,
+    opts: &AnonymizeOptions,
+) -> Result<AnonymizeStats> {
+    let hprof = parse_hprof(input).map_err(|err| anyhow!("Failed to parse hprof: {:?}", err))?;
+    let header = hprof.header();
+    let mut writer = HprofWriter::new(
+        output,
+        header.label().unwrap_or("JAVA PROFILE 1.0.2"),
+        header.id_size(),
+        header.timestamp_millis(),
+    )?;
+    let mut stats = AnonymizeStats::default();
+    let mut contents = Vec::new();
+    for record in hprof.records_iter() {
+        let record = record.map_err(|err| anyhow!("Failed to parse record: {:?}", err))?;
+        stats.records += 1;
+        let micros = record.micros_since_header_ts();
+        match record.tag() {
+            RecordTag::Utf8 if !opts.keep_names => {
+                let utf8 = record
+                    .as_utf_8()
+                    .context("Utf8 record expected")?
+                    .map_err(|err| anyhow!("Failed to parse Utf8 record: {:?}", err))?;
+                // names have to stay distinct, classes are looked up by name
+                let placeholder = format!("n{:x}", utf8.name_id().id());
+                writer.write_utf8(micros, utf8.name_id(), placeholder.as_bytes())?;
+                stats.names += 1;
+            }
+            RecordTag::HeapDump | RecordTag::HeapDumpSegment => {
+                let segment = record
+                    .as_heap_dump_segment()
+                    .context("Heap dump record expected")?
+                    .map_err(|err| anyhow!("Failed to parse heap dump segment: {:?}", err))?;
+                let mut segment_writer =
+                    writer.heap_dump_segment(record.tag(), micros, record.body().len() as u32)?;
+                for sub_record in segment.sub_records().with_bytes() {
+                    let (sub_record, bytes) = sub_record
+                        .map_err(|err| anyhow!("Failed to parse sub record: {:?}", err))?;
+                    let array = match sub_record {
+                        SubRecord::PrimitiveArray(array) => array,
+                        _ => {
+                            segment_writer.write_raw(bytes)?;
+                            continue;
+                        }
+                    };
+                    let element = match array.primitive_type() {
+                        PrimitiveArrayType::Byte => &[PLACEHOLDER][..],
+                        PrimitiveArrayType::Char => &[0, PLACEHOLDER][..],
+                        _ => {
+                            segment_writer.write_raw(bytes)?;
+                            continue;
+                        }
+                    };
+                    if opts.keep_arrays.contains(&array.obj_id().into()) {
+                        stats.kept_arrays += 1;
+                        segment_writer.write_raw(bytes)?;
+                        continue;
+                    }
+                    contents.clear();
+                    for _ in 0..array.num_elements() {
+                        contents.extend_from_slice(element);
+                    }
+                    segment_writer.write_primitive_array(
+                        array.obj_id(),
+                        array.stack_trace_serial(),
+                        array.primitive_type(),
+                        &contents,
+                    )?;
+                    stats.arrays += 1;
+                    stats.array_bytes += contents.len() as u64;
+                }
+                segment_writer.finish()?;
+            }
+            tag => writer.write_record(tag, micros, record.body())?,
+        }
+    }
+    writer.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashMap;
+    use jvm_hprof::heap_dump::{FieldType, FieldValue};
+    use jvm_hprof::Id;
+
+    use super::*;
+    use crate::hprof::fixture::HeapBuilder;
+    use crate::hprof::JavaProfile;
+
+    /// Records of a dump the tests compare
+    #[derive(Default)]
+    struct Contents {
+        utf8: Vec<(Id, Vec<u8>)>,
+        /// Ids of classes, instances and arrays in the order they were written
+        objects: Vec<Id>,
+        /// Primitive arrays by id, with their length and contents
+        arrays: AHashMap<Id, (PrimitiveArrayType, u32, Vec<u8>)>,
+    }
+
+    fn contents(dump: &[u8]) -> Contents {
+        let hprof = parse_hprof(dump).unwrap();
+        let mut contents = Contents::default();
+        for record in hprof.records_iter() {
+            let record = record.unwrap();
+            match record.tag() {
+                RecordTag::Utf8 => {
+                    let utf8 = record.as_utf_8().unwrap().unwrap();
+                    contents.utf8.push((utf8.name_id(), utf8.text().to_vec()));
+                }
+                RecordTag::HeapDump | RecordTag::HeapDumpSegment => {
+                    let segment = record.as_heap_dump_segment().unwrap().unwrap();
+                    for sub_record in segment.sub_records() {
+                        match sub_record.unwrap() {
+                            SubRecord::Class(class) => contents.objects.push(class.obj_id()),
+                            SubRecord::Instance(instance) => {
+                                contents.objects.push(instance.obj_id())
+                            }
+                            SubRecord::ObjectArray(array) => contents.objects.push(array.obj_id()),
+                            SubRecord::PrimitiveArray(array) => {
+                                let bytes = match array.primitive_type() {
+                                    PrimitiveArrayType::Byte => array
+                                        .bytes()
+                                        .unwrap()
+                                        .map(|byte| byte.unwrap() as u8)
+                                        .collect(),
+                                    PrimitiveArrayType::Char => array
+                                        .chars()
+                                        .unwrap()
+                                        .flat_map(|char| char.unwrap().to_be_bytes())
+                                        .collect(),
+                                    _ => Vec::new(),
+                                };
+                                contents.objects.push(array.obj_id());
+                                contents.arrays.insert(
+                                    array.obj_id(),
+                                    (array.primitive_type(), array.num_elements(), bytes),
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        contents
+    }
+
+    /// A document holding a string, a char array and a metadata byte array, and the id of the
+    /// metadata array
+    fn fixture() -> (Vec<u8>, Id) {
+        let mut heap = HeapBuilder::new();
+        heap.class(
+            "com/example/Document",
+            None,
+            &[
+                ("title", FieldType::ObjectId),
+                ("body", FieldType::ObjectId),
+                ("index", FieldType::ObjectId),
+            ],
+        );
+        let title = heap.string("secret title");
+        let body = heap.char_array("secret body");
+        let metadata = heap.byte_array(b"logs-metadata");
+        let document = heap.instance(
+            "com/example/Document",
+            &[
+                ("title", FieldValue::ObjectId(Some(title))),
+                ("body", FieldValue::ObjectId(Some(body))),
+                ("index", FieldValue::ObjectId(Some(metadata))),
+            ],
+        );
+        heap.gc_root(document);
+        (heap.build(), metadata)
+    }
+
+    fn anonymized(dump: &[u8], opts: &AnonymizeOptions) -> (Vec<u8>, AnonymizeStats) {
+        let mut output = Vec::new();
+        let stats = anonymize(dump, &mut output, opts).unwrap();
+        (output, stats)
+    }
+
+    fn placeholders(primitive_type: PrimitiveArrayType, length: u32) -> Vec<u8> {
+        let element = match primitive_type {
+            PrimitiveArrayType::Byte => &[PLACEHOLDER][..],
+            PrimitiveArrayType::Char => &[0, PLACEHOLDER][..],
+            _ => &[][..],
+        };
+        element.repeat(length as usize)
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn replaces_array_contents() {
+        let (dump, _) = fixture();
+        let opts = AnonymizeOptions {
+            keep_names: true,
+            keep_arrays: Default::default(),
+        };
+        let (output, stats) = anonymized(&dump, &opts);
+        let before = contents(&dump);
+        let after = contents(&output);
+
+        assert_eq!(after.objects, before.objects);
+        assert_eq!(after.arrays.len(), before.arrays.len());
+        for (id, (primitive_type, length, _)) in &before.arrays {
+            assert_eq!(
+                after.arrays[id],
+                (
+                    *primitive_type,
+                    *length,
+                    placeholders(*primitive_type, *length)
+                )
+            );
+        }
+        assert!(contains(&dump, b"secret"));
+        assert!(!contains(&output, b"secret"));
+        assert!(!contains(&output, b"logs-metadata"));
+        assert_eq!(after.utf8, before.utf8);
+
+        assert_eq!(stats.arrays, 3);
+        assert_eq!(stats.array_bytes, 12 + 2 * 11 + 13);
+        assert_eq!(stats.kept_arrays, 0);
+        assert_eq!(stats.names, 0);
+
+        // the copy is still a dump the profile reads
+        let mut profile = JavaProfile::new(&output);
+        profile.process();
+        assert_eq!(
+            profile
+                .get_instances_by_class_name("com/example/Document")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn keeps_listed_arrays() {
+        let (dump, metadata) = fixture();
+        let opts = AnonymizeOptions {
+            keep_names: true,
+            keep_arrays: std::iter::once(ObjectId::from(metadata)).collect(),
+        };
+        let (output, stats) = anonymized(&dump, &opts);
+        let before = contents(&dump);
+        let after = contents(&output);
+
+        assert_eq!(after.arrays[&metadata], before.arrays[&metadata]);
+        assert_eq!(after.arrays[&metadata].2, b"logs-metadata");
+        assert!(!contains(&output, b"secret"));
+        assert_eq!(stats.arrays, 2);
+        assert_eq!(stats.kept_arrays, 1);
+    }
+
+    #[test]
+    fn replaces_names() {
+        let (dump, _) = fixture();
+        let opts = AnonymizeOptions {
+            keep_names: false,
+            keep_arrays: Default::default(),
+        };
+        let (output, stats) = anonymized(&dump, &opts);
+        let before = contents(&dump);
+        let after = contents(&output);
+
+        assert_eq!(stats.names, before.utf8.len() as u64);
+        assert_eq!(
+            after.utf8.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            before.utf8.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        let names = after
+            .utf8
+            .iter()
+            .map(|(_, text)| text.as_slice())
+            .collect::<AHashSet<_>>();
+        assert_eq!(names.len(), after.utf8.len(), "placeholders are distinct");
+        for (_, text) in &before.utf8 {
+            assert!(!names.contains(text.as_slice()));
+            assert!(!contains(&output, text));
+        }
+        assert_eq!(after.objects, before.objects);
+
+        let mut profile = JavaProfile::new(&output);
+        profile.process();
+        assert!(profile.get_class_by_name("com/example/Document").is_none());
+    }
+}
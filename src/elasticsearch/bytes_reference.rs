@@ -0,0 +1,318 @@
+//! Reading the content of `BytesReference` implementations.
+//!
+//! Elasticsearch passes request bodies and serialized sources around as `BytesReference`s backed
+//! by plain arrays, recycled pages of `BigArrays` or netty buffers. Content of direct netty
+//! buffers lives off heap and is not part of a heap dump, it is reported as unavailable.
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::hprof::*;
+
+use super::ElasticsearchMemory;
+
+const BYTES_ARRAY_CLASS: &str = "org/elasticsearch/common/bytes/BytesArray";
+const COMPOSITE_BYTES_REFERENCE_CLASS: &str =
+    "org/elasticsearch/common/bytes/CompositeBytesReference";
+/// Also the base of `ReleasablePagedBytesReference` before 7.x
+const PAGED_BYTES_REFERENCE_CLASS: &str = "org/elasticsearch/common/bytes/PagedBytesReference";
+const RELEASABLE_BYTES_REFERENCE_CLASS: &str =
+    "org/elasticsearch/common/bytes/ReleasableBytesReference";
+/// Netty buffers wrapped by `Netty4Utils.toBytesReference`
+const BYTE_BUF_BYTES_REFERENCE_CLASS: &str =
+    "org/elasticsearch/transport/netty4/ByteBufBytesReference";
+
+const BYTES_REFERENCE_CLASSES: [&str; 5] = [
+    BYTES_ARRAY_CLASS,
+    COMPOSITE_BYTES_REFERENCE_CLASS,
+    PAGED_BYTES_REFERENCE_CLASS,
+    RELEASABLE_BYTES_REFERENCE_CLASS,
+    BYTE_BUF_BYTES_REFERENCE_CLASS,
+];
+
+/// Size of `BigByteArray` pages, `PageCacheRecycler.BYTE_PAGE_SIZE`
+const BYTE_PAGE_SIZE: usize = 16 * 1024;
+/// Nesting of composite references and derived netty buffers followed
+const MAX_NESTING: usize = 32;
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Whether the class is one of the supported `BytesReference` implementations
+    pub(super) fn is_bytes_reference(&self, class_name: &str) -> bool {
+        BYTES_REFERENCE_CLASSES.iter().any(|bytes_class| {
+            self.profile
+                .is_subclass_by_name(class_name, bytes_class)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Reads the content of any supported `BytesReference`, dispatching on its class
+    pub(super) fn read_bytes_reference(&self, reference: &JavaInstance) -> anyhow::Result<Vec<u8>> {
+        self.read_nested_bytes_reference(reference, 0)
+    }
+
+    fn read_nested_bytes_reference(
+        &self,
+        reference: &JavaInstance,
+        depth: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        if depth > MAX_NESTING {
+            bail!("Bytes references nested too deep");
+        }
+        self.debug_instance(reference);
+        let class_name = reference.name(&self.profile).unwrap_or("unknown");
+        let is_subclass = |parent: &str| {
+            self.profile
+                .is_subclass_by_name(class_name, parent)
+                .unwrap_or(false)
+        };
+        if is_subclass(BYTES_ARRAY_CLASS) {
+            self.read_bytes_array(reference)
+        } else if is_subclass(COMPOSITE_BYTES_REFERENCE_CLASS) {
+            self.read_composite_bytes(reference, depth)
+        } else if is_subclass(PAGED_BYTES_REFERENCE_CLASS) {
+            self.read_paged_bytes(reference)
+        } else if is_subclass(RELEASABLE_BYTES_REFERENCE_CLASS) {
+            let delegate: &JavaInstance = reference
+                .fields(&self.profile)
+                .value(&self.profile, "delegate")
+                .ok_or(anyhow!("delegate not found"))?;
+            self.read_nested_bytes_reference(delegate, depth + 1)
+        } else if is_subclass(BYTE_BUF_BYTES_REFERENCE_CLASS) {
+            let fields = reference.fields(&self.profile);
+            let buffer: &JavaInstance = fields
+                .value(&self.profile, "buffer")
+                .ok_or(anyhow!("buffer not found"))?;
+            let offset: i32 = fields
+                .value(&self.profile, "offset")
+                .ok_or(anyhow!("offset not found"))?;
+            let length: i32 = fields
+                .value(&self.profile, "length")
+                .ok_or(anyhow!("length not found"))?;
+            self.read_byte_buf(buffer, offset.max(0) as usize, length.max(0) as usize, 0)
+        } else {
+            Err(anyhow!("Unknown bytes reference class {class_name}"))
+        }
+    }
+
+    fn read_bytes_array(&self, instance: &JavaInstance) -> anyhow::Result<Vec<u8>> {
+        let fields = instance.fields(&self.profile);
+        let bytes: &JavaPrimitiveArray = fields
+            .value(&self.profile, "bytes")
+            .ok_or(anyhow!("bytes not found"))?;
+
+        let offset: i32 = fields
+            .value(&self.profile, "offset")
+            .ok_or(anyhow!("offset not found"))?;
+
+        let length: i32 = fields
+            .value(&self.profile, "length")
+            .ok_or(anyhow!("length not found"))?;
+
+        read_byte_range(bytes, offset.max(0) as usize, length.max(0) as usize)
+    }
+
+    fn read_composite_bytes(
+        &self,
+        composite_bytes: &JavaInstance,
+        depth: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let fields = composite_bytes.fields(&self.profile);
+        let references: &JavaObjectArray = fields
+            .value(&self.profile, "references")
+            .ok_or(anyhow!("failed to read references"))?;
+
+        let chunks = references
+            .values(&self.profile)
+            .filter_map(|reference| {
+                if let Some(reference) = reference {
+                    Some(
+                        self.read_nested_bytes_reference(reference, depth + 1)
+                            .context("Failed to read composite bytes reference part"),
+                    )
+                } else {
+                    log::warn!("Could not read chunk request fragment! Expect corrupted query");
+                    None
+                }
+            })
+            .collect::<anyhow::Result<Vec<Vec<u8>>>>()?;
+
+        Ok(chunks.concat())
+    }
+
+    /// `PagedBytesReference` over a `ByteArray` of `BigArrays`, recycled pages or a plain array
+    fn read_paged_bytes(&self, reference: &JavaInstance) -> anyhow::Result<Vec<u8>> {
+        let fields = reference.fields(&self.profile);
+        let byte_array: &JavaInstance = fields
+            .value(&self.profile, "byteArray")
+            .ok_or(anyhow!("byteArray not found"))?;
+        let offset = fields.value::<i32>(&self.profile, "offset").unwrap_or(0);
+        let length: i32 = fields
+            .value(&self.profile, "length")
+            .ok_or(anyhow!("length not found"))?;
+        let (offset, length) = (offset.max(0) as usize, length.max(0) as usize);
+
+        let array_fields = byte_array.fields(&self.profile);
+        // `ByteArrayWrapper` of arrays not worth recycling
+        if let Some(array) = array_fields.value::<&JavaPrimitiveArray>(&self.profile, "array") {
+            return read_byte_range(array, offset, length);
+        }
+        let pages: &JavaObjectArray = array_fields.value(&self.profile, "pages").ok_or(anyhow!(
+            "pages not found in {}",
+            byte_array.name(&self.profile).unwrap_or("unknown")
+        ))?;
+        let pages = pages.elements(&self.profile);
+        let mut bytes = Vec::with_capacity(length);
+        let mut position = offset;
+        while bytes.len() < length {
+            let page = match pages.get(position / BYTE_PAGE_SIZE) {
+                Some(JavaLocalValue::PrimitiveArray(page)) => page,
+                _ => bail!("Page {} missing", position / BYTE_PAGE_SIZE),
+            };
+            let page_offset = position % BYTE_PAGE_SIZE;
+            let chunk = (BYTE_PAGE_SIZE - page_offset).min(length - bytes.len());
+            bytes.extend(read_byte_range(page, page_offset, chunk)?);
+            position += chunk;
+        }
+        Ok(bytes)
+    }
+
+    /// Reads the readable bytes of a netty `ByteBuf`, between its reader and writer index
+    pub(super) fn read_byte_buf_content(&self, buffer: &JavaInstance) -> anyhow::Result<Vec<u8>> {
+        let fields = buffer.fields(&self.profile);
+        let reader_index = fields
+            .value::<i32>(&self.profile, "readerIndex")
+            .unwrap_or(0);
+        let writer_index: i32 = fields
+            .value(&self.profile, "writerIndex")
+            .ok_or(anyhow!("writerIndex not found"))?;
+        self.read_byte_buf(
+            buffer,
+            reader_index.max(0) as usize,
+            (writer_index - reader_index).max(0) as usize,
+            0,
+        )
+    }
+
+    /// Reads `length` bytes of a netty `ByteBuf` starting at `index`, following derived buffers to
+    /// the buffer holding the memory
+    fn read_byte_buf(
+        &self,
+        buffer: &JavaInstance,
+        index: usize,
+        length: usize,
+        depth: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        if depth > MAX_NESTING {
+            bail!("Netty buffers nested too deep");
+        }
+        let class_name = buffer.name(&self.profile).unwrap_or("unknown");
+        let fields = buffer.fields(&self.profile);
+        // unpooled heap buffers
+        if let Some(array) = fields.value::<&JavaPrimitiveArray>(&self.profile, "array") {
+            return read_byte_range(array, index, length);
+        }
+        // pooled buffers, heap buffers' memory is a byte array, direct ones' a `ByteBuffer`
+        match fields.value::<JavaLocalValue>(&self.profile, "memory") {
+            Some(JavaLocalValue::PrimitiveArray(memory)) => {
+                let offset = fields.value::<i32>(&self.profile, "offset").unwrap_or(0);
+                return read_byte_range(memory, offset.max(0) as usize + index, length);
+            }
+            Some(JavaLocalValue::Object(_)) => {
+                bail!("Content is held off heap by direct buffer {class_name}, unavailable")
+            }
+            _ => {}
+        }
+        if class_name.contains("Direct") {
+            bail!("Content is held off heap by direct buffer {class_name}, unavailable");
+        }
+        if let Some(components) = fields.value::<&JavaObjectArray>(&self.profile, "components") {
+            return self.read_composite_byte_buf(components, index, length, depth);
+        }
+        // sliced, duplicated and read only buffers
+        let adjustment = fields
+            .value::<i32>(&self.profile, "adjustment")
+            .unwrap_or(0);
+        let parent = ["rootParent", "buffer", "buf"]
+            .iter()
+            .find_map(|name| fields.value::<&JavaInstance>(&self.profile, name))
+            .ok_or_else(|| anyhow!("Unsupported netty buffer {class_name}"))?;
+        self.read_byte_buf(
+            parent,
+            (index as i64 + i64::from(adjustment)).max(0) as usize,
+            length,
+            depth + 1,
+        )
+    }
+
+    /// `CompositeByteBuf` components cover consecutive ranges `[offset, endOffset)` of the buffer
+    fn read_composite_byte_buf(
+        &self,
+        components: &JavaObjectArray,
+        index: usize,
+        length: usize,
+        depth: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let end = index + length;
+        let mut bytes = Vec::with_capacity(length);
+        for component in components.values(&self.profile).flatten() {
+            let fields = component.fields(&self.profile);
+            let (offset, end_offset) = match (
+                fields.value::<i32>(&self.profile, "offset"),
+                fields.value::<i32>(&self.profile, "endOffset"),
+            ) {
+                (Some(offset), Some(end_offset)) => {
+                    (offset.max(0) as usize, end_offset.max(0) as usize)
+                }
+                _ => continue,
+            };
+            if end_offset <= index || offset >= end {
+                continue;
+            }
+            let buf: &JavaInstance = fields
+                .value(&self.profile, "buf")
+                .ok_or(anyhow!("component buf not found"))?;
+            // netty 4.1.35+ components index their buffer with an adjustment, older ones hold a
+            // slice starting at the component offset
+            let adjustment = fields
+                .value::<i32>(&self.profile, "adjustment")
+                .map(i64::from)
+                .unwrap_or(-(offset as i64));
+            let from = index.max(offset);
+            let to = end.min(end_offset);
+            bytes.extend(self.read_byte_buf(
+                buf,
+                (from as i64 + adjustment).max(0) as usize,
+                to - from,
+                depth + 1,
+            )?);
+        }
+        if bytes.len() != length {
+            bail!(
+                "Composite buffer components cover {} of {length} bytes",
+                bytes.len()
+            );
+        }
+        Ok(bytes)
+    }
+}
+
+fn read_byte_range(
+    array: &JavaPrimitiveArray,
+    offset: usize,
+    length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    match array.values() {
+        PrimitiveArrayValues::Byte(bytes) => Ok(bytes
+            .get(offset..offset + length)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Range {offset}..{} out of bounds of array of {} bytes",
+                    offset + length,
+                    bytes.len()
+                )
+            })?
+            .iter()
+            .map(|&b| b as u8)
+            .collect()),
+        _ => Err(anyhow!("Expected array of bytes")),
+    }
+}
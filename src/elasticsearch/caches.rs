@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use ahash::{AHashMap, AHashSet};
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::object_json::simple_class_name;
+use super::{ElasticsearchMemory, ShardId};
+
+const INDICES_REQUEST_CACHE_CLASS: &str = "org/elasticsearch/indices/IndicesRequestCache";
+const INDICES_QUERY_CACHE_CLASS: &str = "org/elasticsearch/indices/IndicesQueryCache";
+const INDICES_FIELD_DATA_CACHE_CLASS: &str =
+    "org/elasticsearch/indices/fielddata/cache/IndicesFieldDataCache";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheKind {
+    Request,
+    Query,
+    Fielddata,
+}
+
+impl Display for CacheKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            CacheKind::Request => "request",
+            CacheKind::Query => "query",
+            CacheKind::Fielddata => "fielddata",
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct CacheReport {
+    pub kind: CacheKind,
+    pub entries: Vec<CacheEntry>,
+}
+
+#[derive(Serialize)]
+pub struct CacheEntry {
+    pub shard: Option<ShardId>,
+    pub description: String,
+    /// Retained size of the cached value, including the key for the request cache
+    pub size: u64,
+}
+
+impl CacheReport {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn size_by_shard(&self) -> BTreeMap<Option<&ShardId>, u64> {
+        let mut sizes = BTreeMap::new();
+        for entry in &self.entries {
+            *sizes.entry(entry.shard.as_ref()).or_default() += entry.size;
+        }
+        sizes
+    }
+
+    pub fn size_by_index(&self) -> BTreeMap<Option<&str>, u64> {
+        let mut sizes = BTreeMap::new();
+        for entry in &self.entries {
+            *sizes
+                .entry(entry.shard.as_ref().map(|s| s.index.as_str()))
+                .or_default() += entry.size;
+        }
+        sizes
+    }
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads entries of the shard request cache, node query cache and fielddata cache.
+    /// Caches not found in the heap are skipped.
+    pub fn read_caches(&self) -> Vec<CacheReport> {
+        let mut reports = Vec::new();
+        for (kind, class_name) in [
+            (CacheKind::Request, INDICES_REQUEST_CACHE_CLASS),
+            (CacheKind::Query, INDICES_QUERY_CACHE_CLASS),
+            (CacheKind::Fielddata, INDICES_FIELD_DATA_CACHE_CLASS),
+        ] {
+            let class = match self.profile.get_class_by_name(class_name) {
+                Some(class) => class,
+                None => {
+                    log::warn!("{class_name} not found in heap");
+                    continue;
+                }
+            };
+            let mut entries = Vec::new();
+            for cache in class.instances(&self.profile) {
+                self.debug_instance(cache);
+                match kind {
+                    CacheKind::Request => entries.extend(self.read_request_cache(cache)),
+                    CacheKind::Query => entries.extend(self.read_query_cache(cache)),
+                    CacheKind::Fielddata => entries.extend(self.read_fielddata_cache(cache)),
+                }
+            }
+            entries.sort_by_key(|e| std::cmp::Reverse(e.size));
+            reports.push(CacheReport { kind, entries });
+        }
+        reports
+    }
+
+    fn read_request_cache(&self, request_cache: &JavaInstance) -> Vec<CacheEntry> {
+        self.read_cache_field_entries(request_cache)
+            .into_iter()
+            .map(|(key, value)| {
+                let fields = key.fields(&self.profile);
+                let shard = fields
+                    .value::<&JavaInstance>(&self.profile, "entity")
+                    .and_then(|entity| {
+                        entity
+                            .fields(&self.profile)
+                            .value::<&JavaInstance>(&self.profile, "indexShard")
+                    })
+                    .and_then(|shard| self.read_index_shard_id(shard));
+                // the key is the serialized shard request
+                let request_length = fields
+                    .value::<&JavaInstance>(&self.profile, "value")
+                    .and_then(|request| {
+                        request
+                            .fields(&self.profile)
+                            .value::<i32>(&self.profile, "length")
+                    });
+                CacheEntry {
+                    shard,
+                    description: match request_length {
+                        Some(length) => format!("request of {length} bytes"),
+                        None => "request".to_string(),
+                    },
+                    size: self.profile.retained_size(&key.id()).unwrap_or(0)
+                        + self.retained_size_of(value),
+                }
+            })
+            .collect()
+    }
+
+    fn read_query_cache(&self, query_cache: &JavaInstance) -> Vec<CacheEntry> {
+        let fields = query_cache.fields(&self.profile);
+        // Lucene leaf caches are keyed by segment core keys, ES tracks which shard these belong to
+        let core_key_shards: AHashMap<ObjectId, ShardId> = fields
+            .value::<&JavaInstance>(&self.profile, "shardKeyMap")
+            .and_then(|shard_key_map| {
+                shard_key_map
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "coreKeyToShard")
+            })
+            .map(|core_key_to_shard| self.profile.map_entries(core_key_to_shard))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(core_key, shard_id)| match shard_id {
+                JavaLocalValue::Object(shard_id) => {
+                    Some((core_key.object_id()?, self.read_shard_id(shard_id)?))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let leaf_caches = fields
+            .value::<&JavaInstance>(&self.profile, "cache")
+            .and_then(|lru_cache| {
+                lru_cache
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "cache")
+            })
+            .map(|leaf_caches| self.profile.map_entries(leaf_caches))
+            .unwrap_or_default();
+
+        let mut entries = Vec::new();
+        for (core_key, leaf_cache) in leaf_caches {
+            let shard = core_key
+                .object_id()
+                .and_then(|core_key| core_key_shards.get(&core_key))
+                .cloned();
+            let cached = match leaf_cache {
+                JavaLocalValue::Object(leaf_cache) => leaf_cache
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "cache")
+                    .map(|cache| self.profile.map_entries(cache))
+                    .unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            for (query, doc_id_set) in cached {
+                entries.push(CacheEntry {
+                    shard: shard.clone(),
+                    description: match query {
+                        JavaLocalValue::Object(query) => self.describe_query(query),
+                        query => query.type_name(&self.profile).into_owned(),
+                    },
+                    size: self.retained_size_of(doc_id_set),
+                });
+            }
+        }
+        entries
+    }
+
+    fn read_fielddata_cache(&self, fielddata_cache: &JavaInstance) -> Vec<CacheEntry> {
+        self.read_cache_field_entries(fielddata_cache)
+            .into_iter()
+            .map(|(key, value)| {
+                let fields = key.fields(&self.profile);
+                let index_cache = fields.value::<&JavaInstance>(&self.profile, "indexCache");
+                CacheEntry {
+                    shard: fields
+                        .value::<&JavaInstance>(&self.profile, "shardId")
+                        .and_then(|shard_id| self.read_shard_id(shard_id)),
+                    description: format!(
+                        "field {}",
+                        index_cache
+                            .and_then(|c| self.read_string_field(c, "fieldName"))
+                            .unwrap_or_else(|| "unknown".to_string())
+                    ),
+                    size: self.retained_size_of(value),
+                }
+            })
+            .collect()
+    }
+
+    /// Entries of the `org.elasticsearch.common.cache.Cache` kept in the `cache` field
+    fn read_cache_field_entries<'b>(
+        &'b self,
+        instance: &'b JavaInstance<'b>,
+    ) -> Vec<(&'b JavaInstance<'b>, JavaLocalValue<'b>)> {
+        match instance
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "cache")
+        {
+            Some(cache) => self.read_cache_entries(cache),
+            None => {
+                log::warn!(
+                    "Cache not found in {}",
+                    instance.name(&self.profile).unwrap_or("unknown")
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    /// Walks the LRU list of an `org.elasticsearch.common.cache.Cache`, which links all entries
+    /// across cache segments
+    fn read_cache_entries<'b>(
+        &'b self,
+        cache: &'b JavaInstance<'b>,
+    ) -> Vec<(&'b JavaInstance<'b>, JavaLocalValue<'b>)> {
+        let mut entries = Vec::new();
+        let mut visited = AHashSet::new();
+        let mut entry = cache
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "head");
+        while let Some(current) = entry {
+            if !visited.insert(current.id()) {
+                break;
+            }
+            let fields = current.fields(&self.profile);
+            if let Some(key) = fields.value::<&JavaInstance>(&self.profile, "key") {
+                entries.push((
+                    key,
+                    fields
+                        .value(&self.profile, "value")
+                        .unwrap_or(JavaLocalValue::Null),
+                ));
+            }
+            entry = fields.value(&self.profile, "after");
+        }
+        entries
+    }
+
+    /// Short description of a Lucene query, the query class with the term for term queries
+    fn describe_query(&self, query: &JavaInstance) -> String {
+        let class_name = query.name(&self.profile).unwrap_or("unknown");
+        let class_name = simple_class_name(class_name);
+        let term = query
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "term")
+            .and_then(|term| {
+                let field = self.read_string_field(term, "field")?;
+                let bytes: &JavaInstance =
+                    term.fields(&self.profile).value(&self.profile, "bytes")?;
+                Some(format!("{field}:{}", self.read_bytes_ref(bytes)?))
+            });
+        match term {
+            Some(term) => format!("{class_name}({term})"),
+            None => class_name.to_string(),
+        }
+    }
+
+    /// Reads a Lucene `BytesRef` as UTF-8
+    fn read_bytes_ref(&self, bytes_ref: &JavaInstance) -> Option<String> {
+        let fields = bytes_ref.fields(&self.profile);
+        let bytes: &JavaPrimitiveArray = fields.value(&self.profile, "bytes")?;
+        let offset = fields.value::<i32>(&self.profile, "offset")?.max(0) as usize;
+        let length = fields.value::<i32>(&self.profile, "length")?.max(0) as usize;
+        match bytes.values() {
+            PrimitiveArrayValues::Byte(bytes) => {
+                let bytes = bytes
+                    .get(offset..offset + length)?
+                    .iter()
+                    .map(|&b| b as u8)
+                    .collect::<Vec<_>>();
+                Some(String::from_utf8_lossy(&bytes).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    fn retained_size_of(&self, value: JavaLocalValue) -> u64 {
+        value
+            .object_id()
+            .and_then(|id| self.profile.retained_size(&id))
+            .unwrap_or(0)
+    }
+}
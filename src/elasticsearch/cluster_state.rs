@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+use ahash::AHashSet;
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::ElasticsearchMemory;
+
+const CLUSTER_APPLIER_SERVICE_CLASS: &str =
+    "org/elasticsearch/cluster/service/ClusterApplierService";
+const CLUSTER_STATE_CLASS: &str = "org/elasticsearch/cluster/ClusterState";
+/// Classes holding metadata outside of the cluster state, e.g. in shards and transport connections
+const METADATA_CLASSES: [&str; 3] = [
+    "org/elasticsearch/index/Index",
+    "org/elasticsearch/cluster/node/DiscoveryNode",
+    "org/elasticsearch/cluster/ClusterName",
+];
+
+#[derive(Serialize)]
+pub struct ClusterStateSummary {
+    pub cluster_name: Option<String>,
+    pub version: Option<i64>,
+    pub state_uuid: Option<String>,
+    pub master_node_id: Option<String>,
+    pub local_node_id: Option<String>,
+    pub nodes: Vec<DiscoveryNode>,
+    pub index_count: usize,
+    pub shards: ShardCounts,
+    /// All indices, largest mapping first
+    pub mappings: Vec<IndexMappingSize>,
+}
+
+#[derive(Serialize)]
+pub struct DiscoveryNode {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub address: Option<String>,
+    pub roles: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct ShardCounts {
+    pub primaries: usize,
+    pub replicas: usize,
+    /// Shard counts keyed by routing state (`STARTED`, `UNASSIGNED`, ...)
+    pub states: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize)]
+pub struct IndexMappingSize {
+    pub index: String,
+    /// Size of the compressed mapping source as kept in the heap
+    pub serialized_size: usize,
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads the cluster state applied on the node.
+    ///
+    /// Taken from `ClusterApplierService`, when that's not available the cluster state with the
+    /// highest version found in the heap is used.
+    pub fn read_cluster_state(&self) -> Option<ClusterStateSummary> {
+        let state = self.applied_cluster_state()?;
+        self.debug_instance(state);
+        Some(self.read_cluster_state_summary(state))
+    }
+
+    /// Cluster state taken from `ClusterApplierService`, when that's not available the cluster
+    /// state with the highest version found in the heap is used.
+    pub(super) fn applied_cluster_state(&self) -> Option<&JavaInstance<'_>> {
+        let applied = self
+            .profile
+            .get_class_by_name(CLUSTER_APPLIER_SERVICE_CLASS)
+            .and_then(|class| class.instances(&self.profile).into_iter().next())
+            .and_then(|service| {
+                service
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "state")
+            })
+            .and_then(|reference| {
+                reference
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "value")
+            });
+        applied.or_else(|| {
+            log::warn!("Applied cluster state not found, using the most recent one in heap");
+            self.profile
+                .get_class_by_name(CLUSTER_STATE_CLASS)?
+                .instances(&self.profile)
+                .into_iter()
+                .max_by_key(|state| self.read_long_field(state, "version"))
+        })
+    }
+
+    /// Primitive arrays reachable from the applied cluster state and other metadata objects, they
+    /// hold index names, settings, mappings and node names and attributes
+    pub fn read_metadata_arrays(&self) -> AHashSet<ObjectId> {
+        let mut pending = METADATA_CLASSES
+            .iter()
+            .flat_map(|class| self.profile.get_instances_by_class_name(class))
+            .chain(self.applied_cluster_state())
+            .map(|instance| instance.id())
+            .collect::<Vec<_>>();
+        let mut visited = AHashSet::new();
+        let mut arrays = AHashSet::new();
+        while let Some(object_id) = pending.pop() {
+            if !visited.insert(object_id) {
+                continue;
+            }
+            let references = match self.profile.get_object(&object_id) {
+                Some(Object::Instance(instance)) => instance.references(&self.profile),
+                Some(Object::Array(array)) => array.references(&self.profile),
+                Some(Object::PrimitiveArray(_)) => {
+                    arrays.insert(object_id);
+                    continue;
+                }
+                None => continue,
+            };
+            pending.extend(references.into_iter().map(ObjectId::from));
+        }
+        log::debug!(
+            "Found {} metadata arrays in {} objects",
+            arrays.len(),
+            visited.len()
+        );
+        arrays
+    }
+
+    /// `IndexMetadata` of all indices in the cluster state
+    pub(super) fn read_cluster_state_indices<'b>(
+        &'b self,
+        state: &'b JavaInstance<'b>,
+    ) -> Vec<&'b JavaInstance<'b>> {
+        let fields = state.fields(&self.profile);
+        fields
+            .value::<&JavaInstance>(&self.profile, "metadata")
+            .or_else(|| fields.value(&self.profile, "metaData"))
+            .and_then(|metadata| {
+                metadata
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "indices")
+            })
+            .map(|indices| self.map_values(indices))
+            .unwrap_or_default()
+    }
+
+    fn read_cluster_state_summary(&self, state: &JavaInstance) -> ClusterStateSummary {
+        let fields = state.fields(&self.profile);
+        let nodes = fields.value::<&JavaInstance>(&self.profile, "nodes");
+        let indices = self.read_cluster_state_indices(state);
+
+        let mut mappings = indices
+            .iter()
+            .filter_map(|index| self.read_index_mapping_size(index))
+            .collect::<Vec<_>>();
+        mappings.sort_by_key(|m| std::cmp::Reverse(m.serialized_size));
+
+        ClusterStateSummary {
+            cluster_name: fields
+                .value::<&JavaInstance>(&self.profile, "clusterName")
+                .and_then(|name| self.read_string_field(name, "value")),
+            version: self.read_long_field(state, "version"),
+            state_uuid: self.read_string_field(state, "stateUUID"),
+            master_node_id: nodes.and_then(|n| self.read_string_field(n, "masterNodeId")),
+            local_node_id: nodes.and_then(|n| self.read_string_field(n, "localNodeId")),
+            nodes: self.read_discovery_nodes(state),
+            index_count: indices.len(),
+            shards: fields
+                .value::<&JavaInstance>(&self.profile, "routingTable")
+                .map(|routing| self.read_shard_counts(routing))
+                .unwrap_or_default(),
+            mappings,
+        }
+    }
+
+    /// Nodes of the cluster state
+    pub(super) fn read_discovery_nodes(&self, state: &JavaInstance) -> Vec<DiscoveryNode> {
+        state
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "nodes")
+            .and_then(|nodes| {
+                nodes
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "nodes")
+            })
+            .map(|nodes| self.map_values(nodes))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|node| self.read_discovery_node(node))
+            .collect()
+    }
+
+    fn read_discovery_node(&self, node: &JavaInstance) -> DiscoveryNode {
+        let fields = node.fields(&self.profile);
+        DiscoveryNode {
+            id: self.read_string_field(node, "nodeId"),
+            name: self.read_string_field(node, "nodeName"),
+            address: self
+                .read_string_field(node, "hostAddress")
+                .or_else(|| self.read_string_field(node, "hostName")),
+            roles: fields
+                .value::<&JavaInstance>(&self.profile, "roles")
+                .map(|roles| self.profile.collection_items(roles))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|role| match role {
+                    JavaLocalValue::Object(role) => self.read_string_field(role, "roleName"),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn read_shard_counts(&self, routing_table: &JavaInstance) -> ShardCounts {
+        let mut counts = ShardCounts::default();
+        let indices = routing_table
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "indicesRouting")
+            .map(|indices| self.map_values(indices))
+            .unwrap_or_default();
+        for index in indices {
+            // shard tables are kept in an int keyed map up to 7.x and in an array since 8.0
+            let shard_tables = match index.fields(&self.profile).value(&self.profile, "shards") {
+                Some(JavaLocalValue::ObjectArray(shards)) => {
+                    shards.values(&self.profile).flatten().collect()
+                }
+                Some(JavaLocalValue::Object(shards)) => self.map_values(shards),
+                _ => Vec::new(),
+            };
+            for shard_table in shard_tables {
+                let shards = match shard_table
+                    .fields(&self.profile)
+                    .value(&self.profile, "shards")
+                {
+                    Some(JavaLocalValue::ObjectArray(shards)) => {
+                        shards.values(&self.profile).flatten().collect()
+                    }
+                    Some(JavaLocalValue::Object(shards)) => self
+                        .profile
+                        .collection_items(shards)
+                        .into_iter()
+                        .filter_map(|shard| match shard {
+                            JavaLocalValue::Object(shard) => Some(shard),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                for shard in shards {
+                    let fields = shard.fields(&self.profile);
+                    if fields.value(&self.profile, "primary").unwrap_or(false) {
+                        counts.primaries += 1;
+                    } else {
+                        counts.replicas += 1;
+                    }
+                    let state = fields
+                        .value::<&JavaInstance>(&self.profile, "state")
+                        .and_then(|state| self.read_enum_name(state))
+                        .unwrap_or_else(|| "unknown".to_string());
+                    *counts.states.entry(state).or_default() += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    fn read_index_mapping_size(&self, index_metadata: &JavaInstance) -> Option<IndexMappingSize> {
+        let fields = index_metadata.fields(&self.profile);
+        let index = fields
+            .value::<&JavaInstance>(&self.profile, "index")
+            .and_then(|index| self.read_string_field(index, "name"))?;
+        let serialized_size = self
+            .read_index_mappings(index_metadata)
+            .into_iter()
+            .filter_map(|mapping| self.read_mapping_source(mapping))
+            .map(|source| source.len())
+            .sum();
+        Some(IndexMappingSize {
+            index,
+            serialized_size,
+        })
+    }
+}
@@ -0,0 +1,107 @@
+//! Decompression of payloads compressed by Elasticsearch's compressors.
+//!
+//! `CompressedXContent` and compressed transport messages are prefixed with a header naming the
+//! scheme, payloads without a known header are returned unchanged. HTTP bodies are compressed
+//! according to their `Content-Encoding` instead.
+
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context};
+
+/// `DeflateCompressor`, raw deflate stream without zlib header
+const DEFLATE_HEADER: &[u8] = b"DFL\0";
+/// Transport LZ4, a sequence of blocks in the lz4-java `LZ4BlockOutputStream` format
+const LZ4_HEADER: &[u8] = b"LZ4\0";
+/// `LZFCompressor`, dropped in 6.0
+const LZF_HEADER: &[u8] = b"ZV";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+const LZ4_BLOCK_MAGIC: &[u8] = b"LZ4Block";
+const LZ4_BLOCK_HEADER_LENGTH: usize = LZ4_BLOCK_MAGIC.len() + 1 + 3 * 4;
+const LZ4_BLOCK_RAW: u8 = 0x10;
+const LZ4_BLOCK_COMPRESSED: u8 = 0x20;
+
+/// Decompresses bytes written by any of the supported compressors
+pub fn decompress(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if let Some(deflated) = bytes.strip_prefix(DEFLATE_HEADER) {
+        let mut inflated = Vec::with_capacity(deflated.len() * 4);
+        flate2::read::DeflateDecoder::new(deflated)
+            .read_to_end(&mut inflated)
+            .context("Failed to inflate deflate compressed bytes")?;
+        Ok(inflated)
+    } else if let Some(blocks) = bytes.strip_prefix(LZ4_HEADER) {
+        decompress_lz4_blocks(blocks).context("Failed to decompress lz4 compressed bytes")
+    } else if bytes.starts_with(LZF_HEADER) {
+        Err(anyhow!("LZF compressed content is not supported"))
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Inflates an HTTP body compressed with `gzip` or `deflate`, detected by the content encoding or
+/// the gzip magic. Returns the encoding alongside the inflated body, `None` for bodies that are not
+/// compressed
+pub fn inflate_http_body(
+    content_encoding: Option<&str>,
+    bytes: &[u8],
+) -> anyhow::Result<Option<(&'static str, Vec<u8>)>> {
+    let encoding = content_encoding
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let mut inflated = Vec::with_capacity(bytes.len() * 4);
+    if encoding == "gzip" || encoding == "x-gzip" || bytes.starts_with(GZIP_MAGIC) {
+        flate2::read::MultiGzDecoder::new(bytes)
+            .read_to_end(&mut inflated)
+            .context("Failed to inflate gzip body")?;
+        Ok(Some(("gzip", inflated)))
+    } else if encoding == "deflate" {
+        // HTTP deflate is a zlib stream, some clients send raw deflate instead
+        if flate2::read::ZlibDecoder::new(bytes)
+            .read_to_end(&mut inflated)
+            .is_err()
+        {
+            inflated.clear();
+            flate2::read::DeflateDecoder::new(bytes)
+                .read_to_end(&mut inflated)
+                .context("Failed to inflate deflate body")?;
+        }
+        Ok(Some(("deflate", inflated)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decompress_lz4_blocks(mut blocks: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    while !blocks.is_empty() {
+        if blocks.len() < LZ4_BLOCK_HEADER_LENGTH || !blocks.starts_with(LZ4_BLOCK_MAGIC) {
+            bail!("Invalid block header");
+        }
+        let token = blocks[LZ4_BLOCK_MAGIC.len()];
+        let int_at = |offset: usize| {
+            let offset = LZ4_BLOCK_MAGIC.len() + 1 + offset * 4;
+            u32::from_le_bytes([
+                blocks[offset],
+                blocks[offset + 1],
+                blocks[offset + 2],
+                blocks[offset + 3],
+            ]) as usize
+        };
+        let compressed_length = int_at(0);
+        let decompressed_length = int_at(1);
+        let data = blocks
+            .get(LZ4_BLOCK_HEADER_LENGTH..LZ4_BLOCK_HEADER_LENGTH + compressed_length)
+            .ok_or(anyhow!("Block truncated"))?;
+        match token & 0xF0 {
+            LZ4_BLOCK_RAW => decompressed.extend_from_slice(data),
+            LZ4_BLOCK_COMPRESSED => decompressed.extend(
+                lz4_flex::block::decompress(data, decompressed_length)
+                    .context("Invalid block data")?,
+            ),
+            method => bail!("Unknown block compression method {method:#x}"),
+        }
+        blocks = &blocks[LZ4_BLOCK_HEADER_LENGTH + compressed_length..];
+    }
+    Ok(decompressed)
+}
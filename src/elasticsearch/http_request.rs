@@ -0,0 +1,599 @@
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::hprof::*;
+
+use super::compression::inflate_http_body;
+use super::query_cost::QueryRisk;
+use super::query_shape::QueryShape;
+use super::xcontent::{self, XContentType};
+use super::ElasticsearchMemory;
+
+const REST_REQUEST_CLASS: &str = "org/elasticsearch/rest/RestRequest";
+/// `AbstractRefCounted` of bodies backed by recycled memory
+const REF_COUNTED_FIELD: &str = "refCounted";
+/// Base of netty's `HttpObjectAggregator`
+const MESSAGE_AGGREGATOR_CLASS: &str = "io/netty/handler/codec/MessageAggregator";
+/// Base of reference counted netty buffers, keeps the count in `refCnt`
+const REFERENCE_COUNTED_BYTE_BUF_CLASS: &str = "io/netty/buffer/AbstractReferenceCountedByteBuf";
+/// Only part of netty versions that double reference counts, like the `REFCNT_FIELD_OFFSET` static
+const REFERENCE_COUNT_UPDATER_CLASS: &str = "io/netty/util/internal/ReferenceCountUpdater";
+/// Headers with credentials, their values are replaced before being reported
+const REDACTED_HEADERS: [&str; 2] = ["authorization", "proxy-authorization"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestState {
+    /// Dispatched or waiting to be, body still referenced
+    Pending,
+    /// Handled and released, kept on heap until garbage collected
+    Released,
+    /// Still being received, not yet passed to Elasticsearch
+    Receiving,
+}
+
+impl Display for RequestState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            RequestState::Pending => "pending",
+            RequestState::Released => "released",
+            RequestState::Receiving => "receiving",
+        })
+    }
+}
+
+pub struct InflightRequest {
+    /// Heap object the request was read from, `Netty4HttpRequest` or the message being aggregated
+    pub object_id: ObjectId,
+    pub class_name: String,
+    pub state: RequestState,
+    /// Reference count of the body, when it is reference counted
+    pub ref_count: Option<i32>,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    /// Request headers in the order they were received, credentials redacted
+    pub headers: Vec<(String, String)>,
+    /// Address of the client, only known once the request was dispatched to a REST handler
+    pub remote_address: Option<String>,
+    /// Body as JSON or text, decompressed and converted from binary x-content when needed
+    pub body: String,
+    /// Length of the body as received
+    pub body_length: usize,
+    /// Why the body could not be read, e.g. when held off heap by a direct buffer
+    pub body_unavailable: Option<String>,
+    /// Encodings removed from the body in the order they were undone, e.g. `gzip`, `smile`
+    pub body_encodings: Vec<&'static str>,
+    /// Set for requests still being received, the announced `Content-Length` or 0 when unknown
+    pub expected_body_length: Option<usize>,
+}
+
+impl InflightRequest {
+    /// Whether the body was only partially received
+    pub fn is_incomplete(&self) -> bool {
+        self.expected_body_length.is_some()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Cost heuristics of the body, `None` unless it is a search
+    pub fn risk(&self) -> Option<QueryRisk> {
+        QueryRisk::of(self.uri.as_deref(), &self.body)
+    }
+
+    /// Request details without the body, saved next to the body
+    pub fn metadata(&self) -> Value {
+        json!({
+            "state": self.state.to_string(),
+            "ref_count": self.ref_count,
+            "method": self.method,
+            "uri": self.uri,
+            "remote_address": self.remote_address,
+            "opaque_id": self.header("X-Opaque-Id"),
+            "user_agent": self.header("User-Agent"),
+            "headers": self
+                .headers
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+            "body_length": self.body_length,
+            "body_encodings": self.body_encodings,
+            "body_unavailable": self.body_unavailable,
+            "fingerprint": QueryShape::of(&self.body).map(|shape| shape.fingerprint),
+            "risk": self.risk(),
+            "incomplete": self.is_incomplete(),
+            "expected_body_length": self.expected_body_length.filter(|&length| length > 0),
+        })
+    }
+
+    /// Request as a single record, the body parsed when it is valid JSON
+    pub fn to_json(&self, id: usize) -> Value {
+        let mut record = json!({
+            "id": id,
+            "object_id": self.object_id.to_string(),
+            "class": self.class_name,
+        });
+        if let (Value::Object(record), Value::Object(metadata)) = (&mut record, self.metadata()) {
+            record.extend(metadata);
+            record.insert(
+                "body".to_string(),
+                serde_json::from_str(&self.body)
+                    .unwrap_or_else(|_| Value::String(self.body.clone())),
+            );
+        }
+        record
+    }
+}
+
+/// Prints request details as `#` prefixed lines followed by the body
+impl Display for InflightRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "# {} {}",
+            self.method.as_deref().unwrap_or("-"),
+            self.uri.as_deref().unwrap_or("-")
+        )?;
+        match self.ref_count {
+            Some(ref_count) => writeln!(f, "# state: {} (ref count {ref_count})", self.state)?,
+            None => writeln!(f, "# state: {}", self.state)?,
+        }
+        writeln!(
+            f,
+            "# remote address: {}",
+            self.remote_address.as_deref().unwrap_or("-")
+        )?;
+        for (name, value) in &self.headers {
+            writeln!(f, "# {name}: {value}")?;
+        }
+        match self.expected_body_length {
+            Some(0) => writeln!(f, "# incomplete: still being received")?,
+            Some(expected) => writeln!(
+                f,
+                "# incomplete: {} of {expected} bytes received",
+                self.body_length
+            )?,
+            None => {}
+        }
+        if let Some(reason) = &self.body_unavailable {
+            writeln!(f, "# body unavailable: {reason}")?;
+        } else if self.body_encodings.is_empty() {
+            writeln!(f, "# body length: {}", self.body_length)?;
+        } else {
+            writeln!(
+                f,
+                "# body length: {} ({})",
+                self.body_length,
+                self.body_encodings.join(", ")
+            )?;
+        }
+        if let Some(risk) = self.risk() {
+            writeln!(f, "# risk: {risk}")?;
+        }
+        write!(f, "{}", self.body)
+    }
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads method, uri and headers of a `Netty4HttpRequest` alongside its body
+    pub(super) fn read_inflight_request(
+        &self,
+        http_request: &JavaInstance,
+        (state, ref_count): (RequestState, Option<i32>),
+        body: anyhow::Result<Vec<u8>>,
+        remote_addresses: &AHashMap<ObjectId, String>,
+    ) -> InflightRequest {
+        let fields = http_request.fields(&self.profile);
+        let (method, uri, mut headers) = fields
+            .value::<&JavaInstance>(&self.profile, "request")
+            .map(|request| self.read_netty_request(request))
+            .unwrap_or_default();
+        if headers.is_empty() {
+            // `Map<String, List<String>>` kept by elasticsearch
+            headers = fields
+                .value::<&JavaInstance>(&self.profile, "headers")
+                .map(|headers| self.read_header_map(headers))
+                .unwrap_or_default();
+        }
+        let mut request = InflightRequest {
+            object_id: http_request.id(),
+            class_name: http_request
+                .name(&self.profile)
+                .unwrap_or("unknown")
+                .to_string(),
+            state,
+            ref_count,
+            method,
+            uri,
+            headers: redact_credentials(headers),
+            remote_address: remote_addresses.get(&http_request.id()).cloned(),
+            body: String::new(),
+            body_length: 0,
+            expected_body_length: None,
+            body_unavailable: None,
+            body_encodings: Vec::new(),
+        };
+        request.set_body(body);
+        request
+    }
+
+    /// State of a `Netty4HttpRequest` from its `released` flag and the reference count of its
+    /// body, kept by a `ReleasableBytesReference` or the netty buffer of the request
+    pub(super) fn read_request_state(
+        &self,
+        http_request: &JavaInstance,
+    ) -> (RequestState, Option<i32>) {
+        let fields = http_request.fields(&self.profile);
+        let released = fields
+            .value::<&JavaInstance>(&self.profile, "released")
+            .and_then(|released| {
+                released
+                    .fields(&self.profile)
+                    .value::<i32>(&self.profile, "value")
+            })
+            == Some(1);
+        let content_ref_count = fields
+            .value::<&JavaInstance>(&self.profile, "content")
+            .and_then(|content| {
+                content
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, REF_COUNTED_FIELD)
+            })
+            .and_then(|ref_counted| self.read_int_or_atomic(ref_counted, "refCount"));
+        let ref_count = content_ref_count.or_else(|| {
+            fields
+                .value::<&JavaInstance>(&self.profile, "request")
+                .and_then(|request| {
+                    request
+                        .fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "content")
+                })
+                .and_then(|buffer| {
+                    buffer
+                        .fields(&self.profile)
+                        .value::<i32>(&self.profile, "refCnt")
+                })
+                // netty 4.1.32+ keeps twice the count, odd once released, older versions the
+                // count itself. Unknown unless the version can be told apart
+                .filter(|_| self.doubles_netty_ref_counts())
+                .map(|raw| if raw & 1 == 1 { 0 } else { raw >> 1 })
+        });
+        let state = if released || ref_count == Some(0) {
+            RequestState::Released
+        } else {
+            RequestState::Pending
+        };
+        (state, ref_count)
+    }
+
+    /// Whether netty buffers keep twice their reference count, as netty 4.1.32+ does
+    fn doubles_netty_ref_counts(&self) -> bool {
+        self.profile
+            .get_class_by_name(REFERENCE_COUNT_UPDATER_CLASS)
+            .is_some()
+            || self
+                .profile
+                .get_class_by_name(REFERENCE_COUNTED_BYTE_BUF_CLASS)
+                .and_then(|class| class.static_value(&self.profile, "REFCNT_FIELD_OFFSET"))
+                .is_some()
+    }
+
+    fn read_int_or_atomic(&self, instance: &JavaInstance, name: &str) -> Option<i32> {
+        match instance.fields(&self.profile).value(&self.profile, name)? {
+            JavaLocalValue::Int(value) => Some(value),
+            JavaLocalValue::Object(atomic) => {
+                atomic.fields(&self.profile).value(&self.profile, "value")
+            }
+            _ => None,
+        }
+    }
+
+    /// Requests still being received, their content is aggregated by netty's
+    /// `HttpObjectAggregator` until the last chunk arrives
+    pub(super) fn read_partial_requests(&self) -> Vec<InflightRequest> {
+        let mut requests = Vec::new();
+        for aggregator in self
+            .profile
+            .get_instances_by_class_name(MESSAGE_AGGREGATOR_CLASS)
+        {
+            let fields = aggregator.fields(&self.profile);
+            // cleared once the full message was passed on
+            let current = match fields.value::<&JavaInstance>(&self.profile, "currentMessage") {
+                Some(current) => current,
+                None => continue,
+            };
+            self.debug_instance(current);
+            let current_fields = current.fields(&self.profile);
+            let (method, uri, headers) = self.read_netty_request(
+                current_fields
+                    .value::<&JavaInstance>(&self.profile, "message")
+                    .unwrap_or(current),
+            );
+            let remote_address = fields
+                .value::<&JavaInstance>(&self.profile, "ctx")
+                .and_then(|ctx| {
+                    ctx.fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "pipeline")
+                })
+                .and_then(|pipeline| {
+                    pipeline
+                        .fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "channel")
+                })
+                .and_then(|channel| {
+                    channel
+                        .fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "remoteAddress")
+                })
+                .and_then(|address| self.read_socket_address(address));
+            let body = current_fields
+                .value::<&JavaInstance>(&self.profile, "content")
+                .ok_or_else(|| anyhow!("content not found"))
+                .and_then(|content| self.read_byte_buf_content(content));
+
+            let mut request = InflightRequest {
+                object_id: current.id(),
+                class_name: current.name(&self.profile).unwrap_or("unknown").to_string(),
+                state: RequestState::Receiving,
+                ref_count: None,
+                method,
+                uri,
+                headers: redact_credentials(headers),
+                remote_address,
+                body: String::new(),
+                body_length: 0,
+                expected_body_length: None,
+                body_unavailable: None,
+                body_encodings: Vec::new(),
+            };
+            // chunked requests have no length until the last chunk
+            request.expected_body_length = Some(
+                request
+                    .header("Content-Length")
+                    .and_then(|length| length.trim().parse().ok())
+                    .unwrap_or(0),
+            );
+            request.set_body(body);
+            requests.push(request);
+        }
+        requests
+    }
+
+    /// Method, uri and headers of a netty `HttpRequest`
+    fn read_netty_request(
+        &self,
+        request: &JavaInstance,
+    ) -> (Option<String>, Option<String>, Vec<(String, String)>) {
+        let fields = request.fields(&self.profile);
+        let method = fields
+            .value::<&JavaInstance>(&self.profile, "method")
+            .and_then(|method| {
+                method
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "name")
+            })
+            .and_then(|name| self.read_char_sequence(name));
+        let uri = self.read_string_field(request, "uri");
+        let headers = fields
+            .value::<&JavaInstance>(&self.profile, "headers")
+            .map(|headers| self.read_netty_headers(headers))
+            .unwrap_or_default();
+        (method, uri, headers)
+    }
+
+    /// Maps HTTP requests dispatched to REST handlers to the remote address of their channel
+    pub(super) fn read_remote_addresses(&self) -> AHashMap<ObjectId, String> {
+        let mut addresses = AHashMap::new();
+        for rest_request in self.profile.get_instances_by_class_name(REST_REQUEST_CLASS) {
+            let fields = rest_request.fields(&self.profile);
+            let http_request = match fields.value::<&JavaInstance>(&self.profile, "httpRequest") {
+                Some(http_request) => http_request,
+                None => continue,
+            };
+            // Netty4HttpChannel wraps the netty channel which caches its remote address
+            if let Some(address) = fields
+                .value::<&JavaInstance>(&self.profile, "httpChannel")
+                .and_then(|channel| {
+                    channel
+                        .fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "channel")
+                })
+                .and_then(|channel| {
+                    channel
+                        .fields(&self.profile)
+                        .value::<&JavaInstance>(&self.profile, "remoteAddress")
+                })
+                .and_then(|address| self.read_socket_address(address))
+            {
+                addresses.insert(http_request.id(), address);
+            }
+        }
+        addresses
+    }
+
+    /// Reads netty `DefaultHttpHeaders`, entries are linked in insertion order from `head`
+    fn read_netty_headers(&self, headers: &JavaInstance) -> Vec<(String, String)> {
+        let fields = headers.fields(&self.profile);
+        let head = match fields.value::<&JavaInstance>(&self.profile, "head") {
+            Some(head) => head,
+            // DefaultHttpHeaders delegates to DefaultHeaders
+            None => {
+                return fields
+                    .value::<&JavaInstance>(&self.profile, "headers")
+                    .map(|headers| self.read_netty_headers(headers))
+                    .unwrap_or_default()
+            }
+        };
+        let mut entries = Vec::new();
+        let mut visited = AHashSet::from([head.id()]);
+        let mut entry = head
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "after");
+        while let Some(current) = entry {
+            if !visited.insert(current.id()) {
+                break;
+            }
+            let fields = current.fields(&self.profile);
+            if let (Some(name), Some(value)) = (
+                fields
+                    .value::<&JavaInstance>(&self.profile, "key")
+                    .and_then(|key| self.read_char_sequence(key)),
+                fields
+                    .value::<&JavaInstance>(&self.profile, "value")
+                    .and_then(|value| self.read_char_sequence(value)),
+            ) {
+                entries.push((name, value));
+            }
+            entry = fields.value(&self.profile, "after");
+        }
+        entries
+    }
+
+    fn read_header_map(&self, headers: &JavaInstance) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        for (name, values) in self.profile.map_entries(headers) {
+            let name = match name {
+                JavaLocalValue::Object(name) => match name.string_value(&self.profile) {
+                    Some(name) => name,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let JavaLocalValue::Object(values) = values {
+                for value in self.profile.collection_items(values) {
+                    if let JavaLocalValue::Object(value) = value {
+                        if let Some(value) = value.string_value(&self.profile) {
+                            entries.push((name.clone(), value));
+                        }
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    /// Reads a `String` or netty `AsciiString`
+    fn read_char_sequence(&self, chars: &JavaInstance) -> Option<String> {
+        if chars.name(&self.profile) == Some("java/lang/String") {
+            return chars.string_value(&self.profile);
+        }
+        let fields = chars.fields(&self.profile);
+        let bytes: &JavaPrimitiveArray = fields.value(&self.profile, "value")?;
+        let offset = fields
+            .value::<i32>(&self.profile, "offset")
+            .unwrap_or(0)
+            .max(0) as usize;
+        let length = fields.value::<i32>(&self.profile, "length")?.max(0) as usize;
+        match bytes.values() {
+            PrimitiveArrayValues::Byte(bytes) => Some(
+                bytes
+                    .get(offset..offset + length)?
+                    .iter()
+                    .map(|&b| b as u8 as char)
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Formats `java.net.InetSocketAddress` as `ip:port`
+    fn read_socket_address(&self, address: &JavaInstance) -> Option<String> {
+        let holder = address
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "holder")
+            .unwrap_or(address);
+        let fields = holder.fields(&self.profile);
+        let port: i32 = fields.value(&self.profile, "port")?;
+        let ip = fields
+            .value::<&JavaInstance>(&self.profile, "addr")
+            .and_then(|inet| self.read_inet_address(inet));
+        match ip {
+            Some(IpAddr::V6(ip)) => Some(format!("[{ip}]:{port}")),
+            Some(ip) => Some(format!("{ip}:{port}")),
+            None => self
+                .read_string_field(holder, "hostname")
+                .map(|hostname| format!("{hostname}:{port}")),
+        }
+    }
+
+    fn read_inet_address(&self, inet: &JavaInstance) -> Option<IpAddr> {
+        let fields = inet.fields(&self.profile);
+        if let Some(holder6) = fields.value::<&JavaInstance>(&self.profile, "holder6") {
+            let address: &JavaPrimitiveArray = holder6
+                .fields(&self.profile)
+                .value(&self.profile, "ipaddress")?;
+            if let PrimitiveArrayValues::Byte(bytes) = address.values() {
+                let bytes: [u8; 16] = bytes
+                    .iter()
+                    .map(|&b| b as u8)
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .ok()?;
+                return Some(IpAddr::V6(Ipv6Addr::from(bytes)));
+            }
+            return None;
+        }
+        let address: i32 = fields
+            .value::<&JavaInstance>(&self.profile, "holder")?
+            .fields(&self.profile)
+            .value(&self.profile, "address")?;
+        Some(IpAddr::V4(Ipv4Addr::from(address as u32)))
+    }
+}
+
+impl InflightRequest {
+    /// Decodes the body, or records why it is unavailable
+    fn set_body(&mut self, body: anyhow::Result<Vec<u8>>) {
+        match body {
+            Ok(body) => {
+                self.body_length = body.len();
+                self.body = decode_body(self, &body);
+            }
+            Err(err) => self.body_unavailable = Some(format!("{err:#}")),
+        }
+    }
+}
+
+/// Replaces the credentials of authorization headers, keeping their scheme
+fn redact_credentials(mut headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    for (name, value) in headers.iter_mut() {
+        if REDACTED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            *value = match value.split_once(' ') {
+                Some((scheme, _)) => format!("{scheme} [REDACTED]"),
+                None => "[REDACTED]".to_string(),
+            };
+        }
+    }
+    headers
+}
+
+/// Makes a body readable, inflating compressed bodies and converting x-content to pretty JSON.
+/// Undecodable bodies are kept as they are
+fn decode_body(request: &mut InflightRequest, body: &[u8]) -> String {
+    let inflated = match inflate_http_body(request.header("Content-Encoding"), body) {
+        Ok(Some((encoding, inflated))) => {
+            request.body_encodings.push(encoding);
+            inflated
+        }
+        Ok(None) => body.to_vec(),
+        Err(err) => {
+            log::warn!("Failed to decompress request body: {:#}", err);
+            body.to_vec()
+        }
+    };
+    let (content_type, body) = xcontent::to_pretty_json(request.header("Content-Type"), &inflated);
+    if content_type != XContentType::Json {
+        request.body_encodings.push(content_type.name());
+    }
+    body
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+use crate::hprof::*;
+
+use super::xcontent::{self, XContentType};
+use super::{compression, ElasticsearchMemory};
+
+pub struct IndexMetadataDump {
+    pub index: String,
+    /// Flat settings, values are strings or lists of strings
+    pub settings: BTreeMap<String, Value>,
+    /// Mapping sources keyed by type, a single `_doc` type since 7.0
+    pub mappings: Map<String, Value>,
+}
+
+impl IndexMetadataDump {
+    /// Settings and mappings in the same shape as returned by `GET <index>`
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "settings": self.settings,
+            "mappings": self.mappings,
+        })
+    }
+
+    /// Number of mapped fields, including object fields and multi-fields
+    pub fn field_count(&self) -> usize {
+        fn count(properties: &Value) -> usize {
+            properties
+                .as_object()
+                .map(|properties| {
+                    properties
+                        .values()
+                        .map(|field| {
+                            1 + count(&field["properties"])
+                                + field["fields"].as_object().map_or(0, |f| f.len())
+                        })
+                        .sum()
+                })
+                .unwrap_or(0)
+        }
+        self.mappings
+            .values()
+            .map(|mapping| count(&mapping["properties"]))
+            .sum()
+    }
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads settings and mappings of all indices in the applied cluster state
+    pub fn read_index_metadata(&self) -> Vec<IndexMetadataDump> {
+        let mut indices = self
+            .applied_cluster_state()
+            .map(|state| self.read_cluster_state_indices(state))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|index_metadata| {
+                self.debug_instance(index_metadata);
+                self.read_index_metadata_dump(index_metadata)
+            })
+            .collect::<Vec<_>>();
+        indices.sort_by(|a, b| a.index.cmp(&b.index));
+        indices
+    }
+
+    fn read_index_metadata_dump(&self, index_metadata: &JavaInstance) -> Option<IndexMetadataDump> {
+        let fields = index_metadata.fields(&self.profile);
+        let index = fields
+            .value::<&JavaInstance>(&self.profile, "index")
+            .and_then(|index| self.read_string_field(index, "name"))?;
+
+        let settings = fields
+            .value::<&JavaInstance>(&self.profile, "settings")
+            .and_then(|settings| {
+                settings
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "settings")
+            })
+            .map(|settings| self.read_settings(settings))
+            .unwrap_or_default();
+
+        let mut mappings = Map::new();
+        for mapping in self.read_index_mappings(index_metadata) {
+            let source = match self.read_mapping_source(mapping) {
+                Some(source) => source,
+                None => {
+                    log::warn!("Mapping source of index {index} not found");
+                    continue;
+                }
+            };
+            match compression::decompress(&source)
+                .and_then(|source| xcontent::parse(XContentType::detect(None, &source), &source))
+            {
+                // sources are wrapped in an object keyed by the mapping type
+                Ok(Value::Object(source)) => mappings.extend(source),
+                Ok(_) => log::warn!("Mapping source of index {index} is not an object"),
+                Err(err) => log::warn!("Failed to read mapping source of index {index}: {err:#}"),
+            }
+        }
+
+        Some(IndexMetadataDump {
+            index,
+            settings,
+            mappings,
+        })
+    }
+
+    fn read_settings(&self, settings: &JavaInstance) -> BTreeMap<String, Value> {
+        self.profile
+            .map_entries(settings)
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let key = match key {
+                    JavaLocalValue::Object(key) => key.string_value(&self.profile)?,
+                    _ => return None,
+                };
+                let value = match value {
+                    JavaLocalValue::Object(value) => match value.string_value(&self.profile) {
+                        Some(value) => Value::String(value),
+                        None => Value::Array(
+                            self.profile
+                                .collection_items(value)
+                                .into_iter()
+                                .filter_map(|item| match item {
+                                    JavaLocalValue::Object(item) => {
+                                        item.string_value(&self.profile)
+                                    }
+                                    _ => None,
+                                })
+                                .map(Value::String)
+                                .collect(),
+                        ),
+                    },
+                    _ => Value::Null,
+                };
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    /// `MappingMetadata` instances of an index, a single mapping since 8.0, mappings keyed by
+    /// type before
+    pub(super) fn read_index_mappings<'b>(
+        &'b self,
+        index_metadata: &'b JavaInstance<'b>,
+    ) -> Vec<&'b JavaInstance<'b>> {
+        let fields = index_metadata.fields(&self.profile);
+        match fields.value::<&JavaInstance>(&self.profile, "mapping") {
+            Some(mapping) => vec![mapping],
+            None => fields
+                .value::<&JavaInstance>(&self.profile, "mappings")
+                .map(|mappings| self.map_values(mappings))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Compressed mapping source as kept in `CompressedXContent`
+    pub(super) fn read_mapping_source(&self, mapping: &JavaInstance) -> Option<Vec<u8>> {
+        let source: &JavaInstance = mapping
+            .fields(&self.profile)
+            .value(&self.profile, "source")?;
+        let bytes: &JavaPrimitiveArray =
+            source.fields(&self.profile).value(&self.profile, "bytes")?;
+        match bytes.values() {
+            PrimitiveArrayValues::Byte(bytes) => Some(bytes.into_iter().map(|b| b as u8).collect()),
+            _ => None,
+        }
+    }
+}
@@ -1,11 +1,46 @@
-use anyhow::{anyhow, Context};
+mod bytes_reference;
+mod caches;
+mod cluster_state;
+mod compression;
+mod http_request;
+mod index_metadata;
+mod node;
+mod object_json;
+mod query_cost;
+mod query_shape;
+mod search_contexts;
+mod segments;
+#[cfg(test)]
+mod tests;
+mod thread_pools;
+mod transport;
+mod xcontent;
+
+use std::fmt::Display;
+
+use anyhow::anyhow;
+use serde::Serialize;
 
 use crate::hprof::*;
+pub use http_request::{InflightRequest, RequestState};
+pub use node::NodeInfo;
+pub use query_shape::group_by_shape;
+pub use search_contexts::*;
+pub use segments::*;
 
 const HTTP_REQUEST_CLASS: &str = "org/elasticsearch/http/netty4/Netty4HttpRequest";
-const COMPOSITE_BYTES_REFERENCE_CLASS: &str =
-    "org/elasticsearch/common/bytes/CompositeBytesReference";
-const BYTES_ARRAY_CLASS: &str = "org/elasticsearch/common/bytes/BytesArray";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct ShardId {
+    pub index: String,
+    pub shard: i32,
+}
+
+impl Display for ShardId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}][{}]", self.index, self.shard)
+    }
+}
 
 pub struct ElasticsearchMemory<'a> {
     profile: JavaProfile<'a>,
@@ -19,118 +54,107 @@ impl<'a> ElasticsearchMemory<'a> {
         Self { profile }
     }
 
-    pub fn read_inflight_queries(&self) -> Vec<String> {
+    /// Reads HTTP requests in flight, requests already released only when `include_released`
+    pub fn read_inflight_queries(&self, include_released: bool) -> Vec<InflightRequest> {
         let mut queries = Vec::new();
         if let Some(class) = self.profile.get_class_by_name(HTTP_REQUEST_CLASS) {
+            let remote_addresses = self.read_remote_addresses();
             for http_request in class.instances(&self.profile) {
                 log::debug!("Located HttpRequest {}", http_request.id());
-                if let Some(i) = http_request
-                    .fields(&self.profile)
-                    .value::<&JavaInstance>(&self.profile, "released")
-                {
-                    if i.fields(&self.profile).value(&self.profile, "value") == Some(1i32) {
-                        log::debug!("Request released, skipping");
-                        continue;
-                    }
+                let (state, ref_count) = self.read_request_state(http_request);
+                if state == RequestState::Released && !include_released {
+                    log::debug!("Request released, skipping");
+                    continue;
                 }
                 self.debug_instance(http_request);
-                match self.read_request_data(http_request) {
-                    Ok(query) => {
-                        if query.is_empty() {
-                            log::warn!("Extracted query is empty, skipping");
-                        } else {
-                            queries.push(query);
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("Failed to read query from request: {:#}", err)
+                let body = self.read_request_data(http_request);
+                match &body {
+                    Ok(body) if body.is_empty() => {
+                        log::warn!("Extracted query is empty, skipping");
+                        continue;
                     }
+                    Ok(_) => {}
+                    Err(err) => log::warn!("Failed to read query from request: {:#}", err),
                 }
+                queries.push(self.read_inflight_request(
+                    http_request,
+                    (state, ref_count),
+                    body,
+                    &remote_addresses,
+                ));
             }
         }
+        queries.extend(self.read_partial_requests());
 
         queries
     }
 
-    fn read_request_data(&self, http_request: &'a JavaInstance) -> anyhow::Result<String> {
+    /// Size of the objects kept alive by the object, see [`JavaProfile::retained_size`]
+    pub fn retained_size(&self, object_id: &ObjectId) -> Option<u64> {
+        self.profile.retained_size(object_id)
+    }
+
+    /// Objects in the heap grouped by class, largest first
+    pub fn read_class_histogram(&self) -> Vec<ClassUsage> {
+        self.profile.class_histogram()
+    }
+
+    fn read_request_data(&self, http_request: &'a JavaInstance) -> anyhow::Result<Vec<u8>> {
         let fields = http_request.fields(&self.profile);
         let content: &JavaInstance = fields
             .value(&self.profile, "content")
             .ok_or(anyhow!("content not found"))?;
+        self.read_bytes_reference(content)
+    }
 
-        match content
-            .class(&self.profile)
-            .map(|c| c.name(&self.profile))
-            .unwrap_or("unknown")
-        {
-            COMPOSITE_BYTES_REFERENCE_CLASS => self.read_composite_bytes(content),
-            BYTES_ARRAY_CLASS => self.read_bytes_array(content),
-            class_name => Err(anyhow!("Unknown content class {class_name}")),
-        }
+    fn read_string_field(&self, instance: &JavaInstance, name: &str) -> Option<String> {
+        instance
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, name)
+            .and_then(|s| s.string_value(&self.profile))
     }
 
-    fn read_bytes_array(&self, instance: &'a JavaInstance) -> anyhow::Result<String> {
-        self.debug_instance(instance);
-        let fields = instance.fields(&self.profile);
-        let bytes: &JavaPrimitiveArray = fields
-            .value(&self.profile, "bytes")
-            .ok_or(anyhow!("bytes not found"))?;
-
-        let offset: i32 = fields
-            .value(&self.profile, "offset")
-            .ok_or(anyhow!("offset not found"))?;
-
-        let length: i32 = fields
-            .value(&self.profile, "length")
-            .ok_or(anyhow!("length not found"))?;
-
-        if let PrimitiveArrayValues::Byte(bytes) = bytes.values() {
-            let offset = offset as usize;
-            let length = length as usize;
-            let parsed = bytes[offset..offset + length]
-                .iter()
-                .map(|&i| i as u8)
-                .collect::<Vec<_>>();
-            let str = String::from_utf8_lossy(&parsed);
-            Ok(str.to_string())
-        } else {
-            Err(anyhow!("Expected array of bytes"))
+    /// Reads a `long` field, unwrapping it from `AtomicLong` when needed
+    fn read_long_field(&self, instance: &JavaInstance, name: &str) -> Option<i64> {
+        match instance.fields(&self.profile).value(&self.profile, name)? {
+            JavaLocalValue::Long(value) => Some(value),
+            JavaLocalValue::Object(atomic) => {
+                atomic.fields(&self.profile).value(&self.profile, "value")
+            }
+            _ => None,
         }
     }
 
-    fn read_composite_bytes(&self, composite_bytes: &JavaInstance) -> anyhow::Result<String> {
-        self.debug_instance(composite_bytes);
-        let fields = composite_bytes.fields(&self.profile);
-        let references: &JavaObjectArray = fields
-            .value(&self.profile, "references")
-            .ok_or(anyhow!("failed to read references"))?;
-
-        let references_class_name = references.class_name(&self.profile).unwrap_or("unknown");
-        if self
-            .profile
-            .is_subclass_by_name(references_class_name, BYTES_ARRAY_CLASS)
-            .unwrap_or(false)
-        {
-            log::warn!("Expected {BYTES_ARRAY_CLASS}, found {references_class_name}. Trying anyways, but it'll likely not succeed");
-        }
+    fn read_enum_name(&self, instance: &JavaInstance) -> Option<String> {
+        self.read_string_field(instance, "name")
+    }
 
-        let query = references
-            .values(&self.profile)
-            .filter_map(|i| {
-                if let Some(i) = i {
-                    Some(
-                        self.read_bytes_array(i)
-                            .context("Failed read bytes array instance"),
-                    )
-                } else {
-                    log::warn!("Could not read chunk request fragment! Expect corrupted query");
-                    None
-                }
+    /// Values of a map that are instances, skipping nulls and arrays
+    fn map_values<'b>(&'b self, map: &'b JavaInstance<'b>) -> Vec<&'b JavaInstance<'b>> {
+        self.profile
+            .map_entries(map)
+            .into_iter()
+            .filter_map(|(_, value)| match value {
+                JavaLocalValue::Object(value) => Some(value),
+                _ => None,
             })
-            .collect::<anyhow::Result<Vec<String>>>()?
-            .join("");
+            .collect()
+    }
+
+    fn read_shard_id(&self, shard_id: &JavaInstance) -> Option<ShardId> {
+        let fields = shard_id.fields(&self.profile);
+        let index: &JavaInstance = fields.value(&self.profile, "index")?;
+        Some(ShardId {
+            index: self.read_string_field(index, "name")?,
+            shard: fields.value(&self.profile, "shardId")?,
+        })
+    }
 
-        Ok(query)
+    fn read_index_shard_id(&self, index_shard: &JavaInstance) -> Option<ShardId> {
+        index_shard
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "shardId")
+            .and_then(|shard_id| self.read_shard_id(shard_id))
     }
 
     fn debug_instance(&self, instance: &JavaInstance) {
@@ -0,0 +1,74 @@
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::ElasticsearchMemory;
+
+/// Holds the build of the running node in `CURRENT`, up to 8.9
+const BUILD_CLASS: &str = "org/elasticsearch/Build";
+/// Holds the build of the running node in `CURRENT` since 8.10
+const BUILD_HOLDER_CLASS: &str = "org/elasticsearch/Build$CurrentHolder";
+const VERSION_CLASS: &str = "org/elasticsearch/Version";
+
+#[derive(Serialize)]
+pub struct NodeInfo {
+    pub name: Option<String>,
+    pub id: Option<String>,
+    pub version: Option<String>,
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads the name of the node the dump was taken on and its Elasticsearch version
+    pub fn read_node_info(&self) -> NodeInfo {
+        let state = self.applied_cluster_state();
+        let local_node_id = state
+            .and_then(|state| {
+                state
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "nodes")
+            })
+            .and_then(|nodes| self.read_string_field(nodes, "localNodeId"));
+        let name = state.zip(local_node_id.as_ref()).and_then(|(state, id)| {
+            self.read_discovery_nodes(state)
+                .into_iter()
+                .find(|node| node.id.as_ref() == Some(id))
+                .and_then(|node| node.name)
+        });
+        NodeInfo {
+            name,
+            id: local_node_id,
+            version: self.read_version(),
+        }
+    }
+
+    /// Version of the running node, taken from its build and from `Version.CURRENT` in versions
+    /// where the build is not found
+    fn read_version(&self) -> Option<String> {
+        let build = [BUILD_HOLDER_CLASS, BUILD_CLASS]
+            .iter()
+            .filter_map(|class| self.profile.get_class_by_name(class))
+            .find_map(|class| match class.static_value(&self.profile, "CURRENT") {
+                Some(JavaLocalValue::Object(build)) => Some(build),
+                _ => None,
+            });
+        if let Some(version) = build.and_then(|build| self.read_string_field(build, "version")) {
+            return Some(version);
+        }
+        let version = match self
+            .profile
+            .get_class_by_name(VERSION_CLASS)?
+            .static_value(&self.profile, "CURRENT")?
+        {
+            JavaLocalValue::Object(version) => version,
+            _ => return None,
+        };
+        // e.g. 7170999 for 7.17.9
+        let id: i32 = version.fields(&self.profile).value(&self.profile, "id")?;
+        Some(format!(
+            "{}.{}.{}",
+            id / 1_000_000,
+            id / 10_000 % 100,
+            id / 100 % 100
+        ))
+    }
+}
@@ -0,0 +1,197 @@
+//! Reconstruction of Java objects as JSON from their fields.
+//!
+//! Used for request objects that only exist in their parsed form on heap, e.g. `SearchSourceBuilder`
+//! of a queued shard search. The output follows the objects' field names rather than the
+//! Elasticsearch REST syntax, query builders are wrapped in an object named after their type
+//! (`TermQueryBuilder` becomes `{"term": {...}}`) to keep the query tree readable.
+
+use serde_json::{Map, Number, Value};
+
+use crate::hprof::*;
+
+use super::xcontent;
+use super::ElasticsearchMemory;
+
+/// Guards against reference cycles and very deep object trees
+const MAX_DEPTH: usize = 24;
+
+/// `java.lang` wrappers of primitive values
+const BOXED_CLASSES: [&str; 8] = [
+    "java/lang/Boolean",
+    "java/lang/Character",
+    "java/lang/Byte",
+    "java/lang/Short",
+    "java/lang/Integer",
+    "java/lang/Long",
+    "java/lang/Float",
+    "java/lang/Double",
+];
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reconstructs an object and everything it references as JSON
+    pub(super) fn object_to_json(&self, value: JavaLocalValue) -> Value {
+        self.value_to_json(value, 0)
+    }
+
+    fn value_to_json(&self, value: JavaLocalValue, depth: usize) -> Value {
+        if depth > MAX_DEPTH {
+            return Value::String("...".to_string());
+        }
+        match value {
+            JavaLocalValue::Object(instance) => self.instance_to_json(instance, depth),
+            JavaLocalValue::ObjectArray(array) => Value::Array(
+                array
+                    .elements(&self.profile)
+                    .into_iter()
+                    .map(|element| self.value_to_json(element, depth + 1))
+                    .collect(),
+            ),
+            JavaLocalValue::PrimitiveArray(array) => match array.values() {
+                PrimitiveArrayValues::Char(chars) => {
+                    Value::String(String::from_utf16_lossy(&chars))
+                }
+                PrimitiveArrayValues::Byte(bytes) => {
+                    // e.g. the source of a `WrapperQueryBuilder`
+                    let bytes = bytes.iter().map(|&b| b as u8).collect::<Vec<_>>();
+                    xcontent::parse_detected(&bytes).unwrap_or_else(|| {
+                        Value::String(String::from_utf8_lossy(&bytes).into_owned())
+                    })
+                }
+                PrimitiveArrayValues::Boolean(values) => Value::from(values),
+                PrimitiveArrayValues::Float(values) => Value::Array(
+                    values
+                        .into_iter()
+                        .map(|f| float_to_json(f64::from(f)))
+                        .collect(),
+                ),
+                PrimitiveArrayValues::Double(values) => {
+                    Value::Array(values.into_iter().map(float_to_json).collect())
+                }
+                PrimitiveArrayValues::Short(values) => Value::from(values),
+                PrimitiveArrayValues::Int(values) => Value::from(values),
+                PrimitiveArrayValues::Long(values) => Value::from(values),
+            },
+            JavaLocalValue::Boolean(b) => Value::Bool(b),
+            JavaLocalValue::Char(ch) => Value::String(String::from_utf16_lossy(&[ch])),
+            JavaLocalValue::Float(f) => float_to_json(f64::from(f)),
+            JavaLocalValue::Double(d) => float_to_json(d),
+            JavaLocalValue::Byte(b) => Value::from(b),
+            JavaLocalValue::Short(s) => Value::from(s),
+            JavaLocalValue::Int(i) => Value::from(i),
+            JavaLocalValue::Long(l) => Value::from(l),
+            JavaLocalValue::Null => Value::Null,
+        }
+    }
+
+    fn instance_to_json(&self, instance: &JavaInstance, depth: usize) -> Value {
+        let class_name = instance.name(&self.profile).unwrap_or("unknown");
+        if class_name == "java/lang/String" {
+            return instance
+                .string_value(&self.profile)
+                .map(Value::String)
+                .unwrap_or(Value::Null);
+        }
+        // sources kept serialized, e.g. by `ShardSearchLocalRequest` before 5.0
+        if self.is_bytes_reference(class_name) {
+            if let Ok(bytes) = self.read_bytes_reference(instance) {
+                return xcontent::parse_detected(&bytes).unwrap_or_else(|| {
+                    Value::String(String::from_utf8_lossy(&bytes).into_owned())
+                });
+            }
+        }
+        let fields = instance.fields(&self.profile);
+        if BOXED_CLASSES.contains(&class_name) {
+            return fields
+                .value(&self.profile, "value")
+                .map(|value| self.value_to_json(value, depth + 1))
+                .unwrap_or(Value::Null);
+        }
+        // enums
+        if fields.fields.contains_key("ordinal") {
+            if let Some(name) = self.read_enum_name(instance) {
+                return Value::String(name);
+            }
+        }
+        if class_name.starts_with("java/util/") || class_name.starts_with("com/carrotsearch/hppc/")
+        {
+            let simple_name = simple_class_name(class_name);
+            if simple_name.contains("Map") {
+                return Value::Object(
+                    self.profile
+                        .map_entries(instance)
+                        .into_iter()
+                        .map(|(key, value)| {
+                            let key = match self.value_to_json(key, depth + 1) {
+                                Value::String(key) => key,
+                                key => key.to_string(),
+                            };
+                            (key, self.value_to_json(value, depth + 1))
+                        })
+                        .collect(),
+                );
+            }
+            if ["List", "Set", "Queue", "Deque", "Collection"]
+                .iter()
+                .any(|kind| simple_name.contains(kind))
+            {
+                return Value::Array(
+                    self.profile
+                        .collection_items(instance)
+                        .into_iter()
+                        .map(|item| self.value_to_json(item, depth + 1))
+                        .collect(),
+                );
+            }
+        }
+
+        let mut object = Map::new();
+        let mut names = fields.fields.keys().copied().collect::<Vec<_>>();
+        names.sort_unstable();
+        for name in names {
+            // synthetic fields of inner classes and lambdas
+            if name.starts_with("this$") {
+                continue;
+            }
+            match fields.value::<JavaLocalValue>(&self.profile, name) {
+                Some(JavaLocalValue::Null) | None => {}
+                Some(value) => {
+                    object.insert(name.to_string(), self.value_to_json(value, depth + 1));
+                }
+            }
+        }
+        match simple_class_name(class_name).strip_suffix("QueryBuilder") {
+            Some(query_type) if !query_type.is_empty() => {
+                let mut query = Map::new();
+                query.insert(snake_case(query_type), Value::Object(object));
+                Value::Object(query)
+            }
+            _ => Value::Object(object),
+        }
+    }
+}
+
+fn float_to_json(f: f64) -> Value {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+/// Class name without package and outer classes
+pub(super) fn simple_class_name(class_name: &str) -> &str {
+    class_name.rsplit(['/', '$']).next().unwrap_or(class_name)
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.chars().enumerate() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(ch.to_lowercase());
+        } else {
+            snake.push(ch);
+        }
+    }
+    snake
+}
@@ -0,0 +1,468 @@
+//! Heuristics flagging expensive constructs of the Query DSL.
+//!
+//! Each kind of construct has a weight, the risk score of a query sums the weights of the kinds
+//! found, capped at 100. Weights are rough, they order queries for triage rather than predict their
+//! cost.
+
+use std::fmt::Display;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Endpoints whose bodies are searches, other bodies (`_bulk` documents, ...) are not analyzed
+const SEARCH_ENDPOINTS: [&str; 6] = [
+    "_search",
+    "_msearch",
+    "_count",
+    "_by_query",
+    "_explain",
+    "_validate/query",
+];
+/// Terms lists longer than this are flagged, `index.max_terms_count` defaults to 65536
+const LARGE_TERMS_LIST: usize = 1024;
+/// Default `index.max_result_window`
+const MAX_RESULT_WINDOW: i64 = 10_000;
+const LARGE_AGGREGATION_SIZE: i64 = 1_000;
+const MAX_AGGREGATION_DEPTH: usize = 3;
+const MAX_SCORE: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskKind {
+    LeadingWildcard,
+    Regexp,
+    LargeTerms,
+    DeepPagination,
+    LargeTermsAggregation,
+    DeepAggregations,
+    Script,
+    TrackTotalHits,
+}
+
+impl RiskKind {
+    fn weight(self) -> u32 {
+        match self {
+            RiskKind::LeadingWildcard => 30,
+            RiskKind::Regexp => 20,
+            RiskKind::LargeTerms => 20,
+            RiskKind::DeepPagination => 30,
+            RiskKind::LargeTermsAggregation => 25,
+            RiskKind::DeepAggregations => 15,
+            RiskKind::Script => 20,
+            RiskKind::TrackTotalHits => 10,
+        }
+    }
+}
+
+impl Display for RiskKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            RiskKind::LeadingWildcard => "leading_wildcard",
+            RiskKind::Regexp => "regexp",
+            RiskKind::LargeTerms => "large_terms",
+            RiskKind::DeepPagination => "deep_pagination",
+            RiskKind::LargeTermsAggregation => "large_terms_aggregation",
+            RiskKind::DeepAggregations => "deep_aggregations",
+            RiskKind::Script => "script",
+            RiskKind::TrackTotalHits => "track_total_hits",
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct RiskFlag {
+    pub kind: RiskKind,
+    /// Where the construct was found, e.g. the field of a wildcard query
+    pub detail: String,
+}
+
+#[derive(Default, Serialize)]
+pub struct QueryRisk {
+    pub score: u32,
+    pub flags: Vec<RiskFlag>,
+}
+
+impl QueryRisk {
+    /// Analyzes the body of a request to a search endpoint, `None` for other endpoints and bodies
+    /// that are not JSON. Newline delimited bodies are analyzed line by line
+    pub fn of(uri: Option<&str>, body: &str) -> Option<Self> {
+        if let Some(uri) = uri {
+            let path = uri.split('?').next().unwrap_or(uri);
+            if !SEARCH_ENDPOINTS
+                .iter()
+                .any(|endpoint| path.contains(endpoint))
+            {
+                return None;
+            }
+        }
+        let mut analyzer = Analyzer::default();
+        match serde_json::from_str::<Value>(body) {
+            Ok(json) => analyzer.visit_root(&json),
+            Err(_) => {
+                let lines = body
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(serde_json::from_str::<Value>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()?;
+                for line in &lines {
+                    analyzer.visit_root(line);
+                }
+            }
+        }
+        let mut kinds = analyzer
+            .flags
+            .iter()
+            .map(|flag| flag.kind)
+            .collect::<Vec<_>>();
+        kinds.sort_unstable();
+        kinds.dedup();
+        Some(QueryRisk {
+            score: kinds
+                .into_iter()
+                .map(RiskKind::weight)
+                .sum::<u32>()
+                .min(MAX_SCORE),
+            flags: analyzer.flags,
+        })
+    }
+}
+
+/// Short summary, the score followed by the flagged constructs
+impl Display for QueryRisk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.score)?;
+        if !self.flags.is_empty() {
+            let flags = self
+                .flags
+                .iter()
+                .map(|flag| format!("{} {}", flag.kind, flag.detail))
+                .collect::<Vec<_>>();
+            write!(f, " ({})", flags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Analyzer {
+    flags: Vec<RiskFlag>,
+}
+
+impl Analyzer {
+    fn flag(&mut self, kind: RiskKind, detail: String) {
+        self.flags.push(RiskFlag { kind, detail });
+    }
+
+    fn visit_root(&mut self, value: &Value) {
+        if let Value::Object(object) = value {
+            self.check_pagination(object);
+        }
+        self.visit(value, 0);
+    }
+
+    /// Walks the DSL, `aggregation_depth` counts the aggregations enclosing `value`
+    fn visit(&mut self, value: &Value, aggregation_depth: usize) {
+        let object = match value {
+            Value::Object(object) => object,
+            Value::Array(values) => {
+                for value in values {
+                    self.visit(value, aggregation_depth);
+                }
+                return;
+            }
+            _ => return,
+        };
+        for (key, value) in object {
+            let mut depth = aggregation_depth;
+            match key.as_str() {
+                "wildcard" => self.check_patterns(value, RiskKind::LeadingWildcard),
+                "regexp" => self.check_patterns(value, RiskKind::Regexp),
+                "query_string" | "simple_query_string" => self.check_query_string(value),
+                "terms" => self.check_terms(value),
+                "top_hits" | "inner_hits" => {
+                    if let Value::Object(hits) = value {
+                        self.check_pagination(hits);
+                    }
+                }
+                "script" | "script_score" | "scripted_metric" | "script_fields" => {
+                    // nested scripts are the same construct, e.g. the script of a script query
+                    self.flag(RiskKind::Script, key.clone());
+                    continue;
+                }
+                "_script" => {
+                    // script based sorting runs the script for every matching document
+                    self.flag(RiskKind::Script, "sort _script".to_string());
+                    continue;
+                }
+                "track_total_hits" if value == &Value::Bool(true) => {
+                    self.flag(RiskKind::TrackTotalHits, "true".to_string())
+                }
+                "aggs" | "aggregations" => {
+                    depth += 1;
+                    if depth == MAX_AGGREGATION_DEPTH + 1 {
+                        self.flag(
+                            RiskKind::DeepAggregations,
+                            format!("more than {MAX_AGGREGATION_DEPTH} levels"),
+                        );
+                    }
+                }
+                _ => {}
+            }
+            self.visit(value, depth);
+        }
+    }
+
+    /// `from + size` of the request, `top_hits` or `inner_hits` beyond the result window
+    fn check_pagination(&mut self, object: &Map<String, Value>) {
+        let from = object.get("from").and_then(Value::as_i64).unwrap_or(0);
+        let size = object.get("size").and_then(Value::as_i64).unwrap_or(0);
+        if from.max(0) + size.max(0) > MAX_RESULT_WINDOW {
+            self.flag(RiskKind::DeepPagination, format!("from {from} size {size}"));
+        }
+    }
+
+    /// `wildcard` and `regexp` queries, `{"field": "pattern"}` or `{"field": {"value": "pattern"}}`.
+    /// Wildcards are flagged when leading, regular expressions always
+    fn check_patterns(&mut self, query: &Value, kind: RiskKind) {
+        let fields = match query.as_object() {
+            Some(fields) => fields,
+            None => return,
+        };
+        for (field, pattern) in fields {
+            let pattern = match pattern {
+                Value::String(pattern) => pattern.as_str(),
+                Value::Object(options) => match options
+                    .get("value")
+                    .or_else(|| options.get("wildcard"))
+                    .and_then(Value::as_str)
+                {
+                    Some(pattern) => pattern,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if kind == RiskKind::Regexp || pattern.starts_with(['*', '?']) {
+                self.flag(kind, format!("{field}:{pattern}"));
+            }
+        }
+    }
+
+    /// Terms of a query string starting with a wildcard
+    fn check_query_string(&mut self, query: &Value) {
+        let text = match query.get("query").and_then(Value::as_str) {
+            Some(text) => text,
+            None => return,
+        };
+        for term in text.split_whitespace() {
+            // strip field names, grouping and operators
+            let term = term.rsplit(':').next().unwrap_or(term);
+            let term = term.trim_start_matches(['(', '+', '-', '"']);
+            if term.starts_with(['*', '?']) {
+                self.flag(RiskKind::LeadingWildcard, format!("query_string {term}"));
+            } else if term.starts_with('/') && term.len() > 1 {
+                self.flag(RiskKind::Regexp, format!("query_string {term}"));
+            }
+        }
+    }
+
+    /// `terms` queries with long lists and `terms` aggregations with a large size
+    fn check_terms(&mut self, terms: &Value) {
+        let terms = match terms.as_object() {
+            Some(terms) => terms,
+            None => return,
+        };
+        if let Some(field) = terms.get("field").and_then(Value::as_str) {
+            let size = ["size", "shard_size"]
+                .iter()
+                .filter_map(|name| terms.get(*name).and_then(Value::as_i64))
+                .max()
+                .unwrap_or(0);
+            if size > LARGE_AGGREGATION_SIZE {
+                self.flag(
+                    RiskKind::LargeTermsAggregation,
+                    format!("{field} size {size}"),
+                );
+            }
+            return;
+        }
+        for (field, values) in terms {
+            if let Value::Array(values) = values {
+                if values.len() > LARGE_TERMS_LIST {
+                    self.flag(
+                        RiskKind::LargeTerms,
+                        format!("{field} {} values", values.len()),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn risk(body: Value) -> QueryRisk {
+        QueryRisk::of(Some("/logs/_search"), &body.to_string()).unwrap()
+    }
+
+    fn kinds(risk: &QueryRisk) -> Vec<RiskKind> {
+        risk.flags.iter().map(|flag| flag.kind).collect()
+    }
+
+    #[test]
+    fn flags_each_kind() {
+        let terms = (0..=LARGE_TERMS_LIST).collect::<Vec<_>>();
+        let cases = [
+            (
+                RiskKind::LeadingWildcard,
+                json!({"query": {"wildcard": {"path": {"value": "*.log"}}}}),
+                "path:*.log",
+            ),
+            (
+                RiskKind::LeadingWildcard,
+                json!({"query": {"query_string": {"query": "host:(*prod OR web)"}}}),
+                "query_string *prod",
+            ),
+            (
+                RiskKind::Regexp,
+                json!({"query": {"regexp": {"host": ".*-prod"}}}),
+                "host:.*-prod",
+            ),
+            (
+                RiskKind::Regexp,
+                json!({"query": {"simple_query_string": {"query": "/web-[0-9]+/"}}}),
+                "query_string /web-[0-9]+/",
+            ),
+            (
+                RiskKind::LargeTerms,
+                json!({"query": {"terms": {"id": terms}}}),
+                "id 1025 values",
+            ),
+            (
+                RiskKind::DeepPagination,
+                json!({"from": 9990, "size": 50, "query": {"match_all": {}}}),
+                "from 9990 size 50",
+            ),
+            (
+                RiskKind::DeepPagination,
+                json!({"aggs": {"hits": {"top_hits": {"from": 10000, "size": 1}}}}),
+                "from 10000 size 1",
+            ),
+            (
+                RiskKind::LargeTermsAggregation,
+                json!({"aggs": {"hosts": {"terms": {"field": "host", "size": 5000}}}}),
+                "host size 5000",
+            ),
+            (
+                RiskKind::DeepAggregations,
+                json!({"aggs": {"a": {"terms": {"field": "a"}, "aggs": {"b": {
+                    "terms": {"field": "b"}, "aggs": {"c": {"terms": {"field": "c"},
+                    "aggs": {"d": {"avg": {"field": "d"}}}}}}}}}}),
+                "more than 3 levels",
+            ),
+            (
+                RiskKind::Script,
+                json!({"query": {"script_score": {"query": {"match_all": {}},
+                    "script": {"source": "doc['a'].value"}}}}),
+                "script_score",
+            ),
+            (
+                RiskKind::Script,
+                json!({"sort": [{"_script": {"type": "number",
+                    "script": {"source": "doc['a'].value * 2"}}}]}),
+                "sort _script",
+            ),
+            (
+                RiskKind::TrackTotalHits,
+                json!({"track_total_hits": true, "query": {"match_all": {}}}),
+                "true",
+            ),
+        ];
+        for (kind, body, detail) in cases {
+            let risk = risk(body);
+            assert_eq!(vec![kind], kinds(&risk), "{}", detail);
+            assert_eq!(detail, risk.flags[0].detail);
+            assert_eq!(kind.weight(), risk.score);
+        }
+    }
+
+    #[test]
+    fn benign_query_scores_zero() {
+        let risk = risk(json!({
+            "from": 20,
+            "size": 10,
+            "track_total_hits": 10000,
+            "query": {"bool": {
+                "must": [{"match": {"message": "error"}}],
+                "filter": [
+                    {"wildcard": {"path": "logs-*"}},
+                    {"terms": {"level": ["warn", "error"]}},
+                    {"query_string": {"query": "host:web* AND status:500"}},
+                ],
+            }},
+            "aggs": {"hosts": {"terms": {"field": "host", "size": 10},
+                "aggs": {"latency": {"avg": {"field": "latency"}}}}},
+            "sort": [{"@timestamp": "desc"}],
+        }));
+        assert_eq!(0, risk.score);
+        assert!(risk.flags.is_empty());
+        assert_eq!("0", risk.to_string());
+    }
+
+    #[test]
+    fn counts_each_kind_once() {
+        let risk = risk(json!({"query": {"bool": {"should": [
+            {"wildcard": {"a": "*x"}},
+            {"wildcard": {"b": "*y"}},
+            {"regexp": {"c": "z.*"}},
+        ]}}}));
+        assert_eq!(3, risk.flags.len());
+        assert_eq!(
+            RiskKind::LeadingWildcard.weight() + RiskKind::Regexp.weight(),
+            risk.score
+        );
+    }
+
+    #[test]
+    fn caps_score() {
+        let risk = risk(json!({
+            "from": 20000,
+            "track_total_hits": true,
+            "query": {"bool": {"should": [
+                {"wildcard": {"a": "*x"}},
+                {"regexp": {"c": "z.*"}},
+                {"script": {"script": "true"}},
+            ]}},
+        }));
+        assert_eq!(5, risk.flags.len());
+        assert_eq!(MAX_SCORE, risk.score);
+    }
+
+    #[test]
+    fn analyzes_search_endpoints_only() {
+        let body = r#"{"query":{"regexp":{"host":".*"}}}"#;
+        assert!(QueryRisk::of(Some("/logs/_doc/1"), body).is_none());
+        assert!(QueryRisk::of(Some("/_bulk"), body).is_none());
+        assert!(QueryRisk::of(Some("/logs/_count?q=x"), body).is_some());
+        assert!(QueryRisk::of(None, body).is_some());
+        assert!(QueryRisk::of(Some("/_search"), "not json").is_none());
+    }
+
+    #[test]
+    fn analyzes_msearch_line_by_line() {
+        let body = "{\"index\":\"logs\"}\n{\"query\":{\"regexp\":{\"host\":\".*\"}}}\n\
+            {}\n{\"from\":10000,\"size\":10}\n";
+        let risk = QueryRisk::of(Some("/_msearch"), body).unwrap();
+        assert_eq!(
+            vec![RiskKind::Regexp, RiskKind::DeepPagination],
+            kinds(&risk)
+        );
+        assert_eq!(
+            "50 (regexp host:.*, deep_pagination from 10000 size 10)",
+            risk.to_string()
+        );
+    }
+}
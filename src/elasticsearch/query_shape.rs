@@ -0,0 +1,226 @@
+//! Normalization of extracted queries to their shape.
+//!
+//! Literal values are replaced with placeholders naming their type and keys are sorted, so queries
+//! that only differ in the searched values share a shape. Shapes are identified by a fingerprint
+//! that is stable across runs and dumps.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::InflightRequest;
+
+/// Normalized shape of a request body
+pub struct QueryShape {
+    pub fingerprint: String,
+    pub normalized: Value,
+}
+
+/// Requests with the same body shape
+#[derive(Serialize)]
+pub struct QueryGroup {
+    pub fingerprint: String,
+    pub count: usize,
+    /// Position of the requests in the list they were grouped from
+    pub ids: Vec<usize>,
+    /// Distinct `METHOD uri` of the requests, uris vary more than bodies, e.g. by index
+    pub endpoints: Vec<String>,
+    /// Highest risk score of the requests, `None` when none is a search
+    pub risk: Option<u32>,
+    pub normalized: Value,
+}
+
+impl QueryShape {
+    /// Shape of a JSON body, or of every line of newline delimited bodies (`_msearch`, `_bulk`).
+    /// `None` for bodies that are not JSON
+    pub fn of(body: &str) -> Option<Self> {
+        let normalized = match serde_json::from_str::<Value>(body) {
+            Ok(json) => normalize(&json),
+            Err(_) => Value::Array(
+                body.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| serde_json::from_str::<Value>(line).map(|json| normalize(&json)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|lines| !lines.is_empty())?,
+            ),
+        };
+        Some(Self {
+            fingerprint: fingerprint(&normalized),
+            normalized,
+        })
+    }
+}
+
+/// Replaces literals with placeholders and sorts keys. Arrays of literals collapse to a single
+/// placeholder so `terms` lists of any length share a shape
+pub fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Null => Value::Null,
+        Value::Bool(_) => Value::String("<bool>".to_string()),
+        Value::Number(_) => Value::String("<number>".to_string()),
+        Value::String(_) => Value::String("<string>".to_string()),
+        Value::Array(values) => {
+            let mut normalized = Vec::new();
+            for value in values.iter().map(normalize) {
+                if !value.is_object() && !value.is_array() && normalized.contains(&value) {
+                    continue;
+                }
+                normalized.push(value);
+            }
+            Value::Array(normalized)
+        }
+        Value::Object(object) => {
+            let mut keys = object.keys().collect::<Vec<_>>();
+            keys.sort_unstable();
+            Value::Object(
+                keys.into_iter()
+                    .map(|key| (key.clone(), normalize(&object[key])))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+    }
+}
+
+/// 64 bit FNV-1a of the compact serialization, as hex
+pub fn fingerprint(normalized: &Value) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let hash = normalized
+        .to_string()
+        .bytes()
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        });
+    format!("{hash:016x}")
+}
+
+/// Groups requests by the shape of their body, most frequent shape first. Requests without a JSON
+/// body are left out
+pub fn group_by_shape(requests: &[InflightRequest]) -> Vec<QueryGroup> {
+    let mut groups: Vec<QueryGroup> = Vec::new();
+    for (id, request) in requests.iter().enumerate() {
+        let shape = match QueryShape::of(&request.body) {
+            Some(shape) => shape,
+            None => continue,
+        };
+        let risk = request.risk().map(|risk| risk.score);
+        let endpoint = format!(
+            "{} {}",
+            request.method.as_deref().unwrap_or("-"),
+            request.uri.as_deref().unwrap_or("-")
+        );
+        match groups
+            .iter_mut()
+            .find(|group| group.fingerprint == shape.fingerprint)
+        {
+            Some(group) => {
+                group.count += 1;
+                group.ids.push(id);
+                group.risk = group.risk.max(risk);
+                if !group.endpoints.contains(&endpoint) {
+                    group.endpoints.push(endpoint);
+                }
+            }
+            None => groups.push(QueryGroup {
+                fingerprint: shape.fingerprint,
+                count: 1,
+                ids: vec![id],
+                endpoints: vec![endpoint],
+                risk,
+                normalized: shape.normalized,
+            }),
+        }
+    }
+    groups.sort_by(|a, b| b.count.cmp(&a.count).then(a.ids.cmp(&b.ids)));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fingerprint_of(body: &str) -> String {
+        QueryShape::of(body).unwrap().fingerprint
+    }
+
+    #[test]
+    fn queries_differing_in_literals_share_a_fingerprint() {
+        let a = r#"{"query":{"bool":{"filter":[{"term":{"user":"alice"}},{"range":{"age":{"gte":18}}}]}},"size":10}"#;
+        let b = r#"{"query":{"bool":{"filter":[{"term":{"user":"bob"}},{"range":{"age":{"gte":65}}}]}},"size":500}"#;
+        assert_eq!(fingerprint_of(a), fingerprint_of(b));
+        // terms lists of any length collapse to a single placeholder
+        assert_eq!(
+            fingerprint_of(r#"{"query":{"terms":{"id":["1"]}}}"#),
+            fingerprint_of(r#"{"query":{"terms":{"id":["1","2","3"]}}}"#)
+        );
+    }
+
+    #[test]
+    fn structurally_different_queries_do_not_share_a_fingerprint() {
+        let shapes = [
+            r#"{"query":{"term":{"user":"alice"}}}"#,
+            r#"{"query":{"match":{"user":"alice"}}}"#,
+            r#"{"query":{"term":{"name":"alice"}}}"#,
+            r#"{"query":{"term":{"user":"alice"}},"size":10}"#,
+            r#"{"query":{"term":{"user":1}}}"#,
+            r#"{"query":{"term":{"user":true}}}"#,
+            r#"{"query":{"term":{"user":null}}}"#,
+            r#"{"query":{"bool":{"must":[{"term":{"user":"alice"}}]}}}"#,
+            r#"{"query":{"bool":{"must":[{"term":{"user":"a"}},{"term":{"user":"b"}}]}}}"#,
+        ];
+        let mut fingerprints = shapes.iter().map(|s| fingerprint_of(s)).collect::<Vec<_>>();
+        fingerprints.sort();
+        fingerprints.dedup();
+        assert_eq!(shapes.len(), fingerprints.len());
+    }
+
+    #[test]
+    fn key_order_does_not_matter() {
+        let a = r#"{"size":10,"query":{"range":{"age":{"gte":18,"lt":65}}},"sort":["age"]}"#;
+        let b = r#"{"sort":["name"],"query":{"range":{"age":{"lt":30,"gte":20}}},"size":5}"#;
+        assert_eq!(fingerprint_of(a), fingerprint_of(b));
+    }
+
+    #[test]
+    fn normalizes_literals_to_placeholders() {
+        let normalized = normalize(&json!({
+            "b": [1, 2, {"c": "x"}, {"c": "y"}],
+            "a": {"flag": true, "missing": null},
+        }));
+        assert_eq!(
+            json!({
+                "a": {"flag": "<bool>", "missing": null},
+                "b": ["<number>", {"c": "<string>"}, {"c": "<string>"}],
+            }),
+            normalized
+        );
+        // sorted keys also in the serialization the fingerprint is taken of
+        assert_eq!(
+            r#"{"a":{"flag":"<bool>","missing":null},"b":["<number>",{"c":"<string>"},{"c":"<string>"}]}"#,
+            normalized.to_string()
+        );
+    }
+
+    #[test]
+    fn shapes_newline_delimited_bodies_line_by_line() {
+        let msearch = "{\"index\":\"a\"}\n{\"query\":{\"match_all\":{}}}\n";
+        let shape = QueryShape::of(msearch).unwrap();
+        assert_eq!(
+            json!([{"index": "<string>"}, {"query": {"match_all": {}}}]),
+            shape.normalized
+        );
+        assert_eq!(
+            shape.fingerprint,
+            fingerprint_of("{\"index\":\"b\"}\n\n{\"query\":{\"match_all\":{}}}")
+        );
+        assert!(QueryShape::of("not json").is_none());
+        assert!(QueryShape::of("").is_none());
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        // FNV-1a of `{}`, fingerprints must not change between versions
+        assert_eq!("08f44b07b5901a25", fingerprint(&json!({})));
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::{ElasticsearchMemory, ShardId};
+
+const SEARCH_SERVICE_CLASS: &str = "org/elasticsearch/search/SearchService";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchContextKind {
+    Search,
+    Scroll,
+    #[serde(rename = "pit")]
+    PointInTime,
+}
+
+impl Display for SearchContextKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            SearchContextKind::Search => "search",
+            SearchContextKind::Scroll => "scroll",
+            SearchContextKind::PointInTime => "pit",
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchContext {
+    pub id: Option<i64>,
+    pub session_id: Option<String>,
+    pub shard: Option<ShardId>,
+    pub kind: SearchContextKind,
+    pub keep_alive_ms: Option<i64>,
+    /// Taken from the node's relative clock, only meaningful compared to other contexts
+    pub last_accessed_ms: Option<i64>,
+    pub retained_size: Option<u64>,
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads reader contexts registered in `SearchService`, covering ongoing searches, open
+    /// scrolls and point in time readers. Versions before 7.10 kept `SearchContext`s directly,
+    /// these are read as well.
+    pub fn read_search_contexts(&self) -> Vec<SearchContext> {
+        let mut contexts = Vec::new();
+        if let Some(class) = self.profile.get_class_by_name(SEARCH_SERVICE_CLASS) {
+            for search_service in class.instances(&self.profile) {
+                let fields = search_service.fields(&self.profile);
+                if let Some(active) = fields
+                    .value::<&JavaInstance>(&self.profile, "activeReaders")
+                    .or_else(|| fields.value(&self.profile, "activeContexts"))
+                {
+                    for (_, context) in self.profile.map_entries(active) {
+                        if let JavaLocalValue::Object(context) = context {
+                            self.debug_instance(context);
+                            contexts.push(self.read_search_context(context));
+                        }
+                    }
+                } else {
+                    log::warn!(
+                        "Active contexts not found in SearchService {}",
+                        search_service.id()
+                    );
+                }
+            }
+        } else {
+            log::warn!("{SEARCH_SERVICE_CLASS} not found in heap");
+        }
+        contexts
+    }
+
+    fn read_search_context(&self, context: &JavaInstance) -> SearchContext {
+        let fields = context.fields(&self.profile);
+        let (id, session_id) = match fields.value::<JavaLocalValue>(&self.profile, "id") {
+            Some(JavaLocalValue::Long(id)) => (Some(id), None),
+            Some(JavaLocalValue::Object(id)) => (
+                id.fields(&self.profile).value(&self.profile, "id"),
+                self.read_string_field(id, "sessionId")
+                    .or_else(|| self.read_string_field(id, "readerId")),
+            ),
+            _ => (None, None),
+        };
+
+        let scroll = matches!(
+            fields.value(&self.profile, "scrollContext"),
+            Some(JavaLocalValue::Object(_))
+        );
+        // point in time readers are the only ones that live across multiple requests
+        // without being a scroll
+        let single_session = fields.value(&self.profile, "singleSession").unwrap_or(true);
+        let kind = match (scroll, single_session) {
+            (true, _) => SearchContextKind::Scroll,
+            (false, false) => SearchContextKind::PointInTime,
+            (false, true) => SearchContextKind::Search,
+        };
+
+        SearchContext {
+            id,
+            session_id,
+            shard: fields
+                .value::<&JavaInstance>(&self.profile, "indexShard")
+                .and_then(|shard| self.read_index_shard_id(shard)),
+            kind,
+            keep_alive_ms: self.read_long_field(context, "keepAlive"),
+            last_accessed_ms: self.read_long_field(context, "lastAccessTime"),
+            retained_size: self.profile.retained_size(&context.id()),
+        }
+    }
+}
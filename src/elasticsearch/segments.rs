@@ -0,0 +1,158 @@
+use ahash::AHashSet;
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::{ElasticsearchMemory, ShardId};
+
+const INDEX_SHARD_CLASS: &str = "org/elasticsearch/index/shard/IndexShard";
+
+/// Engine fields holding reader managers, `SearcherManager`s before 7.0
+const READER_MANAGER_FIELDS: [&str; 6] = [
+    "internalReaderManager",
+    "externalReaderManager",
+    "readerManager",
+    "internalSearcherManager",
+    "externalSearcherManager",
+    "searcherManager",
+];
+/// Fields through which filter readers and searchers wrap another reader
+const WRAPPED_READER_FIELDS: [&str; 2] = ["in", "reader"];
+
+/// Heap held by Lucene segments of a single shard
+#[derive(Default, Serialize)]
+pub struct SegmentMemory {
+    pub shard: Option<ShardId>,
+    pub segments: usize,
+    /// Terms index (FSTs) and other state of the postings format
+    pub terms: u64,
+    pub norms: u64,
+    pub doc_values: u64,
+    pub points: u64,
+}
+
+impl SegmentMemory {
+    pub fn total(&self) -> u64 {
+        self.terms + self.norms + self.doc_values + self.points
+    }
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads memory of segments open by the engine of each shard
+    pub fn read_segment_memory(&self) -> Vec<SegmentMemory> {
+        let mut shards = Vec::new();
+        if let Some(class) = self.profile.get_class_by_name(INDEX_SHARD_CLASS) {
+            for index_shard in class.instances(&self.profile) {
+                self.debug_instance(index_shard);
+                match self.read_engine(index_shard) {
+                    Some(engine) => {
+                        shards.push(self.read_shard_segment_memory(index_shard, engine))
+                    }
+                    None => log::debug!("Engine of IndexShard {} not found", index_shard.id()),
+                }
+            }
+        } else {
+            log::warn!("{INDEX_SHARD_CLASS} not found in heap");
+        }
+        shards
+    }
+
+    fn read_engine<'b>(
+        &'b self,
+        index_shard: &'b JavaInstance<'b>,
+    ) -> Option<&'b JavaInstance<'b>> {
+        let fields = index_shard.fields(&self.profile);
+        let engine = fields
+            .value::<&JavaInstance>(&self.profile, "currentEngineReference")
+            .or_else(|| fields.value(&self.profile, "currentEngine"))?;
+        // AtomicReference up to 8.x
+        match engine
+            .fields(&self.profile)
+            .value::<JavaLocalValue>(&self.profile, "value")
+        {
+            Some(JavaLocalValue::Object(engine)) => Some(engine),
+            Some(_) => None,
+            None => Some(engine),
+        }
+    }
+
+    fn read_shard_segment_memory(
+        &self,
+        index_shard: &JavaInstance,
+        engine: &JavaInstance,
+    ) -> SegmentMemory {
+        let mut memory = SegmentMemory {
+            shard: self.read_index_shard_id(index_shard),
+            ..Default::default()
+        };
+        // internal and external readers share segment cores
+        let mut cores = AHashSet::new();
+        let engine_fields = engine.fields(&self.profile);
+        for manager in READER_MANAGER_FIELDS
+            .iter()
+            .filter_map(|name| engine_fields.value::<&JavaInstance>(&self.profile, name))
+        {
+            let current = match manager
+                .fields(&self.profile)
+                .value::<&JavaInstance>(&self.profile, "current")
+            {
+                Some(current) => current,
+                None => continue,
+            };
+            for segment_reader in self.read_segment_readers(current) {
+                let fields = segment_reader.fields(&self.profile);
+                let core = match fields.value::<&JavaInstance>(&self.profile, "core") {
+                    Some(core) => core,
+                    None => continue,
+                };
+                if !cores.insert(core.id()) {
+                    continue;
+                }
+                let core_fields = core.fields(&self.profile);
+                let retained = |value: Option<JavaLocalValue>| {
+                    value
+                        .and_then(|value| value.object_id())
+                        .and_then(|id| self.profile.retained_size(&id))
+                        .unwrap_or(0)
+                };
+                memory.segments += 1;
+                memory.terms += retained(core_fields.value(&self.profile, "fields"));
+                memory.norms += retained(core_fields.value(&self.profile, "normsProducer"));
+                memory.points += retained(core_fields.value(&self.profile, "pointsReader"));
+                memory.doc_values += retained(fields.value(&self.profile, "docValuesProducer"));
+            }
+        }
+        memory
+    }
+
+    /// Unwraps a top level reader or searcher down to its `SegmentReader`s
+    fn read_segment_readers<'b>(
+        &'b self,
+        reader: &'b JavaInstance<'b>,
+    ) -> Vec<&'b JavaInstance<'b>> {
+        let mut segment_readers = Vec::new();
+        let mut visited = AHashSet::new();
+        let mut stack = vec![reader];
+        while let Some(reader) = stack.pop() {
+            if !visited.insert(reader.id()) {
+                continue;
+            }
+            let fields = reader.fields(&self.profile);
+            if fields.fields.contains_key("core") {
+                segment_readers.push(reader);
+                continue;
+            }
+            if let Some(sub_readers) = fields.value::<&JavaObjectArray>(&self.profile, "subReaders")
+            {
+                stack.extend(sub_readers.values(&self.profile).flatten());
+                continue;
+            }
+            stack.extend(
+                WRAPPED_READER_FIELDS
+                    .iter()
+                    .find_map(|name| fields.value::<&JavaInstance>(&self.profile, name)),
+            );
+        }
+        segment_readers
+    }
+}
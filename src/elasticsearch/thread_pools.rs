@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use ahash::AHashSet;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::hprof::*;
+
+use super::object_json::simple_class_name;
+use super::ElasticsearchMemory;
+
+const ES_THREAD_POOL_EXECUTOR_CLASS: &str =
+    "org/elasticsearch/common/util/concurrent/EsThreadPoolExecutor";
+
+/// Fields through which runnables wrap the task they run
+/// (`TimedRunnable`, `ThreadContext`'s context preserving runnables, ...)
+const WRAPPED_RUNNABLE_FIELDS: [&str; 6] =
+    ["original", "in", "runnable", "delegate", "task", "command"];
+/// Number of objects inspected when looking for the action and request of a queued task
+const MAX_TASK_OBJECTS: usize = 256;
+
+#[derive(Serialize)]
+pub struct ThreadPool {
+    pub name: String,
+    pub threads: usize,
+    pub active: usize,
+    pub queue_capacity: Option<i32>,
+    pub queued: Vec<QueuedTask>,
+}
+
+#[derive(Serialize)]
+pub struct QueuedTask {
+    /// Innermost runnable class, after unwrapping wrappers
+    pub task_class: String,
+    /// Transport action that submitted the task, e.g. `indices:data/read/search[phase/query]`
+    pub action: Option<String>,
+    /// Search source of queued shard searches, reconstructed from `SearchSourceBuilder`
+    pub query: Option<Value>,
+    pub retained_size: Option<u64>,
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads named Elasticsearch thread pools with their queued tasks
+    pub fn read_thread_pools(&self) -> Vec<ThreadPool> {
+        let executors = self
+            .profile
+            .get_instances_by_class_name(ES_THREAD_POOL_EXECUTOR_CLASS);
+        if executors.is_empty() {
+            log::warn!("{ES_THREAD_POOL_EXECUTOR_CLASS} not found in heap");
+        }
+        let mut pools = executors
+            .into_iter()
+            .map(|executor| {
+                self.debug_instance(executor);
+                self.read_thread_pool(executor)
+            })
+            .collect::<Vec<_>>();
+        pools.sort_by(|a, b| a.name.cmp(&b.name));
+        pools
+    }
+
+    fn read_thread_pool(&self, executor: &JavaInstance) -> ThreadPool {
+        let fields = executor.fields(&self.profile);
+        // executors are named <node name>/<pool name>
+        let name = self
+            .read_string_field(executor, "name")
+            .map(|name| name.rsplit('/').next().unwrap_or(&name).to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        // workers hold their lock while running a task
+        let workers = fields
+            .value::<&JavaInstance>(&self.profile, "workers")
+            .map(|workers| self.profile.collection_items(workers))
+            .unwrap_or_default();
+        let active = workers
+            .iter()
+            .filter(|worker| match worker {
+                JavaLocalValue::Object(worker) => {
+                    worker.fields(&self.profile).value(&self.profile, "state") == Some(1i32)
+                }
+                _ => false,
+            })
+            .count();
+
+        let work_queue = fields.value::<&JavaInstance>(&self.profile, "workQueue");
+        ThreadPool {
+            name,
+            threads: workers.len(),
+            active,
+            queue_capacity: work_queue
+                .and_then(|queue| queue.fields(&self.profile).value(&self.profile, "capacity")),
+            queued: work_queue
+                .map(|queue| self.profile.collection_items(queue))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|task| match task {
+                    JavaLocalValue::Object(task) => Some(self.read_queued_task(task)),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn read_queued_task(&self, task: &JavaInstance) -> QueuedTask {
+        let mut innermost = task;
+        let mut visited = AHashSet::new();
+        while visited.insert(innermost.id()) {
+            let fields = innermost.fields(&self.profile);
+            match WRAPPED_RUNNABLE_FIELDS
+                .iter()
+                .find_map(|name| fields.value::<&JavaInstance>(&self.profile, name))
+            {
+                Some(wrapped) => innermost = wrapped,
+                None => break,
+            }
+        }
+
+        // the transport action is kept by the request handler registry or the channel, both
+        // captured by the runnable, the request next to them
+        let mut action = None;
+        let mut query = None;
+        let mut visited = AHashSet::new();
+        let mut queue = VecDeque::from([task]);
+        while let Some(instance) = queue.pop_front() {
+            if visited.len() >= MAX_TASK_OBJECTS || (action.is_some() && query.is_some()) {
+                break;
+            }
+            if !visited.insert(instance.id()) {
+                continue;
+            }
+            if action.is_none() {
+                action = self.read_string_field(instance, "action");
+            }
+            if query.is_none() && self.is_search_request(instance) {
+                query = self.read_search_source(instance);
+            }
+            for (_, field) in instance.fields(&self.profile).fields {
+                if let JavaLocalValue::Object(referenced) = field.value(&self.profile) {
+                    if !referenced
+                        .name(&self.profile)
+                        .unwrap_or("")
+                        .starts_with("java/")
+                    {
+                        queue.push_back(referenced);
+                    }
+                }
+            }
+        }
+
+        QueuedTask {
+            task_class: innermost
+                .name(&self.profile)
+                .map(|name| simple_class_name(name).to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            action,
+            query,
+            retained_size: self.profile.retained_size(&task.id()),
+        }
+    }
+
+    fn is_search_request(&self, instance: &JavaInstance) -> bool {
+        instance
+            .name(&self.profile)
+            .map(|name| simple_class_name(name).contains("SearchRequest"))
+            .unwrap_or(false)
+    }
+
+    /// Search source of `ShardSearchRequest`, also wrapped by `ShardSearchTransportRequest`
+    /// before 7.0
+    fn read_search_source(&self, request: &JavaInstance) -> Option<Value> {
+        let fields = request.fields(&self.profile);
+        if let Some(source) = fields.value::<&JavaInstance>(&self.profile, "source") {
+            return Some(self.object_to_json(JavaLocalValue::Object(source)));
+        }
+        fields
+            .value::<&JavaInstance>(&self.profile, "shardSearchLocalRequest")
+            .and_then(|local| self.read_search_source(local))
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Display;
+
+use ahash::AHashMap;
+use serde::Serialize;
+
+use crate::hprof::*;
+
+use super::ElasticsearchMemory;
+
+const TRANSPORT_SERVICE_CLASS: &str = "org/elasticsearch/transport/TransportService";
+const TASK_MANAGER_CLASS: &str = "org/elasticsearch/tasks/TaskManager";
+const INBOUND_AGGREGATOR_CLASS: &str = "org/elasticsearch/transport/InboundAggregator";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportDirection {
+    /// Request sent to another node, waiting for its response
+    Outbound,
+    /// Request received from another node, still being executed
+    Inbound,
+    /// Message only partially received from the network
+    Receiving,
+}
+
+impl Display for TransportDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            TransportDirection::Outbound => "outbound",
+            TransportDirection::Inbound => "inbound",
+            TransportDirection::Receiving => "receiving",
+        })
+    }
+}
+
+/// Transport request known to the node. Sizes of the requests themselves are not available,
+/// neither response contexts nor tasks keep a reference to their request
+#[derive(Serialize)]
+pub struct PendingTransportRequest {
+    pub direction: TransportDirection,
+    /// Id given by the sending node, the same on both nodes. Unknown for inbound requests
+    pub request_id: Option<i64>,
+    /// Id of the local task executing an inbound request
+    pub task_id: Option<i64>,
+    pub action: Option<String>,
+    /// Remote node, the node name when known, otherwise its id
+    pub node: Option<String>,
+    /// Retained size of the `ResponseContext` of outbound requests, the response handler and what
+    /// it keeps alive
+    pub handler_retained_size: Option<u64>,
+    /// Size of the message on the network, only for messages being received
+    pub message_size: Option<u64>,
+}
+
+impl<'a> ElasticsearchMemory<'a> {
+    /// Reads transport requests waiting for a response, executing on behalf of other nodes and
+    /// partially received ones
+    pub fn read_pending_transport_requests(&self) -> Vec<PendingTransportRequest> {
+        let mut requests = Vec::new();
+        match self.profile.get_class_by_name(TRANSPORT_SERVICE_CLASS) {
+            Some(class) => {
+                for transport_service in class.instances(&self.profile) {
+                    self.debug_instance(transport_service);
+                    requests.extend(self.read_response_handlers(transport_service));
+                }
+            }
+            None => log::warn!("{TRANSPORT_SERVICE_CLASS} not found in heap"),
+        }
+        match self.profile.get_class_by_name(TASK_MANAGER_CLASS) {
+            Some(class) => {
+                for task_manager in class.instances(&self.profile) {
+                    requests.extend(self.read_remote_tasks(task_manager));
+                }
+            }
+            None => log::warn!("{TASK_MANAGER_CLASS} not found in heap"),
+        }
+        for aggregator in self
+            .profile
+            .get_class_by_name(INBOUND_AGGREGATOR_CLASS)
+            .map(|class| class.instances(&self.profile))
+            .unwrap_or_default()
+        {
+            if let Some(header) = aggregator
+                .fields(&self.profile)
+                .value::<&JavaInstance>(&self.profile, "currentHeader")
+            {
+                let fields = header.fields(&self.profile);
+                requests.push(PendingTransportRequest {
+                    direction: TransportDirection::Receiving,
+                    request_id: fields.value(&self.profile, "requestId"),
+                    action: self.read_string_field(header, "actionName"),
+                    node: None,
+                    task_id: None,
+                    handler_retained_size: None,
+                    message_size: fields
+                        .value::<i32>(&self.profile, "networkMessageSize")
+                        .map(|size| size.max(0) as u64),
+                });
+            }
+        }
+        requests
+    }
+
+    fn read_response_handlers(
+        &self,
+        transport_service: &JavaInstance,
+    ) -> Vec<PendingTransportRequest> {
+        let fields = transport_service.fields(&self.profile);
+        // `ResponseHandlers` since 6.3, a map directly in `TransportService` before
+        let handlers = match fields.value::<&JavaInstance>(&self.profile, "responseHandlers") {
+            Some(response_handlers) => response_handlers
+                .fields(&self.profile)
+                .value::<&JavaInstance>(&self.profile, "handlers"),
+            None => fields.value(&self.profile, "clientHandlers"),
+        };
+        let handlers = match handlers {
+            Some(handlers) => self.profile.map_entries(handlers),
+            None => {
+                log::warn!("Response handlers not found in TransportService");
+                return Vec::new();
+            }
+        };
+
+        handlers
+            .into_iter()
+            .filter_map(|(request_id, context)| match context {
+                JavaLocalValue::Object(context) => Some((request_id, context)),
+                _ => None,
+            })
+            .map(|(request_id, context)| PendingTransportRequest {
+                direction: TransportDirection::Outbound,
+                request_id: self.read_boxed_long(request_id),
+                task_id: None,
+                action: self.read_string_field(context, "action"),
+                node: context
+                    .fields(&self.profile)
+                    .value::<&JavaInstance>(&self.profile, "connection")
+                    .and_then(|connection| self.read_connection_node(connection)),
+                handler_retained_size: self.profile.retained_size(&context.id()),
+                message_size: None,
+            })
+            .collect()
+    }
+
+    /// Node of a connection, the local node for connections to self
+    fn read_connection_node(&self, connection: &JavaInstance) -> Option<String> {
+        let fields = connection.fields(&self.profile);
+        let node = fields
+            .value::<&JavaInstance>(&self.profile, "node")
+            .or_else(|| {
+                fields
+                    .value::<&JavaInstance>(&self.profile, "this$0")
+                    .and_then(|service| {
+                        service
+                            .fields(&self.profile)
+                            .value::<&JavaInstance>(&self.profile, "localNode")
+                    })
+            })?;
+        self.read_string_field(node, "nodeName")
+            .or_else(|| self.read_string_field(node, "nodeId"))
+    }
+
+    /// Tasks with a parent task on another node, executed for a transport request of that node
+    fn read_remote_tasks(&self, task_manager: &JavaInstance) -> Vec<PendingTransportRequest> {
+        // parent tasks only know the node id
+        let node_names: AHashMap<String, String> = self
+            .applied_cluster_state()
+            .map(|state| self.read_discovery_nodes(state))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|node| Some((node.id?, node.name?)))
+            .collect();
+        let tasks = task_manager
+            .fields(&self.profile)
+            .value::<&JavaInstance>(&self.profile, "tasks")
+            .map(|tasks| self.map_values(tasks))
+            .unwrap_or_default();
+        tasks
+            .into_iter()
+            .filter_map(|task| {
+                let fields = task.fields(&self.profile);
+                let parent_node = fields
+                    .value::<&JavaInstance>(&self.profile, "parentTask")
+                    .and_then(|parent| self.read_string_field(parent, "nodeId"))
+                    .filter(|node_id| !node_id.is_empty())?;
+                Some(PendingTransportRequest {
+                    direction: TransportDirection::Inbound,
+                    // tasks don't keep the id of the request they were created for
+                    request_id: None,
+                    task_id: fields.value(&self.profile, "id"),
+                    action: self.read_string_field(task, "action"),
+                    node: Some(node_names.get(&parent_node).cloned().unwrap_or(parent_node)),
+                    handler_retained_size: None,
+                    message_size: None,
+                })
+            })
+            .collect()
+    }
+
+    fn read_boxed_long(&self, value: JavaLocalValue) -> Option<i64> {
+        match value {
+            JavaLocalValue::Long(value) => Some(value),
+            JavaLocalValue::Object(boxed) => {
+                boxed.fields(&self.profile).value(&self.profile, "value")
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,755 @@
+//! Conversion of x-content (JSON, SMILE, CBOR and YAML) to JSON.
+//!
+//! Elasticsearch accepts request bodies and keeps sources in any of its x-content types. The type
+//! is detected by a header or the `Content-Type` of a request and every payload is converted to
+//! JSON, so extracted requests stay readable and can be replayed against a REST endpoint.
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::{Map, Number, Value};
+
+/// `:)\n` followed by the version and feature flags
+const SMILE_HEADER: &[u8] = b":)\n";
+/// Self-describe tag jackson writes in front of CBOR documents
+const CBOR_SELF_DESCRIBE_TAG: &[u8] = &[0xd9, 0xd9, 0xf7];
+const YAML_DOCUMENT_START: &[u8] = b"---";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XContentType {
+    Json,
+    Smile,
+    Cbor,
+    Yaml,
+}
+
+impl XContentType {
+    /// Detects the type from the content type or the first bytes of the content
+    pub fn detect(content_type: Option<&str>, bytes: &[u8]) -> Self {
+        let media_type = content_type
+            .map(|content_type| content_type.to_ascii_lowercase())
+            .unwrap_or_default();
+        if media_type.contains("smile") || bytes.starts_with(SMILE_HEADER) {
+            XContentType::Smile
+        } else if media_type.contains("cbor") || bytes.starts_with(CBOR_SELF_DESCRIBE_TAG) {
+            XContentType::Cbor
+        } else if media_type.contains("yaml") || bytes.starts_with(YAML_DOCUMENT_START) {
+            XContentType::Yaml
+        } else {
+            XContentType::Json
+        }
+    }
+}
+
+impl XContentType {
+    pub fn name(self) -> &'static str {
+        match self {
+            XContentType::Json => "json",
+            XContentType::Smile => "smile",
+            XContentType::Cbor => "cbor",
+            XContentType::Yaml => "yaml",
+        }
+    }
+}
+
+impl Display for XContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// Parses x-content of any type
+pub fn parse(content_type: XContentType, bytes: &[u8]) -> anyhow::Result<Value> {
+    match content_type {
+        XContentType::Json => serde_json::from_slice(bytes).context("Failed to parse JSON"),
+        XContentType::Smile => SmileParser::new(bytes)?.parse(),
+        XContentType::Cbor => {
+            let value: ciborium::value::Value =
+                ciborium::de::from_reader(bytes).context("Failed to parse CBOR")?;
+            Ok(cbor_to_json(value))
+        }
+        XContentType::Yaml => serde_yaml::from_slice(bytes).context("Failed to parse YAML"),
+    }
+}
+
+/// Formats x-content as pretty JSON. Content that cannot be parsed, e.g. the newline delimited
+/// bodies of `_bulk` and `_msearch`, is returned as text
+pub fn to_pretty_json(content_type: Option<&str>, bytes: &[u8]) -> (XContentType, String) {
+    let xcontent_type = XContentType::detect(content_type, bytes);
+    let text = match parse(xcontent_type, bytes) {
+        Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_default(),
+        Err(err) => {
+            if xcontent_type != XContentType::Json {
+                log::warn!("Failed to convert {xcontent_type} to JSON: {:#}", err);
+            }
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+    };
+    (xcontent_type, text)
+}
+
+/// Parses bytes that look like x-content, i.e. have a binary x-content header or start like a
+/// JSON object
+pub fn parse_detected(bytes: &[u8]) -> Option<Value> {
+    let xcontent_type = XContentType::detect(None, bytes);
+    if xcontent_type == XContentType::Json && bytes.first() != Some(&b'{') {
+        return None;
+    }
+    parse(xcontent_type, bytes).ok()
+}
+
+fn cbor_to_json(value: ciborium::value::Value) -> Value {
+    use ciborium::value::Value as Cbor;
+    match value {
+        Cbor::Integer(i) => {
+            let i = i128::from(i);
+            i64::try_from(i)
+                .map(Value::from)
+                .or_else(|_| u64::try_from(i).map(Value::from))
+                .unwrap_or_else(|_| Value::String(i.to_string()))
+        }
+        Cbor::Bytes(bytes) => Value::String(base64(&bytes)),
+        Cbor::Float(f) => float_to_json(f),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Null => Value::Null,
+        Cbor::Tag(_, value) => cbor_to_json(*value),
+        Cbor::Array(values) => Value::Array(values.into_iter().map(cbor_to_json).collect()),
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match cbor_to_json(key) {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    (key, cbor_to_json(value))
+                })
+                .collect(),
+        ),
+        _ => Value::Null,
+    }
+}
+
+/// Shared name and value tables are reset once they reach this size
+const SMILE_MAX_SHARED: usize = 1024;
+/// Longest strings added to the shared tables
+const SMILE_MAX_SHARED_LENGTH: usize = 64;
+const SMILE_END_OF_STRING: u8 = 0xfc;
+/// Deepest nesting of arrays and objects, as deep as serde_json reads JSON. Bodies come from
+/// clients, deeper ones would overflow the stack
+const SMILE_MAX_DEPTH: usize = 128;
+
+/// Decoder of jackson's SMILE format, the binary JSON used by Elasticsearch
+struct SmileParser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    shared_names: Option<Vec<String>>,
+    shared_values: Option<Vec<String>>,
+    /// Arrays and objects being read
+    depth: usize,
+}
+
+impl<'a> SmileParser<'a> {
+    fn new(bytes: &'a [u8]) -> anyhow::Result<Self> {
+        let flags = match bytes.strip_prefix(SMILE_HEADER) {
+            Some([flags, ..]) => *flags,
+            _ => bail!("Missing SMILE header"),
+        };
+        if flags & 0xf0 != 0 {
+            bail!("Unsupported SMILE version {}", flags >> 4);
+        }
+        Ok(Self {
+            bytes,
+            position: SMILE_HEADER.len() + 1,
+            shared_names: (flags & 0x01 != 0).then(Vec::new),
+            shared_values: (flags & 0x02 != 0).then(Vec::new),
+            depth: 0,
+        })
+    }
+
+    fn parse(&mut self) -> anyhow::Result<Value> {
+        let token = self.next_byte()?;
+        self.parse_value(token)
+    }
+
+    fn next_byte(&mut self) -> anyhow::Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| anyhow!("Unexpected end of SMILE content"))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, length: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .position
+            .checked_add(length)
+            .and_then(|end| self.bytes.get(self.position..end))
+            .ok_or_else(|| anyhow!("Unexpected end of SMILE content"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn take_string(&mut self, length: usize) -> anyhow::Result<String> {
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    /// String terminated by the end of string marker
+    fn take_terminated_string(&mut self) -> anyhow::Result<String> {
+        let length = self.bytes[self.position..]
+            .iter()
+            .position(|&b| b == SMILE_END_OF_STRING)
+            .ok_or_else(|| anyhow!("Unterminated SMILE string"))?;
+        let string = self.take_string(length)?;
+        self.position += 1;
+        Ok(string)
+    }
+
+    /// Big endian 7 bit groups, the last byte has its high bit set and holds 6 bits
+    fn read_vint(&mut self) -> anyhow::Result<u64> {
+        let mut value = 0u64;
+        loop {
+            let byte = self.next_byte()?;
+            if byte & 0x80 != 0 {
+                return Ok((value << 6) | u64::from(byte & 0x3f));
+            }
+            value = (value << 7) | u64::from(byte);
+        }
+    }
+
+    fn read_zigzag(&mut self) -> anyhow::Result<i64> {
+        let value = self.read_vint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Fixed number of bytes holding 7 bits each
+    fn read_7bit_bits(&mut self, count: usize) -> anyhow::Result<u64> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 7) | u64::from(self.next_byte()? & 0x7f);
+        }
+        Ok(value)
+    }
+
+    /// Binary data with every 7 bytes spread over 8 bytes of 7 bits
+    fn read_7bit_binary(&mut self) -> anyhow::Result<Vec<u8>> {
+        let length = self.read_vint()?;
+        // every 7 bytes take 8, trailing bytes one more than their number
+        let encoded_length = (length / 7)
+            .saturating_mul(8)
+            .saturating_add(match length % 7 {
+                0 => 0,
+                trailing => trailing + 1,
+            });
+        // the length comes from the heap, garbage must not turn into a huge allocation
+        if encoded_length > (self.bytes.len() - self.position) as u64 {
+            bail!(
+                "SMILE binary of {length} bytes at offset {} exceeds the content",
+                self.position
+            );
+        }
+        let length = length as usize;
+        let mut decoded = Vec::with_capacity(length);
+        while decoded.len() + 7 <= length {
+            let bits = self.read_7bit_bits(8)?;
+            decoded.extend_from_slice(&bits.to_be_bytes()[1..]);
+        }
+        // trailing bytes take one more byte, the last one holding the remaining right-aligned bits
+        let remaining = length - decoded.len();
+        if remaining > 0 {
+            let mut value = u64::from(self.next_byte()? & 0x7f);
+            for i in 1..remaining {
+                value = (value << 7) | u64::from(self.next_byte()? & 0x7f);
+                decoded.push((value >> (7 - i)) as u8);
+            }
+            value <<= remaining;
+            decoded.push((value + u64::from(self.next_byte()? & 0x7f)) as u8);
+        }
+        Ok(decoded)
+    }
+
+    fn parse_value(&mut self, token: u8) -> anyhow::Result<Value> {
+        Ok(match token {
+            0x01..=0x1f => Value::String(self.shared_value(usize::from(token - 1))?),
+            0x20 => Value::String(String::new()),
+            0x21 => Value::Null,
+            0x22 => Value::Bool(false),
+            0x23 => Value::Bool(true),
+            0x24 | 0x25 => Value::from(self.read_zigzag()?),
+            0x26 => {
+                let bytes = self.read_7bit_binary()?;
+                big_integer_to_json(&bytes)
+            }
+            0x28 => float_to_json(f64::from(f32::from_bits(self.read_7bit_bits(5)? as u32))),
+            0x29 => float_to_json(f64::from_bits(self.read_7bit_bits(10)?)),
+            0x2a => {
+                let scale = self.read_zigzag()?;
+                let unscaled = big_integer_to_json(&self.read_7bit_binary()?);
+                let digits = unscaled.to_string();
+                match digits.parse::<f64>() {
+                    Ok(unscaled) => float_to_json(unscaled / 10f64.powi(scale as i32)),
+                    Err(_) => Value::String(format!("{digits}E-{scale}")),
+                }
+            }
+            0x40..=0xbf => {
+                let length = match token {
+                    0x40..=0x5f => usize::from(token & 0x1f) + 1,
+                    0x60..=0x7f => usize::from(token & 0x1f) + 33,
+                    0x80..=0x9f => usize::from(token & 0x1f) + 2,
+                    _ => usize::from(token & 0x1f) + 34,
+                };
+                let value = self.take_string(length)?;
+                if let Some(shared) = self.shared_values.as_mut() {
+                    if length <= SMILE_MAX_SHARED_LENGTH {
+                        if shared.len() >= SMILE_MAX_SHARED {
+                            shared.clear();
+                        }
+                        shared.push(value.clone());
+                    }
+                }
+                Value::String(value)
+            }
+            0xc0..=0xdf => {
+                let zigzag = i64::from(token & 0x1f);
+                Value::from((zigzag >> 1) ^ -(zigzag & 1))
+            }
+            0xe0 | 0xe4 => Value::String(self.take_terminated_string()?),
+            0xe8 => Value::String(base64(&self.read_7bit_binary()?)),
+            0xec..=0xef => {
+                let index = (usize::from(token & 0x03) << 8) | usize::from(self.next_byte()?);
+                Value::String(self.shared_value(index)?)
+            }
+            0xf8 => {
+                self.enter()?;
+                let mut values = Vec::new();
+                loop {
+                    match self.next_byte()? {
+                        0xf9 => break,
+                        token => values.push(self.parse_value(token)?),
+                    }
+                }
+                self.depth -= 1;
+                Value::Array(values)
+            }
+            0xfa => {
+                self.enter()?;
+                let mut object = Map::new();
+                loop {
+                    let name = match self.next_byte()? {
+                        0xfb => break,
+                        token => self.parse_name(token)?,
+                    };
+                    let token = self.next_byte()?;
+                    object.insert(name, self.parse_value(token)?);
+                }
+                self.depth -= 1;
+                Value::Object(object)
+            }
+            0xfd => {
+                let length = self.read_vint()? as usize;
+                Value::String(base64(self.take(length)?))
+            }
+            token => bail!(
+                "Unexpected SMILE token {token:#04x} at offset {}",
+                self.position - 1
+            ),
+        })
+    }
+
+    /// Starts reading an array or object
+    fn enter(&mut self) -> anyhow::Result<()> {
+        self.depth += 1;
+        if self.depth > SMILE_MAX_DEPTH {
+            bail!(
+                "SMILE content nested deeper than {SMILE_MAX_DEPTH} levels at offset {}",
+                self.position - 1
+            );
+        }
+        Ok(())
+    }
+
+    fn parse_name(&mut self, token: u8) -> anyhow::Result<String> {
+        let name = match token {
+            0x20 => return Ok(String::new()),
+            0x30..=0x33 => {
+                let index = (usize::from(token & 0x03) << 8) | usize::from(self.next_byte()?);
+                return self.shared_name(index);
+            }
+            0x40..=0x7f => return self.shared_name(usize::from(token & 0x3f)),
+            0x34 => self.take_terminated_string()?,
+            0x80..=0xbf => self.take_string(usize::from(token & 0x3f) + 1)?,
+            0xc0..=0xf7 => self.take_string(usize::from(token & 0x3f) + 2)?,
+            token => bail!(
+                "Unexpected SMILE field name token {token:#04x} at offset {}",
+                self.position - 1
+            ),
+        };
+        if let Some(shared) = self.shared_names.as_mut() {
+            if name.len() <= SMILE_MAX_SHARED_LENGTH {
+                if shared.len() >= SMILE_MAX_SHARED {
+                    shared.clear();
+                }
+                shared.push(name.clone());
+            }
+        }
+        Ok(name)
+    }
+
+    fn shared_name(&self, index: usize) -> anyhow::Result<String> {
+        self.shared_names
+            .as_ref()
+            .and_then(|names| names.get(index))
+            .cloned()
+            .ok_or_else(|| anyhow!("Invalid SMILE shared name reference {index}"))
+    }
+
+    fn shared_value(&self, index: usize) -> anyhow::Result<String> {
+        self.shared_values
+            .as_ref()
+            .and_then(|values| values.get(index))
+            .cloned()
+            .ok_or_else(|| anyhow!("Invalid SMILE shared value reference {index}"))
+    }
+}
+
+/// Two's complement big endian integer, approximated when it does not fit a long
+fn big_integer_to_json(bytes: &[u8]) -> Value {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return Value::String(format!("0x{}", hex(bytes)));
+    }
+    let negative = bytes[0] & 0x80 != 0;
+    let mut padded = [if negative { 0xff } else { 0 }; 16];
+    padded[16 - bytes.len()..].copy_from_slice(bytes);
+    let value = i128::from_be_bytes(padded);
+    i64::try_from(value)
+        .map(Value::from)
+        .unwrap_or_else(|_| float_to_json(value as f64))
+}
+
+fn float_to_json(f: f64) -> Value {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Binary values have no JSON counterpart, jackson writes them base64 encoded
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Header with shared names and shared values enabled
+    const SMILE_SHARED: &[u8] = b":)\n\x03";
+    const SMILE_NOT_SHARED: &[u8] = b":)\n\x00";
+
+    fn smile(header: &[u8], content: &[u8]) -> anyhow::Result<Value> {
+        parse(XContentType::Smile, &[header, content].concat())
+    }
+
+    /// Jackson's `_write7BitBinaryWithLength` without the length prefix
+    fn encode_7bit(bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let mut chunks = bytes.chunks_exact(7);
+        for chunk in &mut chunks {
+            let value = chunk
+                .iter()
+                .fold(0u64, |value, &b| (value << 8) | u64::from(b));
+            encoded.extend((0..8).rev().map(|i| ((value >> (7 * i)) & 0x7f) as u8));
+        }
+        let trailing = chunks.remainder();
+        if !trailing.is_empty() {
+            let bits = 8 * trailing.len();
+            let value = trailing
+                .iter()
+                .fold(0u64, |value, &b| (value << 8) | u64::from(b));
+            for i in 1..=trailing.len() {
+                encoded.push(((value >> (bits - 7 * i)) & 0x7f) as u8);
+            }
+            encoded.push((value & ((1 << trailing.len()) - 1)) as u8);
+        }
+        encoded
+    }
+
+    /// SMILE vint, as read by `SmileParser::read_vint`
+    fn vint(value: u64) -> Vec<u8> {
+        let mut encoded = vec![0x80 | (value & 0x3f) as u8];
+        let mut value = value >> 6;
+        while value > 0 {
+            encoded.insert(0, (value & 0x7f) as u8);
+            value >>= 7;
+        }
+        encoded
+    }
+
+    fn smile_binary(token: u8, bytes: &[u8]) -> Vec<u8> {
+        [&[token][..], &vint(bytes.len() as u64), &encode_7bit(bytes)].concat()
+    }
+
+    #[test]
+    fn detects_content_type() {
+        assert_eq!(
+            XContentType::Smile,
+            XContentType::detect(None, b":)\n\x03\xfa")
+        );
+        assert_eq!(
+            XContentType::Cbor,
+            XContentType::detect(None, &[0xd9, 0xd9, 0xf7, 0xa0])
+        );
+        assert_eq!(XContentType::Yaml, XContentType::detect(None, b"---\na: 1"));
+        assert_eq!(XContentType::Json, XContentType::detect(None, b"{}"));
+        assert_eq!(
+            XContentType::Smile,
+            XContentType::detect(Some("application/SMILE"), b"")
+        );
+    }
+
+    #[test]
+    fn parses_smile_scalars() {
+        let content = [
+            &[0xf8][..],
+            // small ints are zigzag encoded in the token
+            &[0xc2, 0xc1],
+            &[0x24],
+            &vint(2 * 1000),
+            &[0x21, 0x22, 0x23, 0x20],
+            // tiny and short ASCII values
+            &[0x42],
+            b"abc",
+            &[0x60],
+            &[b'x'; 33],
+            &[0xe0],
+            b"long",
+            &[0xfc, 0xf9],
+        ]
+        .concat();
+        assert_eq!(
+            json!([
+                1,
+                -1,
+                1000,
+                null,
+                false,
+                true,
+                "",
+                "abc",
+                "x".repeat(33),
+                "long"
+            ]),
+            smile(SMILE_NOT_SHARED, &content).unwrap()
+        );
+    }
+
+    #[test]
+    fn resolves_smile_shared_names_and_values() {
+        let content = [
+            &[0xf8, 0xfa][..],
+            // short ASCII name and tiny ASCII value, both added to the shared tables
+            &[0x83],
+            b"name",
+            &[0x42],
+            b"val",
+            &[0x81],
+            b"id",
+            &[0x40],
+            b"x",
+            &[0xfb, 0xfa],
+            // short references, to the first name and the first value
+            &[0x40, 0x01],
+            // long references, to the second name and the second value
+            &[0x30, 0x01, 0xec, 0x01],
+            &[0xfb, 0xf9],
+        ]
+        .concat();
+        assert_eq!(
+            json!([{"name": "val", "id": "x"}, {"name": "val", "id": "x"}]),
+            smile(SMILE_SHARED, &content).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_smile_references_without_shared_tables() {
+        let name_reference = [&[0xfa, 0x83][..], b"name", &[0x21, 0x40, 0x21, 0xfb]].concat();
+        assert!(smile(SMILE_NOT_SHARED, &name_reference).is_err());
+        let value_reference = [&[0xf8, 0x42][..], b"val", &[0x01, 0xf9]].concat();
+        assert!(smile(SMILE_NOT_SHARED, &value_reference).is_err());
+        // references past the values seen so far
+        assert!(smile(SMILE_SHARED, &[0xf8, 0x01, 0xf9]).is_err());
+    }
+
+    #[test]
+    fn decodes_smile_7bit_binary_trailing_bytes() {
+        // 0xff01 is spread over 7 bits, the next 7 bits and the 2 remaining bits
+        let content = [0xe8, 0x82, 0x7f, 0x40, 0x01];
+        assert_eq!(
+            json!(base64(&[0xff, 0x01])),
+            smile(SMILE_NOT_SHARED, &content).unwrap()
+        );
+    }
+
+    #[test]
+    fn decodes_smile_7bit_binary_of_every_length() {
+        for length in 0..=21 {
+            let bytes = (0..length)
+                .map(|i| (0x80 + i * 37) as u8)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                json!(base64(&bytes)),
+                smile(SMILE_NOT_SHARED, &smile_binary(0xe8, &bytes)).unwrap(),
+                "length {length}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_smile_big_numbers() {
+        // 2^64 + 1 does not fit a long
+        let big_integer = smile_binary(0x26, &[0x01, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        assert_eq!(
+            json!(18446744073709551617f64),
+            smile(SMILE_NOT_SHARED, &big_integer).unwrap()
+        );
+        let negative = smile_binary(0x26, &[0xff, 0x00]);
+        assert_eq!(json!(-256), smile(SMILE_NOT_SHARED, &negative).unwrap());
+        // 12345 with a scale of 2, the scale is zigzag encoded
+        let big_decimal = [&[0x2a][..], &vint(4), &vint(2), &encode_7bit(&[0x30, 0x39])].concat();
+        assert_eq!(
+            json!(123.45),
+            smile(SMILE_NOT_SHARED, &big_decimal).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_smile() {
+        let content = [
+            &[0xfa, 0x83][..],
+            b"name",
+            &[0x42],
+            b"val",
+            &smile_binary(0xe8, b"binary"),
+            &[0xfb],
+        ]
+        .concat();
+        for end in 0..content.len() {
+            assert!(
+                smile(SMILE_SHARED, &content[..end]).is_err(),
+                "truncated at {}",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn limits_smile_nesting() {
+        // arrays in objects, `{"a":[{"a":[...]}]}`
+        let nested = |depth: usize| {
+            let mut content = Vec::new();
+            for level in 0..depth {
+                content.extend_from_slice(if level % 2 == 0 {
+                    &[0xfa, 0x80, b'a']
+                } else {
+                    &[0xf8]
+                });
+            }
+            for level in (0..depth).rev() {
+                content.push(if level % 2 == 0 { 0xfb } else { 0xf9 });
+            }
+            content
+        };
+        let value = smile(SMILE_NOT_SHARED, &nested(SMILE_MAX_DEPTH)).unwrap();
+        let mut depth = 0;
+        let mut current = &value;
+        while let Some(inner) = current.get("a").or_else(|| current.get(0)) {
+            current = inner;
+            depth += 1;
+        }
+        assert_eq!(depth, SMILE_MAX_DEPTH - 1);
+
+        let err = smile(SMILE_NOT_SHARED, &nested(SMILE_MAX_DEPTH + 1)).unwrap_err();
+        assert!(
+            err.to_string().contains("nested deeper than 128 levels"),
+            "{}",
+            err
+        );
+
+        // a large body of array starts must fail instead of overflowing the stack
+        let content = vec![0xf8; 500_000];
+        assert!(smile(SMILE_SHARED, &content).is_err());
+    }
+
+    #[test]
+    fn rejects_smile_binary_longer_than_content() {
+        // a length read from garbage must not be allocated
+        for token in [0x26, 0xe8, 0xfd] {
+            let content = [&[token][..], &vint(u64::MAX >> 1), &[0x01, 0x02]].concat();
+            assert!(smile(SMILE_NOT_SHARED, &content).is_err());
+        }
+    }
+
+    #[test]
+    fn converts_cbor() {
+        let content = [
+            // self describe tag, map of 3
+            0xd9, 0xd9, 0xf7, 0xa3, //
+            0x61, b'a', 0x01, //
+            0x61, b'b', 0x82, 0xf5, 0xf6, //
+            0x61, b'c', 0x42, 0x01, 0x02,
+        ];
+        assert_eq!(
+            json!({"a": 1, "b": [true, null], "c": "AQI="}),
+            parse(XContentType::Cbor, &content).unwrap()
+        );
+    }
+
+    #[test]
+    fn converts_cbor_numbers_out_of_long_range() {
+        let max = [0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(json!(u64::MAX), parse(XContentType::Cbor, &max).unwrap());
+        let min = [0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(
+            json!("-18446744073709551616"),
+            parse(XContentType::Cbor, &min).unwrap()
+        );
+        // non string keys are written as their JSON
+        let map = [0xa1, 0x01, 0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0];
+        assert_eq!(json!({"1": 1.5}), parse(XContentType::Cbor, &map).unwrap());
+    }
+
+    #[test]
+    fn rejects_truncated_cbor() {
+        let content = [0xa2, 0x61, b'a', 0x01, 0x61, b'b', 0x63, b'x', b'y', b'z'];
+        for end in 0..content.len() {
+            assert!(
+                parse(XContentType::Cbor, &content[..end]).is_err(),
+                "truncated at {}",
+                end
+            );
+        }
+        assert!(parse(XContentType::Cbor, &content).is_ok());
+    }
+}
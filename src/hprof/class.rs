@@ -1,6 +1,7 @@
-use jvm_hprof::heap_dump::{Class, FieldDescriptors};
+use jvm_hprof::heap_dump::{Class, FieldDescriptors, FieldValue};
+use jvm_hprof::Id;
 
-use super::{instance::JavaInstance, ClassId, JavaProfile};
+use super::{instance::JavaInstance, ClassId, JavaFieldValue, JavaLocalValue, JavaProfile};
 
 pub struct JavaClass<'a> {
     class: Class<'a>,
@@ -23,7 +24,7 @@ impl<'a> JavaClass<'a> {
         self.class.obj_id().into()
     }
 
-    pub fn instances(&self, profile: &'a JavaProfile) -> Vec<&JavaInstance<'a>> {
+    pub fn instances(&self, profile: &'a JavaProfile) -> Vec<&'a JavaInstance<'a>> {
         profile
             .class_instance_map
             .get(&self.id())
@@ -43,11 +44,59 @@ impl<'a> JavaClass<'a> {
             })
             .unwrap_or_default()
     }
-    pub fn instance_field_descriptors(&self) -> FieldDescriptors {
+
+    pub fn instance_field_descriptors(&self) -> FieldDescriptors<'_> {
         self.class.instance_field_descriptors()
     }
 
     pub fn parent_class(&self) -> Option<ClassId> {
         self.class.super_class_obj_id().map(ClassId::from)
     }
+
+    /// Ids of the Utf8 records naming the class and its static and instance fields
+    pub fn name_ids(&self, profile: &JavaProfile) -> Vec<Id> {
+        profile
+            .load_classes
+            .get(&self.id())
+            .map(|lc| lc.class_name_id())
+            .into_iter()
+            .chain(
+                self.class
+                    .static_fields()
+                    .filter_map(|f| f.ok())
+                    .map(|f| f.name_id()),
+            )
+            .chain(
+                self.class
+                    .instance_field_descriptors()
+                    .filter_map(|f| f.ok())
+                    .map(|f| f.name_id()),
+            )
+            .collect()
+    }
+
+    /// Value of the named static field, `None` when the class has no such field
+    pub fn static_value(&self, profile: &'a JavaProfile, name: &str) -> Option<JavaLocalValue<'a>> {
+        self.class
+            .static_fields()
+            .filter_map(|f| f.ok())
+            .find_map(|f| match profile.strings.get(&f.name_id().into()) {
+                Some(&field_name) if field_name == name => {
+                    Some(JavaFieldValue::new(field_name, f.value()).value(profile))
+                }
+                _ => None,
+            })
+    }
+
+    /// Ids of all objects referenced from static fields
+    pub fn static_references(&self) -> Vec<Id> {
+        self.class
+            .static_fields()
+            .filter_map(|f| f.ok())
+            .filter_map(|f| match f.value() {
+                FieldValue::ObjectId(id) => id,
+                _ => None,
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,280 @@
+//! Readers for the contents of common collections.
+//!
+//! Collections are recognized by the layout of their fields rather than by class name, so JDK
+//! versions, library implementations and subclasses sharing a layout are all handled alike.
+
+use ahash::AHashSet;
+
+use super::{JavaInstance, JavaLocalValue, JavaObjectArray, JavaProfile, PrimitiveArrayValues};
+
+/// Fields through which wrapper collections (unmodifiable, synchronized, ...) delegate
+const DELEGATE_FIELDS: [&str; 7] = ["m", "c", "map", "list", "queue", "delegate", "in"];
+
+impl<'a> JavaProfile<'a> {
+    /// Reads key/value pairs of a map.
+    ///
+    /// Supports hash based maps (`HashMap`, `ConcurrentHashMap`, `IdentityHashMap`, ...),
+    /// `TreeMap`, immutable maps, hppc maps used by `ImmutableOpenMap` and wrappers around them.
+    pub fn map_entries(
+        &'a self,
+        map: &'a JavaInstance<'a>,
+    ) -> Vec<(JavaLocalValue<'a>, JavaLocalValue<'a>)> {
+        let fields = map.fields(self);
+        let get = |name: &str| fields.value::<JavaLocalValue>(self, name);
+
+        if let Some(JavaLocalValue::ObjectArray(table)) = get("table") {
+            return self.hash_table_entries(table);
+        }
+        if fields.fields.contains_key("root") {
+            return match get("root") {
+                Some(JavaLocalValue::Object(root)) => self.tree_entries(root),
+                _ => Vec::new(),
+            };
+        }
+        if let (Some(keys), Some(JavaLocalValue::ObjectArray(values))) =
+            (get("keys"), get("values"))
+        {
+            let has_empty_key = fields.value(self, "hasEmptyKey").unwrap_or(false);
+            return hppc_keys(self, keys, has_empty_key)
+                .into_iter()
+                .zip(values.elements(self))
+                .filter_map(|(key, value)| key.map(|key| (key, value)))
+                .collect();
+        }
+        if let Some(key) = get("k0") {
+            return match key {
+                JavaLocalValue::Null => Vec::new(),
+                key => vec![(key, get("v0").unwrap_or(JavaLocalValue::Null))],
+            };
+        }
+        for delegate in DELEGATE_FIELDS {
+            if let Some(JavaLocalValue::Object(delegate)) = get(delegate) {
+                return self.map_entries(delegate);
+            }
+        }
+
+        log::debug!(
+            "Unsupported map class {}",
+            map.name(self).unwrap_or("unknown")
+        );
+        Vec::new()
+    }
+
+    /// Reads elements of a list, set or queue.
+    ///
+    /// Supports array backed collections (`ArrayList`, `ArrayDeque`, `ArrayBlockingQueue`,
+    /// `PriorityQueue`, ...), linked ones (`LinkedList`, `LinkedBlockingQueue`,
+    /// `LinkedTransferQueue`, ...), sets backed by maps and wrappers around them.
+    pub fn collection_items(&'a self, collection: &'a JavaInstance<'a>) -> Vec<JavaLocalValue<'a>> {
+        let fields = collection.fields(self);
+        let get = |name: &str| fields.value::<JavaLocalValue>(self, name);
+        let int = |name: &str| fields.value::<i32>(self, name).map(|i| i.max(0) as usize);
+
+        // ArrayList, hppc ObjectArrayList
+        for (array, size) in [("elementData", "size"), ("buffer", "elementsCount")] {
+            if let (Some(JavaLocalValue::ObjectArray(items)), Some(size)) = (get(array), int(size))
+            {
+                return items.elements(self).into_iter().take(size).collect();
+            }
+        }
+        // ArrayDeque
+        if let (Some(JavaLocalValue::ObjectArray(items)), Some(head), Some(tail)) =
+            (get("elements"), int("head"), int("tail"))
+        {
+            let len = (tail + items.len() - head) % items.len().max(1);
+            return circular(items.elements(self), head, len);
+        }
+        // ArrayBlockingQueue
+        if let (Some(JavaLocalValue::ObjectArray(items)), Some(head), Some(count)) =
+            (get("items"), int("takeIndex"), int("count"))
+        {
+            return circular(items.elements(self), head, count);
+        }
+        // PriorityQueue, PriorityBlockingQueue
+        if let (Some(JavaLocalValue::ObjectArray(items)), Some(size)) = (get("queue"), int("size"))
+        {
+            return items.elements(self).into_iter().take(size).collect();
+        }
+        // immutable collections, CopyOnWriteArrayList, Arrays.asList
+        for array in ["elements", "array", "a"] {
+            if let Some(JavaLocalValue::ObjectArray(items)) = get(array) {
+                return non_null(items.elements(self));
+            }
+        }
+        if fields.fields.contains_key("e0") {
+            return non_null(vec![
+                get("e0").unwrap_or(JavaLocalValue::Null),
+                get("e1").unwrap_or(JavaLocalValue::Null),
+            ]);
+        }
+        for head in ["head", "first"] {
+            if fields.fields.contains_key(head) {
+                return match get(head) {
+                    Some(JavaLocalValue::Object(node)) => self.linked_items(node),
+                    _ => Vec::new(),
+                };
+            }
+        }
+        // sets backed by maps
+        for map in ["map", "m"] {
+            if let Some(JavaLocalValue::Object(map)) = get(map) {
+                let entries = self.map_entries(map);
+                if !entries.is_empty() {
+                    return entries.into_iter().map(|(key, _)| key).collect();
+                }
+            }
+        }
+        for delegate in DELEGATE_FIELDS {
+            if let Some(JavaLocalValue::Object(delegate)) = get(delegate) {
+                return self.collection_items(delegate);
+            }
+        }
+
+        log::debug!(
+            "Unsupported collection class {}",
+            collection.name(self).unwrap_or("unknown")
+        );
+        Vec::new()
+    }
+
+    fn hash_table_entries(
+        &'a self,
+        table: &'a JavaObjectArray<'a>,
+    ) -> Vec<(JavaLocalValue<'a>, JavaLocalValue<'a>)> {
+        let elements = table.elements(self);
+        let is_node_table = elements.iter().any(|e| {
+            matches!(e, JavaLocalValue::Object(node)
+                if node.find_field_by_name(self, "key").is_some()
+                    && node.find_field_by_name(self, "next").is_some())
+        });
+        if !is_node_table {
+            // IdentityHashMap and immutable maps keep keys and values interleaved in one array
+            return elements
+                .chunks_exact(2)
+                .filter(|pair| !matches!(pair[0], JavaLocalValue::Null))
+                .map(|pair| (pair[0], pair[1]))
+                .collect();
+        }
+
+        let mut entries = Vec::new();
+        let mut visited = AHashSet::new();
+        for bucket in elements {
+            let mut node = match bucket {
+                JavaLocalValue::Object(node) => Some(node),
+                _ => None,
+            };
+            while let Some(current) = node {
+                if !visited.insert(current.id()) {
+                    break;
+                }
+                let fields = current.fields(self);
+                // ConcurrentHashMap forwarding and reservation nodes have no key
+                if let Some(key) = fields.value::<JavaLocalValue>(self, "key") {
+                    if !matches!(key, JavaLocalValue::Null) {
+                        let value = fields
+                            .value::<JavaLocalValue>(self, "value")
+                            .or_else(|| fields.value(self, "val"))
+                            .unwrap_or(JavaLocalValue::Null);
+                        entries.push((key, value));
+                    }
+                }
+                node = fields.value(self, "next");
+            }
+        }
+        entries
+    }
+
+    fn tree_entries(
+        &'a self,
+        root: &'a JavaInstance<'a>,
+    ) -> Vec<(JavaLocalValue<'a>, JavaLocalValue<'a>)> {
+        let mut entries = Vec::new();
+        let mut visited = AHashSet::new();
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node.id()) {
+                continue;
+            }
+            let fields = node.fields(self);
+            entries.push((
+                fields.value(self, "key").unwrap_or(JavaLocalValue::Null),
+                fields.value(self, "value").unwrap_or(JavaLocalValue::Null),
+            ));
+            stack.extend(fields.value::<&JavaInstance>(self, "right"));
+            stack.extend(fields.value::<&JavaInstance>(self, "left"));
+        }
+        entries
+    }
+
+    fn linked_items(&'a self, head: &'a JavaInstance<'a>) -> Vec<JavaLocalValue<'a>> {
+        let mut items = Vec::new();
+        let mut visited = AHashSet::new();
+        let mut node = Some(head);
+        while let Some(current) = node {
+            // removed nodes of concurrent queues may link to themselves
+            if !visited.insert(current.id()) {
+                break;
+            }
+            let fields = current.fields(self);
+            match fields
+                .value::<JavaLocalValue>(self, "item")
+                .or_else(|| fields.value(self, "value"))
+            {
+                Some(JavaLocalValue::Null) | None => {}
+                Some(item) => items.push(item),
+            }
+            node = fields.value(self, "next");
+        }
+        items
+    }
+}
+
+/// hppc maps store keys in open addressing arrays, empty slots hold a default value.
+/// The "empty" key itself is kept in an extra slot at the end of the array.
+fn hppc_keys<'a>(
+    profile: &'a JavaProfile,
+    keys: JavaLocalValue<'a>,
+    has_empty_key: bool,
+) -> Vec<Option<JavaLocalValue<'a>>> {
+    match keys {
+        JavaLocalValue::ObjectArray(keys) => keys
+            .elements(profile)
+            .into_iter()
+            .map(|key| match key {
+                JavaLocalValue::Null => None,
+                key => Some(key),
+            })
+            .collect(),
+        JavaLocalValue::PrimitiveArray(keys) => {
+            let keys: Vec<i64> = match keys.values() {
+                PrimitiveArrayValues::Int(keys) => keys.into_iter().map(i64::from).collect(),
+                PrimitiveArrayValues::Long(keys) => keys,
+                _ => Vec::new(),
+            };
+            let last = keys.len().saturating_sub(1);
+            keys.into_iter()
+                .enumerate()
+                .map(|(i, key)| {
+                    (key != 0 || (i == last && has_empty_key)).then_some(JavaLocalValue::Long(key))
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn circular(items: Vec<JavaLocalValue>, start: usize, len: usize) -> Vec<JavaLocalValue> {
+    if items.is_empty() {
+        return Vec::new();
+    }
+    (0..len.min(items.len()))
+        .map(|i| items[(start + i) % items.len()])
+        .collect()
+}
+
+fn non_null(items: Vec<JavaLocalValue>) -> Vec<JavaLocalValue> {
+    items
+        .into_iter()
+        .filter(|i| !matches!(i, JavaLocalValue::Null))
+        .collect()
+}
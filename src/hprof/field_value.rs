@@ -2,8 +2,9 @@ use std::borrow::Cow;
 
 use jvm_hprof::heap_dump::FieldValue;
 
-use super::{JavaInstance, JavaObjectArray, JavaPrimitiveArray, JavaProfile, Object};
+use super::{JavaInstance, JavaObjectArray, JavaPrimitiveArray, JavaProfile, ObjectId};
 
+#[derive(Clone, Copy)]
 pub enum JavaLocalValue<'a> {
     Object(&'a JavaInstance<'a>),
     ObjectArray(&'a JavaObjectArray<'a>),
@@ -20,7 +21,17 @@ pub enum JavaLocalValue<'a> {
 }
 
 impl<'a> JavaLocalValue<'a> {
-    pub fn type_name(&'a self, profile: &'a JavaProfile) -> Cow<str> {
+    /// Id of the referenced object, `None` for primitives and nulls
+    pub fn object_id(&self) -> Option<ObjectId> {
+        match self {
+            JavaLocalValue::Object(o) => Some(o.id()),
+            JavaLocalValue::ObjectArray(oa) => Some(oa.id()),
+            JavaLocalValue::PrimitiveArray(a) => Some(a.id()),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&'a self, profile: &'a JavaProfile) -> Cow<'a, str> {
         match self {
             JavaLocalValue::Object(o) => o
                 .class(profile)
@@ -61,17 +72,9 @@ impl<'a> JavaFieldValue<'a> {
         self.name
     }
 
-    pub fn value(&self, profile: &'a JavaProfile) -> JavaLocalValue {
+    pub fn value(&self, profile: &'a JavaProfile) -> JavaLocalValue<'a> {
         match self.field {
-            FieldValue::ObjectId(id) => id
-                .and_then(|id| {
-                    profile.objects.get(&id.into()).map(|obj| match obj {
-                        Object::Instance(obj) => JavaLocalValue::Object(obj),
-                        Object::Array(arr) => JavaLocalValue::ObjectArray(arr),
-                        Object::PrimitiveArray(arr) => JavaLocalValue::PrimitiveArray(arr),
-                    })
-                })
-                .unwrap_or(JavaLocalValue::Null),
+            FieldValue::ObjectId(id) => profile.value_of(id),
             FieldValue::Boolean(bool) => JavaLocalValue::Boolean(bool),
             FieldValue::Char(ch) => JavaLocalValue::Char(ch),
             FieldValue::Float(f) => JavaLocalValue::Float(f),
@@ -0,0 +1,353 @@
+//! Synthetic heap dumps for tests.
+//!
+//! Classes are declared by name with their own instance fields, instances are given values by field
+//! name. Static fields, class loaders and protection domains are added to declared classes.
+//! Everything is written to a single heap dump segment when the dump is built.
+
+use ahash::AHashMap;
+use jvm_hprof::heap_dump::{FieldType, FieldValue, PrimitiveArrayType};
+use jvm_hprof::{HprofWriter, Id, IdSize, RecordTag};
+
+const STRING_CLASS: &str = "java/lang/String";
+
+struct FixtureClass {
+    id: Id,
+    super_class: Option<Id>,
+    /// Fields declared by the class itself
+    fields: Vec<(String, FieldType)>,
+    statics: Vec<(String, FieldValue)>,
+    class_loader: Option<Id>,
+    protection_domain: Option<Id>,
+}
+
+enum FixtureObject {
+    Instance {
+        id: Id,
+        class: Id,
+        values: Vec<FieldValue>,
+    },
+    ObjectArray {
+        id: Id,
+        class: Id,
+        elements: Vec<Option<Id>>,
+    },
+    PrimitiveArray {
+        id: Id,
+        primitive_type: PrimitiveArrayType,
+        contents: Vec<u8>,
+    },
+}
+
+pub struct HeapBuilder {
+    next_id: u64,
+    strings: AHashMap<String, Id>,
+    classes: AHashMap<String, FixtureClass>,
+    /// Class names in declaration order, superclasses come first
+    class_order: Vec<String>,
+    objects: Vec<FixtureObject>,
+    gc_roots: Vec<Id>,
+}
+
+impl HeapBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self {
+            next_id: 0x1000,
+            strings: Default::default(),
+            classes: Default::default(),
+            class_order: Default::default(),
+            objects: Default::default(),
+            gc_roots: Default::default(),
+        };
+        builder.class("java/lang/Object", None, &[]);
+        builder
+    }
+
+    fn next_id(&mut self) -> Id {
+        // aligned like real object addresses
+        self.next_id += 0x10;
+        Id::from(self.next_id)
+    }
+
+    fn utf8(&mut self, text: &str) -> Id {
+        if let Some(id) = self.strings.get(text) {
+            return *id;
+        }
+        let id = self.next_id();
+        self.strings.insert(text.to_string(), id);
+        id
+    }
+
+    /// Declares a class with its own instance fields, `super_class` must be declared before,
+    /// `java/lang/Object` when `None`
+    pub fn class(
+        &mut self,
+        name: &str,
+        super_class: Option<&str>,
+        fields: &[(&str, FieldType)],
+    ) -> Id {
+        let super_class = match (super_class, name) {
+            (_, "java/lang/Object") => None,
+            (Some(super_class), _) => Some(self.class_id(super_class)),
+            (None, _) => Some(self.class_id("java/lang/Object")),
+        };
+        self.utf8(name);
+        for (field, _) in fields {
+            self.utf8(field);
+        }
+        let id = self.next_id();
+        self.classes.insert(
+            name.to_string(),
+            FixtureClass {
+                id,
+                super_class,
+                fields: fields
+                    .iter()
+                    .map(|(field, field_type)| (field.to_string(), *field_type))
+                    .collect(),
+                statics: Vec::new(),
+                class_loader: None,
+                protection_domain: None,
+            },
+        );
+        self.class_order.push(name.to_string());
+        id
+    }
+
+    fn class_id(&self, name: &str) -> Id {
+        match self.classes.get(name) {
+            Some(class) => class.id,
+            None => panic!("Class {} not declared", name),
+        }
+    }
+
+    fn class_mut(&mut self, name: &str) -> &mut FixtureClass {
+        match self.classes.get_mut(name) {
+            Some(class) => class,
+            None => panic!("Class {} not declared", name),
+        }
+    }
+
+    pub fn static_field(&mut self, class: &str, name: &str, value: FieldValue) {
+        self.utf8(name);
+        self.class_mut(class)
+            .statics
+            .push((name.to_string(), value));
+    }
+
+    pub fn class_loader(&mut self, class: &str, loader: Id) {
+        self.class_mut(class).class_loader = Some(loader);
+    }
+
+    pub fn protection_domain(&mut self, class: &str, protection_domain: Id) {
+        self.class_mut(class).protection_domain = Some(protection_domain);
+    }
+
+    /// Fields of the class followed by those of its superclasses, as instances are serialized
+    fn all_fields(&self, name: &str) -> Vec<(String, FieldType)> {
+        let mut fields = Vec::new();
+        let mut current = self.classes.get(name);
+        while let Some(class) = current {
+            fields.extend(class.fields.iter().cloned());
+            current = class.super_class.and_then(|super_class| {
+                self.classes
+                    .values()
+                    .find(|candidate| candidate.id == super_class)
+            });
+        }
+        fields
+    }
+
+    /// Adds an instance, fields without a value are zero or null. A value is given to the field of
+    /// the most specific class with its name, shadowed superclass fields keep their default
+    pub fn instance(&mut self, class: &str, values: &[(&str, FieldValue)]) -> Id {
+        let fields = self.all_fields(class);
+        let mut field_values = fields
+            .iter()
+            .map(|(_, field_type)| default_value(*field_type))
+            .collect::<Vec<_>>();
+        for (name, value) in values {
+            // fields of the class come before those of its superclasses
+            let index = match fields.iter().position(|(field, _)| field == name) {
+                Some(index) => index,
+                None => panic!("Class {} has no field {}", class, name),
+            };
+            let field_type = fields[index].1;
+            assert_eq!(
+                value.field_type().type_code(),
+                field_type.type_code(),
+                "Field {}.{} is {}, not {}",
+                class,
+                name,
+                field_type.java_type_name(),
+                value.field_type().java_type_name()
+            );
+            field_values[index] = *value;
+        }
+        let id = self.next_id();
+        let class = self.class_id(class);
+        self.objects.push(FixtureObject::Instance {
+            id,
+            class,
+            values: field_values,
+        });
+        id
+    }
+
+    pub fn byte_array(&mut self, bytes: &[u8]) -> Id {
+        self.primitive_array(PrimitiveArrayType::Byte, bytes.to_vec())
+    }
+
+    pub fn char_array(&mut self, text: &str) -> Id {
+        let contents = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        self.primitive_array(PrimitiveArrayType::Char, contents)
+    }
+
+    fn primitive_array(&mut self, primitive_type: PrimitiveArrayType, contents: Vec<u8>) -> Id {
+        let id = self.next_id();
+        self.objects.push(FixtureObject::PrimitiveArray {
+            id,
+            primitive_type,
+            contents,
+        });
+        id
+    }
+
+    /// Adds an array of `element_class`, declaring the array class on first use
+    pub fn object_array(&mut self, element_class: &str, elements: &[Option<Id>]) -> Id {
+        let array_class = format!("[L{element_class};");
+        if !self.classes.contains_key(&array_class) {
+            self.class(&array_class, None, &[]);
+        }
+        let id = self.next_id();
+        let class = self.class_id(&array_class);
+        self.objects.push(FixtureObject::ObjectArray {
+            id,
+            class,
+            elements: elements.to_vec(),
+        });
+        id
+    }
+
+    /// Adds a compact Latin-1 `java.lang.String`, declaring the class on first use
+    pub fn string(&mut self, value: &str) -> Id {
+        if !self.classes.contains_key(STRING_CLASS) {
+            self.class(
+                STRING_CLASS,
+                None,
+                &[("value", FieldType::ObjectId), ("coder", FieldType::Byte)],
+            );
+        }
+        let bytes = value.chars().map(|c| c as u8).collect::<Vec<_>>();
+        let bytes = self.byte_array(&bytes);
+        self.instance(
+            STRING_CLASS,
+            &[("value", FieldValue::ObjectId(Some(bytes)))],
+        )
+    }
+
+    pub fn gc_root(&mut self, id: Id) {
+        self.gc_roots.push(id);
+    }
+
+    /// Writes the dump with 64 bit ids
+    pub fn build(&self) -> Vec<u8> {
+        self.write().expect("Writing to memory does not fail")
+    }
+
+    fn write(&self) -> std::io::Result<Vec<u8>> {
+        let mut writer = HprofWriter::new(Vec::new(), "JAVA PROFILE 1.0.2", IdSize::U64, 0)?;
+        let mut strings = self.strings.iter().collect::<Vec<_>>();
+        strings.sort_by_key(|(_, id)| id.id());
+        for (text, id) in strings {
+            writer.write_utf8(0, *id, text.as_bytes())?;
+        }
+        writer.write_stack_trace(0, 0.into(), 0.into(), &[])?;
+        for (serial, name) in self.class_order.iter().enumerate() {
+            writer.write_load_class(
+                0,
+                (serial as u32 + 1).into(),
+                self.classes[name].id,
+                0.into(),
+                self.strings[name],
+            )?;
+        }
+
+        let mut segment = writer.buffered_heap_dump_segment(RecordTag::HeapDumpSegment, 0)?;
+        for name in &self.class_order {
+            let class = &self.classes[name];
+            let fields = class
+                .fields
+                .iter()
+                .map(|(field, field_type)| (self.strings[field], *field_type))
+                .collect::<Vec<_>>();
+            let statics = class
+                .statics
+                .iter()
+                .map(|(field, value)| (self.strings[field], *value))
+                .collect::<Vec<_>>();
+            let instance_size = self
+                .all_fields(name)
+                .iter()
+                .map(|(_, field_type)| field_size(*field_type))
+                .sum();
+            segment.write_class(
+                class.id,
+                0.into(),
+                class.super_class,
+                class.class_loader,
+                None,
+                class.protection_domain,
+                instance_size,
+                &statics,
+                &fields,
+            )?;
+        }
+        for object in &self.objects {
+            match object {
+                FixtureObject::Instance { id, class, values } => {
+                    segment.write_instance(*id, 0.into(), *class, values)?
+                }
+                FixtureObject::ObjectArray {
+                    id,
+                    class,
+                    elements,
+                } => segment.write_object_array(*id, 0.into(), *class, elements)?,
+                FixtureObject::PrimitiveArray {
+                    id,
+                    primitive_type,
+                    contents,
+                } => segment.write_primitive_array(*id, 0.into(), *primitive_type, contents)?,
+            }
+        }
+        for root in &self.gc_roots {
+            segment.write_gc_root_unknown(*root)?;
+        }
+        segment.finish()?;
+        writer.write_heap_dump_end(0)?;
+        writer.into_inner()
+    }
+}
+
+fn default_value(field_type: FieldType) -> FieldValue {
+    match field_type {
+        FieldType::ObjectId => FieldValue::ObjectId(None),
+        FieldType::Boolean => FieldValue::Boolean(false),
+        FieldType::Char => FieldValue::Char(0),
+        FieldType::Float => FieldValue::Float(0.0),
+        FieldType::Double => FieldValue::Double(0.0),
+        FieldType::Byte => FieldValue::Byte(0),
+        FieldType::Short => FieldValue::Short(0),
+        FieldType::Int => FieldValue::Int(0),
+        FieldType::Long => FieldValue::Long(0),
+    }
+}
+
+fn field_size(field_type: FieldType) -> u32 {
+    match field_type {
+        FieldType::ObjectId => 8,
+        FieldType::Boolean | FieldType::Byte => 1,
+        FieldType::Char | FieldType::Short => 2,
+        FieldType::Float | FieldType::Int => 4,
+        FieldType::Double | FieldType::Long => 8,
+    }
+}
@@ -0,0 +1,255 @@
+//! Object reference graph and its dominator tree, used to compute retained sizes.
+//!
+//! An object `d` dominates object `o` when every path from the GC roots to `o` goes through `d`,
+//! the retained size of `d` is then the total size of all objects it dominates.
+//! The dominator tree is computed with the Lengauer-Tarjan algorithm, all recursion is replaced
+//! with explicit stacks as reference chains in heap dumps can be millions of objects long.
+
+use ahash::AHashMap;
+
+use super::{object_header_size, ClassId, JavaProfile, Object, ObjectId};
+
+const NONE: u32 = u32::MAX;
+
+/// Adjacency lists in compressed sparse row form
+struct Edges {
+    offsets: Vec<usize>,
+    targets: Vec<u32>,
+}
+
+impl Edges {
+    fn of(&self, node: u32) -> &[u32] {
+        let node = node as usize;
+        &self.targets[self.offsets[node]..self.offsets[node + 1]]
+    }
+}
+
+pub struct ObjectGraph {
+    index: AHashMap<ObjectId, u32>,
+    retained: Vec<u64>,
+}
+
+impl ObjectGraph {
+    pub fn new(profile: &JavaProfile) -> Self {
+        let mut nodes: Vec<ObjectId> = profile
+            .classes
+            .keys()
+            .map(|&id| ObjectId::from(id))
+            .chain(profile.objects.keys().copied())
+            .collect();
+        // stable order keeps the choice of roots for unreachable objects deterministic
+        nodes.sort_unstable();
+        let index: AHashMap<ObjectId, u32> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i as u32))
+            .collect();
+
+        let mut sizes = Vec::with_capacity(nodes.len() + 1);
+        let mut offsets = Vec::with_capacity(nodes.len() + 2);
+        let mut targets = Vec::new();
+        offsets.push(0);
+        for id in &nodes {
+            let (size, references) = match profile.objects.get(id) {
+                Some(Object::Instance(instance)) => {
+                    (instance.shallow_size(profile), instance.references(profile))
+                }
+                Some(Object::Array(array)) => {
+                    (array.shallow_size(profile), array.references(profile))
+                }
+                Some(Object::PrimitiveArray(array)) => (array.shallow_size(profile), Vec::new()),
+                None => (
+                    object_header_size(profile),
+                    profile
+                        .classes
+                        .get(&ClassId::from(*id))
+                        .map(|class| class.static_references())
+                        .unwrap_or_default(),
+                ),
+            };
+            sizes.push(size);
+            targets.extend(
+                references
+                    .into_iter()
+                    .filter_map(|reference| index.get(&reference.into())),
+            );
+            offsets.push(targets.len());
+        }
+
+        // virtual root, the last node, referencing all GC roots and classes
+        let root = nodes.len() as u32;
+        sizes.push(0);
+        let mut roots: Vec<u32> = profile
+            .gc_roots
+            .iter()
+            .copied()
+            .chain(profile.classes.keys().map(|&id| ObjectId::from(id)))
+            .filter_map(|id| index.get(&id).copied())
+            .collect();
+        roots.sort_unstable();
+        roots.dedup();
+        let mut edges = Edges { offsets, targets };
+        add_unreachable_roots(&edges, &mut roots, nodes.len());
+        edges.targets.extend(roots);
+        edges.offsets.push(edges.targets.len());
+
+        let retained = retained_sizes(&edges, root, sizes);
+        Self { index, retained }
+    }
+
+    pub fn retained_size(&self, object_id: &ObjectId) -> Option<u64> {
+        self.index
+            .get(object_id)
+            .map(|&node| self.retained[node as usize])
+    }
+}
+
+/// Objects not reachable from GC roots would be dropped by the next GC, but they're still
+/// interesting for analysis (e.g. recently released requests). Each group of them is attached
+/// to the virtual root through one of its objects.
+fn add_unreachable_roots(edges: &Edges, roots: &mut Vec<u32>, nodes: usize) {
+    let mut reached = vec![false; nodes];
+    let mark = |start: u32, reached: &mut Vec<bool>| {
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            if reached[node as usize] {
+                continue;
+            }
+            reached[node as usize] = true;
+            stack.extend(
+                edges
+                    .of(node)
+                    .iter()
+                    .filter(|&&target| !reached[target as usize]),
+            );
+        }
+    };
+    for &root in roots.iter() {
+        mark(root, &mut reached);
+    }
+    let mut unreachable = 0;
+    for node in 0..nodes as u32 {
+        if !reached[node as usize] {
+            unreachable += 1;
+            roots.push(node);
+            mark(node, &mut reached);
+        }
+    }
+    log::debug!("{unreachable} unreachable object groups");
+}
+
+fn predecessors(edges: &Edges, nodes: usize) -> Edges {
+    let mut offsets = vec![0; nodes + 1];
+    for &target in &edges.targets {
+        offsets[target as usize + 1] += 1;
+    }
+    for i in 0..nodes {
+        offsets[i + 1] += offsets[i];
+    }
+    let mut position = offsets.clone();
+    let mut targets = vec![0; edges.targets.len()];
+    for source in 0..nodes as u32 {
+        for &target in edges.of(source) {
+            targets[position[target as usize]] = source;
+            position[target as usize] += 1;
+        }
+    }
+    Edges { offsets, targets }
+}
+
+fn retained_sizes(edges: &Edges, root: u32, mut sizes: Vec<u64>) -> Vec<u64> {
+    let nodes = sizes.len();
+    let predecessors = predecessors(edges, nodes);
+
+    // depth first numbering
+    let mut dfnum = vec![NONE; nodes];
+    let mut vertex = Vec::with_capacity(nodes);
+    let mut parent = vec![NONE; nodes];
+    let mut stack = vec![(root, 0)];
+    dfnum[root as usize] = 0;
+    vertex.push(root);
+    while let Some((node, position)) = stack.last_mut() {
+        let successors = edges.of(*node);
+        if *position < successors.len() {
+            let successor = successors[*position];
+            *position += 1;
+            if dfnum[successor as usize] == NONE {
+                dfnum[successor as usize] = vertex.len() as u32;
+                vertex.push(successor);
+                parent[successor as usize] = *node;
+                stack.push((successor, 0));
+            }
+        } else {
+            stack.pop();
+        }
+    }
+
+    let mut semi = dfnum.clone();
+    let mut label: Vec<u32> = (0..nodes as u32).collect();
+    let mut ancestor = vec![NONE; nodes];
+    let mut idom = vec![NONE; nodes];
+    let mut bucket_head = vec![NONE; nodes];
+    let mut bucket_next = vec![NONE; nodes];
+    let mut path = Vec::new();
+
+    for i in (1..vertex.len()).rev() {
+        let w = vertex[i];
+        for &v in predecessors.of(w) {
+            if dfnum[v as usize] == NONE {
+                continue;
+            }
+            let u = eval(v, &mut ancestor, &mut label, &semi, &mut path);
+            if semi[u as usize] < semi[w as usize] {
+                semi[w as usize] = semi[u as usize];
+            }
+        }
+        let s = vertex[semi[w as usize] as usize];
+        bucket_next[w as usize] = bucket_head[s as usize];
+        bucket_head[s as usize] = w;
+
+        let p = parent[w as usize];
+        ancestor[w as usize] = p;
+
+        let mut v = std::mem::replace(&mut bucket_head[p as usize], NONE);
+        while v != NONE {
+            let u = eval(v, &mut ancestor, &mut label, &semi, &mut path);
+            idom[v as usize] = if semi[u as usize] < semi[v as usize] {
+                u
+            } else {
+                p
+            };
+            v = bucket_next[v as usize];
+        }
+    }
+    for &w in vertex.iter().skip(1) {
+        if idom[w as usize] != vertex[semi[w as usize] as usize] {
+            idom[w as usize] = idom[idom[w as usize] as usize];
+        }
+    }
+
+    // children always come after their dominators in depth first order
+    for &w in vertex.iter().skip(1).rev() {
+        sizes[idom[w as usize] as usize] += sizes[w as usize];
+    }
+    sizes
+}
+
+fn eval(v: u32, ancestor: &mut [u32], label: &mut [u32], semi: &[u32], path: &mut Vec<u32>) -> u32 {
+    if ancestor[v as usize] == NONE {
+        return v;
+    }
+    // path compression
+    let mut node = v;
+    while ancestor[ancestor[node as usize] as usize] != NONE {
+        path.push(node);
+        node = ancestor[node as usize];
+    }
+    while let Some(node) = path.pop() {
+        let a = ancestor[node as usize] as usize;
+        if semi[label[a] as usize] < semi[label[node as usize] as usize] {
+            label[node as usize] = label[a];
+        }
+        ancestor[node as usize] = ancestor[a];
+    }
+    label[v as usize]
+}
@@ -1,4 +1,6 @@
 use std::fmt::Display;
+use std::num::ParseIntError;
+use std::str::FromStr;
 
 use jvm_hprof::Id;
 
@@ -27,8 +29,51 @@ impl From<Id> for ObjectId {
     }
 }
 
+impl From<ObjectId> for Id {
+    fn from(val: ObjectId) -> Self {
+        val.0
+    }
+}
+
+impl From<ClassId> for ObjectId {
+    fn from(val: ClassId) -> Self {
+        ObjectId(val.0)
+    }
+}
+
+impl From<ObjectId> for ClassId {
+    fn from(val: ObjectId) -> Self {
+        ClassId(val.0)
+    }
+}
+
+impl PartialOrd for ObjectId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ObjectId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.id().cmp(&other.0.id())
+    }
+}
+
 impl Display for ObjectId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{:#08X}", &self.0))
     }
 }
+
+/// Parses ids as displayed, hexadecimal with or without `0x`
+impl FromStr for ObjectId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .strip_prefix("0x")
+            .or_else(|| s.strip_prefix("0X"))
+            .unwrap_or(s);
+        u64::from_str_radix(hex, 16).map(|id| ObjectId(Id::from(id)))
+    }
+}
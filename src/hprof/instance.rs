@@ -1,25 +1,29 @@
 use std::iter::FromIterator;
 
+use std::slice;
+
 use ahash::AHashMap;
-use jvm_hprof::heap_dump::{FieldDescriptors, Instance};
+use jvm_hprof::heap_dump::{FieldDescriptor, FieldType, FieldValue, Instance};
+use jvm_hprof::Id;
 
 use super::{
     ClassId, JavaClass, JavaFieldValue, JavaLocalValue, JavaObjectArray, JavaPrimitiveArray,
-    JavaProfile, ObjectId,
+    JavaProfile, ObjectId, PrimitiveArrayValues,
 };
 
+/// Iterates over instance fields, including the ones inherited from superclasses.
 pub struct LocalFieldsIterator<'a> {
     fields_memory: &'a [u8],
-    fd_iter: Option<FieldDescriptors<'a>>,
+    fd_iter: Option<slice::Iter<'a, FieldDescriptor>>,
     profile: &'a JavaProfile<'a>,
 }
 
 impl<'a> LocalFieldsIterator<'a> {
     fn new(profile: &'a JavaProfile<'a>, instance: &'a Instance<'a>) -> Self {
-        let class = profile.classes.get(&instance.class_obj_id().into());
+        let fields = profile.class_fields.get(&instance.class_obj_id().into());
         Self {
             fields_memory: instance.fields(),
-            fd_iter: class.map(|c| c.instance_field_descriptors()),
+            fd_iter: fields.map(|f| f.iter()),
             profile,
         }
     }
@@ -29,7 +33,7 @@ impl<'a> Iterator for LocalFieldsIterator<'a> {
     type Item = JavaFieldValue<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(Ok(fd)) = self.fd_iter.as_mut().and_then(|fd| fd.next()) {
+        if let Some(fd) = self.fd_iter.as_mut().and_then(|fd| fd.next()) {
             if let Ok((rest_memory, value)) = fd
                 .field_type()
                 .parse_value(self.fields_memory, self.profile.hprof.header().id_size())
@@ -48,7 +52,8 @@ impl<'a> FromIterator<JavaFieldValue<'a>> for AHashMap<&'a str, JavaFieldValue<'
     fn from_iter<T: IntoIterator<Item = JavaFieldValue<'a>>>(iter: T) -> Self {
         let mut fields = AHashMap::default();
         for item in iter {
-            fields.insert(item.name(), item);
+            // subclass fields come first and shadow the superclass ones with the same name
+            fields.entry(item.name()).or_insert(item);
         }
         fields
     }
@@ -67,6 +72,7 @@ impl<'a> JavaInstance<'a> {
         self.instance.obj_id().into()
     }
 
+    /// Iterates over all instance fields, including the inherited ones.
     pub fn local_fields(&'a self, profile: &'a JavaProfile) -> LocalFieldsIterator<'a> {
         LocalFieldsIterator::new(profile, &self.instance)
     }
@@ -79,18 +85,76 @@ impl<'a> JavaInstance<'a> {
         &'a self,
         profile: &'a JavaProfile,
         name: &str,
-    ) -> Option<JavaFieldValue> {
+    ) -> Option<JavaFieldValue<'a>> {
         self.local_fields(profile).find(|f| f.name() == name)
     }
 
-    pub fn class(&'a self, profile: &'a JavaProfile) -> Option<&'a JavaClass> {
+    pub fn class(&'a self, profile: &'a JavaProfile) -> Option<&'a JavaClass<'a>> {
         let class_id = ClassId::from(self.instance.class_obj_id());
         profile.get_class_by_id(&class_id)
     }
 
+    pub fn class_id(&self) -> ClassId {
+        ClassId::from(self.instance.class_obj_id())
+    }
+
     pub fn fields(&'a self, profile: &'a JavaProfile) -> JavaInstanceFields<'a> {
         JavaInstanceFields::new(self.local_fields(profile))
     }
+
+    pub fn shallow_size(&self, profile: &JavaProfile) -> u64 {
+        super::align_size(super::object_header_size(profile) + self.instance.fields().len() as u64)
+    }
+
+    /// Ids of all objects referenced from instance fields, including inherited ones.
+    pub fn references(&self, profile: &JavaProfile) -> Vec<Id> {
+        let mut references = Vec::new();
+        let mut memory: &[u8] = self.instance.fields();
+        if let Some(fields) = profile.class_fields.get(&self.class_id()) {
+            for fd in fields {
+                match fd.field_type().parse_value(memory, profile.id_size()) {
+                    Ok((rest_memory, value)) => {
+                        memory = rest_memory;
+                        if let (FieldType::ObjectId, FieldValue::ObjectId(Some(id))) =
+                            (fd.field_type(), value)
+                        {
+                            references.push(id);
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        references
+    }
+
+    /// Reads the value of a `java.lang.String` instance.
+    ///
+    /// Handles both the compact strings layout (`byte[]` with `coder`) used since Java 9
+    /// and the older `char[]` one.
+    pub fn string_value(&'a self, profile: &'a JavaProfile) -> Option<String> {
+        let fields = self.fields(profile);
+        let value: &JavaPrimitiveArray = fields.value(profile, "value")?;
+        match value.values() {
+            PrimitiveArrayValues::Byte(bytes) => {
+                let coder: i8 = fields.value(profile, "coder").unwrap_or(0);
+                if coder == 0 {
+                    // LATIN1
+                    Some(bytes.iter().map(|&b| b as u8 as char).collect())
+                } else {
+                    // UTF16, stored in the platform byte order which is little endian on
+                    // everything elasticsearch runs on
+                    let chars = bytes
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0] as u8, c[1] as u8]))
+                        .collect::<Vec<_>>();
+                    Some(String::from_utf16_lossy(&chars))
+                }
+            }
+            PrimitiveArrayValues::Char(chars) => Some(String::from_utf16_lossy(&chars)),
+            _ => None,
+        }
+    }
 }
 
 pub struct JavaInstanceFields<'a> {
@@ -117,6 +181,12 @@ where
 {
     fn extract_value(value: &JavaLocalValue<'a>) -> Option<Self>;
 }
+impl<'a> FieldValueOutput<'a> for JavaLocalValue<'a> {
+    fn extract_value(value: &JavaLocalValue<'a>) -> Option<Self> {
+        Some(*value)
+    }
+}
+
 impl_java_value_output!(&'a JavaInstance<'a>, Object);
 impl_java_value_output!(&'a JavaObjectArray<'a>, ObjectArray);
 impl_java_value_output!(&'a JavaPrimitiveArray<'a>, PrimitiveArray);
@@ -136,7 +206,7 @@ impl<'a> JavaInstanceFields<'a> {
         }
     }
 
-    pub fn value<T>(&'a self, profile: &'a JavaProfile, name: &str) -> Option<T>
+    pub fn value<T>(&self, profile: &'a JavaProfile, name: &str) -> Option<T>
     where
         T: FieldValueOutput<'a>,
     {
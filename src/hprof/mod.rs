@@ -1,20 +1,53 @@
 mod class;
+mod collections;
 mod field_value;
+#[cfg(test)]
+pub mod fixture;
+mod graph;
 mod ids;
 mod instance;
 mod object_array;
 mod primitive_array;
+#[cfg(test)]
+mod tests;
 
+use std::cell::OnceCell;
 use std::collections::hash_map::{self, Entry};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 pub use class::*;
 pub use field_value::*;
+use graph::ObjectGraph;
 pub use ids::*;
 pub use instance::*;
-use jvm_hprof::{parse_hprof, Hprof, LoadClass};
+use jvm_hprof::heap_dump::FieldDescriptor;
+use jvm_hprof::{parse_hprof, Hprof, IdSize, LoadClass};
 pub use object_array::*;
 pub use primitive_array::*;
+use serde::Serialize;
+
+/// Size of the object header, as the hprof doesn't record it this is an estimate based on the
+/// id size
+fn object_header_size(profile: &JavaProfile) -> u64 {
+    match profile.id_size() {
+        IdSize::U32 => 8,
+        IdSize::U64 => 16,
+    }
+}
+
+/// Objects are aligned to 8 bytes in the JVM heap
+fn align_size(size: u64) -> u64 {
+    (size + 7) & !7
+}
+
+/// Number and shallow size of the objects of a class
+#[derive(Serialize)]
+pub struct ClassUsage {
+    /// Class name, primitive arrays are named after their element type, e.g. `byte[]`
+    pub name: String,
+    pub instances: u64,
+    pub shallow_size: u64,
+}
 
 pub enum Object<'a> {
     Instance(JavaInstance<'a>),
@@ -30,6 +63,9 @@ pub struct JavaProfile<'a> {
     class_id_index: AHashMap<String, ClassId>,
     objects: AHashMap<ObjectId, Object<'a>>,
     class_instance_map: AHashMap<ClassId, Vec<ObjectId>>,
+    class_fields: AHashMap<ClassId, Vec<FieldDescriptor>>,
+    gc_roots: Vec<ObjectId>,
+    graph: OnceCell<ObjectGraph>,
 }
 
 impl<'a> JavaProfile<'a> {
@@ -43,6 +79,9 @@ impl<'a> JavaProfile<'a> {
             class_id_index: Default::default(),
             objects: Default::default(),
             class_instance_map: Default::default(),
+            class_fields: Default::default(),
+            gc_roots: Default::default(),
+            graph: Default::default(),
         }
     }
 
@@ -108,8 +147,34 @@ impl<'a> JavaProfile<'a> {
                                     self.objects
                                         .insert(instance.id(), Object::PrimitiveArray(instance));
                                 }
-                                _ => {
-                                    log::trace!("Skipping unsupported heap dump sub-record type");
+                                jvm_hprof::heap_dump::SubRecord::GcRootUnknown(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootThreadObj(root) => {
+                                    if let Some(id) = root.thread_obj_id() {
+                                        self.gc_roots.push(id.into());
+                                    }
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootJniGlobal(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootJniLocalRef(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootJavaStackFrame(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootNativeStack(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootSystemClass(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootThreadBlock(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
+                                }
+                                jvm_hprof::heap_dump::SubRecord::GcRootBusyMonitor(root) => {
+                                    self.gc_roots.push(root.obj_id().into());
                                 }
                             }
                         }
@@ -131,26 +196,180 @@ impl<'a> JavaProfile<'a> {
 
     fn build_index(&mut self) {
         let mut class_id_index = AHashMap::new();
+        let mut class_fields = AHashMap::new();
         for (id, class) in self.classes() {
             class_id_index.insert(class.name(self).to_string(), *id);
+
+            // instance fields are serialized with the concrete class's fields first,
+            // followed by each superclass up to the root of the hierarchy
+            let mut fields = Vec::new();
+            let mut current = Some(class);
+            while let Some(class) = current {
+                fields.extend(class.instance_field_descriptors().flatten());
+                current = class
+                    .parent_class()
+                    .and_then(|parent| self.get_class_by_id(&parent));
+            }
+            class_fields.insert(*id, fields);
         }
         self.class_id_index = class_id_index;
+        self.class_fields = class_fields;
     }
 
-    pub fn classes(&self) -> hash_map::Iter<ClassId, JavaClass> {
+    pub fn classes(&self) -> hash_map::Iter<'_, ClassId, JavaClass<'a>> {
         self.classes.iter()
     }
 
-    pub fn get_class_by_name(&self, class_name: &str) -> Option<&JavaClass> {
+    pub fn id_size(&self) -> IdSize {
+        self.hprof.header().id_size()
+    }
+
+    pub fn get_object(&self, object_id: &ObjectId) -> Option<&Object<'a>> {
+        self.objects.get(object_id)
+    }
+
+    pub fn get_instance(&self, object_id: &ObjectId) -> Option<&JavaInstance<'a>> {
+        match self.objects.get(object_id) {
+            Some(Object::Instance(instance)) => Some(instance),
+            _ => None,
+        }
+    }
+
+    /// Resolves an object reference into a value, `JavaLocalValue::Null` when the object is not
+    /// present in the dump.
+    pub fn value_of(&self, object_id: Option<jvm_hprof::Id>) -> JavaLocalValue<'_> {
+        object_id
+            .and_then(|id| {
+                self.objects.get(&id.into()).map(|obj| match obj {
+                    Object::Instance(obj) => JavaLocalValue::Object(obj),
+                    Object::Array(arr) => JavaLocalValue::ObjectArray(arr),
+                    Object::PrimitiveArray(arr) => JavaLocalValue::PrimitiveArray(arr),
+                })
+            })
+            .unwrap_or(JavaLocalValue::Null)
+    }
+
+    /// Size of the object itself, excluding anything it references.
+    pub fn shallow_size(&self, object_id: &ObjectId) -> Option<u64> {
+        match self.objects.get(object_id)? {
+            Object::Instance(instance) => Some(instance.shallow_size(self)),
+            Object::Array(array) => Some(array.shallow_size(self)),
+            Object::PrimitiveArray(array) => Some(array.shallow_size(self)),
+        }
+    }
+
+    /// Size of all objects that would be garbage collected if this object was collected.
+    ///
+    /// The first call builds the object graph and its dominator tree, which requires a pass over
+    /// the whole heap, later calls are cheap.
+    /// Objects unreachable from GC roots are treated as if they were roots themselves,
+    /// so they still get a retained size.
+    pub fn retained_size(&self, object_id: &ObjectId) -> Option<u64> {
+        self.graph().retained_size(object_id)
+    }
+
+    fn graph(&self) -> &ObjectGraph {
+        self.graph.get_or_init(|| {
+            log::info!("Building object graph...");
+            ObjectGraph::new(self)
+        })
+    }
+
+    /// Objects in the heap grouped by class, largest shallow size first
+    pub fn class_histogram(&self) -> Vec<ClassUsage> {
+        let mut usage: AHashMap<String, (u64, u64)> = AHashMap::new();
+        for object in self.objects.values() {
+            let (name, size) = match object {
+                Object::Instance(instance) => (
+                    instance.name(self).unwrap_or("unknown").to_string(),
+                    instance.shallow_size(self),
+                ),
+                Object::Array(array) => (
+                    array.class_name(self).unwrap_or("unknown").to_string(),
+                    array.shallow_size(self),
+                ),
+                Object::PrimitiveArray(array) => (
+                    format!("{}[]", array.value_type()),
+                    array.shallow_size(self),
+                ),
+            };
+            let entry = usage.entry(name).or_default();
+            entry.0 += 1;
+            entry.1 += size;
+        }
+        let mut histogram = usage
+            .into_iter()
+            .map(|(name, (instances, shallow_size))| ClassUsage {
+                name,
+                instances,
+                shallow_size,
+            })
+            .collect::<Vec<_>>();
+        histogram.sort_by(|a, b| {
+            b.shallow_size
+                .cmp(&a.shallow_size)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        histogram
+    }
+
+    /// Objects reachable from `roots` through instance fields and array elements, roots included.
+    ///
+    /// Classes referenced on the way are included without following their static fields, those
+    /// are only followed for classes among the roots.
+    pub fn reachable_from(&self, roots: &[ObjectId]) -> AHashSet<ObjectId> {
+        let mut reached = AHashSet::new();
+        let mut stack = Vec::new();
+        for root in roots {
+            match self.classes.get(&ClassId::from(*root)) {
+                Some(class) => {
+                    reached.insert(*root);
+                    stack.extend(class.static_references().into_iter().map(ObjectId::from));
+                }
+                None => stack.push(*root),
+            }
+        }
+        while let Some(id) = stack.pop() {
+            if reached.contains(&id) {
+                continue;
+            }
+            let references = match self.objects.get(&id) {
+                Some(Object::Instance(instance)) => instance.references(self),
+                Some(Object::Array(array)) => array.references(self),
+                Some(Object::PrimitiveArray(_)) => Vec::new(),
+                None if self.classes.contains_key(&ClassId::from(id)) => Vec::new(),
+                // dangling reference, the object is not in the dump
+                None => continue,
+            };
+            reached.insert(id);
+            stack.extend(references.into_iter().map(ObjectId::from));
+        }
+        reached
+    }
+
+    pub fn get_class_by_name(&self, class_name: &str) -> Option<&JavaClass<'a>> {
         self.class_id_index
             .get(class_name)
             .and_then(|class_id| self.get_class_by_id(class_id))
     }
 
-    pub fn get_class_by_id(&self, class_id: &ClassId) -> Option<&JavaClass> {
+    pub fn get_class_by_id(&self, class_id: &ClassId) -> Option<&JavaClass<'a>> {
         self.classes.get(class_id)
     }
 
+    /// Instances of the named class and all of its subclasses
+    pub fn get_instances_by_class_name(&'a self, class_name: &str) -> Vec<&'a JavaInstance<'a>> {
+        let parent_id = match self.get_class_by_name(class_name) {
+            Some(class) => class.id(),
+            None => return Vec::new(),
+        };
+        self.classes
+            .values()
+            .filter(|class| self.is_subclass(class.id(), parent_id).unwrap_or(false))
+            .flat_map(|class| class.instances(self))
+            .collect()
+    }
+
     /// Checks if the class with the given `child_id` is a subclass of the class with the given `parent_id`.\
     /// Returns `None` if either class is not found.
     pub fn is_subclass(&self, child_id: ClassId, parent_id: ClassId) -> Option<bool> {
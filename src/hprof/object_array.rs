@@ -1,6 +1,7 @@
 use jvm_hprof::heap_dump::{NullableIds, ObjectArray};
+use jvm_hprof::Id;
 
-use super::{ClassId, JavaInstance, JavaProfile, Object, ObjectId};
+use super::{ClassId, JavaInstance, JavaLocalValue, JavaProfile, Object, ObjectId};
 
 pub struct JavaObjectArrayIterator<'a> {
     profile: &'a JavaProfile<'a>,
@@ -21,10 +22,13 @@ impl<'a> Iterator for JavaObjectArrayIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(Ok(item_id)) = self.iter.next() {
-            return item_id.map(|id| match self.profile.objects.get(&id.into()) {
-                Some(Object::Instance(instance)) => Some(instance),
-                _ => None,
-            });
+            // null elements are yielded as `None` rather than ending the iteration
+            return Some(
+                item_id.and_then(|id| match self.profile.objects.get(&id.into()) {
+                    Some(Object::Instance(instance)) => Some(instance),
+                    _ => None,
+                }),
+            );
         }
         None
     }
@@ -43,10 +47,39 @@ impl<'a> JavaObjectArray<'a> {
         self.array.obj_id().into()
     }
 
-    pub fn values(&self, profile: &'a JavaProfile) -> JavaObjectArrayIterator {
+    pub fn values(&self, profile: &'a JavaProfile) -> JavaObjectArrayIterator<'_> {
         JavaObjectArrayIterator::new(profile, &self.array)
     }
 
+    /// Array elements of any type, unlike `values` which only resolves instances.
+    pub fn elements(&'a self, profile: &'a JavaProfile) -> Vec<JavaLocalValue<'a>> {
+        self.array
+            .elements(profile.id_size())
+            .filter_map(|i| i.ok())
+            .map(|id| profile.value_of(id))
+            .collect()
+    }
+
+    /// Ids of all non null elements
+    pub fn references(&self, profile: &JavaProfile) -> Vec<Id> {
+        self.array
+            .elements(profile.id_size())
+            .filter_map(|i| i.ok().flatten())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.array.num_elements() as usize
+    }
+
+    pub fn shallow_size(&self, profile: &JavaProfile) -> u64 {
+        let id_size = match profile.id_size() {
+            jvm_hprof::IdSize::U32 => 4,
+            jvm_hprof::IdSize::U64 => 8,
+        };
+        super::align_size(super::object_header_size(profile) + 4 + self.len() as u64 * id_size)
+    }
+
     pub fn class_id(&self) -> ClassId {
         ClassId::from(self.array.array_class_obj_id())
     }
@@ -30,6 +30,20 @@ impl<'a> JavaPrimitiveArray<'a> {
         self.array.primitive_type().java_type_name()
     }
 
+    pub fn len(&self) -> usize {
+        self.array.num_elements() as usize
+    }
+
+    pub fn shallow_size(&self, profile: &super::JavaProfile) -> u64 {
+        let element_size = match self.array.primitive_type() {
+            PrimitiveArrayType::Boolean | PrimitiveArrayType::Byte => 1,
+            PrimitiveArrayType::Char | PrimitiveArrayType::Short => 2,
+            PrimitiveArrayType::Float | PrimitiveArrayType::Int => 4,
+            PrimitiveArrayType::Double | PrimitiveArrayType::Long => 8,
+        };
+        super::align_size(super::object_header_size(profile) + 4 + self.len() as u64 * element_size)
+    }
+
     pub fn values(&self) -> PrimitiveArrayValues {
         match self.array.primitive_type() {
             PrimitiveArrayType::Boolean => PrimitiveArrayValues::Boolean(
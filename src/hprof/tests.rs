@@ -0,0 +1,242 @@
+use jvm_hprof::heap_dump::{FieldType, FieldValue};
+
+use super::fixture::HeapBuilder;
+use super::*;
+
+fn profile(dump: &[u8]) -> JavaProfile<'_> {
+    let mut profile = JavaProfile::new(dump);
+    profile.process();
+    profile
+}
+
+#[test]
+fn indexes_classes_and_instances_by_name() {
+    let mut heap = HeapBuilder::new();
+    heap.class("com/example/Animal", None, &[("legs", FieldType::Int)]);
+    heap.class("com/example/Cat", Some("com/example/Animal"), &[]);
+    heap.class("com/example/Stone", None, &[]);
+    let animal = heap.instance("com/example/Animal", &[]);
+    let cat = heap.instance("com/example/Cat", &[]);
+    heap.instance("com/example/Stone", &[]);
+    let dump = heap.build();
+    let profile = profile(&dump);
+
+    let class = profile.get_class_by_name("com/example/Cat").unwrap();
+    assert_eq!("com/example/Cat", class.name(&profile));
+    assert_eq!(
+        Some(true),
+        profile.is_subclass_by_name("com/example/Cat", "com/example/Animal")
+    );
+    assert_eq!(
+        Some(false),
+        profile.is_subclass_by_name("com/example/Animal", "com/example/Cat")
+    );
+    assert_eq!(
+        None,
+        profile.is_subclass_by_name("com/example/Dog", "com/example/Animal")
+    );
+
+    let mut animals = profile
+        .get_instances_by_class_name("com/example/Animal")
+        .iter()
+        .map(|instance| instance.id())
+        .collect::<Vec<_>>();
+    animals.sort();
+    assert_eq!(vec![ObjectId::from(animal), ObjectId::from(cat)], animals);
+    assert!(profile
+        .get_instances_by_class_name("com/example/Dog")
+        .is_empty());
+}
+
+#[test]
+fn reads_inherited_fields() {
+    let mut heap = HeapBuilder::new();
+    heap.class(
+        "com/example/Base",
+        None,
+        &[("name", FieldType::ObjectId), ("size", FieldType::Long)],
+    );
+    heap.class(
+        "com/example/Derived",
+        Some("com/example/Base"),
+        &[("size", FieldType::Int), ("flag", FieldType::Boolean)],
+    );
+    let name = heap.string("derived");
+    let derived = heap.instance(
+        "com/example/Derived",
+        &[
+            ("name", FieldValue::ObjectId(Some(name))),
+            ("size", FieldValue::Int(7)),
+            ("flag", FieldValue::Boolean(true)),
+        ],
+    );
+    let dump = heap.build();
+    let profile = profile(&dump);
+
+    let instance = profile.get_instance(&derived.into()).unwrap();
+    assert_eq!(Some("com/example/Derived"), instance.name(&profile));
+    let fields = instance.fields(&profile);
+    // the subclass field shadows the one of the superclass
+    assert_eq!(Some(7), fields.value::<i32>(&profile, "size"));
+    assert_eq!(None, fields.value::<i64>(&profile, "size"));
+    assert_eq!(Some(true), fields.value::<bool>(&profile, "flag"));
+    let name = fields
+        .value::<&JavaInstance>(&profile, "name")
+        .and_then(|name| name.string_value(&profile));
+    assert_eq!(Some("derived".to_string()), name);
+    assert!(fields.value::<&JavaInstance>(&profile, "missing").is_none());
+
+    // the shadowed superclass field keeps its own type and default value
+    let local_fields = instance
+        .local_fields(&profile)
+        .map(|field| (field.name(), field.value(&profile)))
+        .collect::<Vec<_>>();
+    assert_eq!(4, local_fields.len());
+    assert!(matches!(local_fields[0], ("size", JavaLocalValue::Int(7))));
+    assert!(matches!(
+        local_fields[1],
+        ("flag", JavaLocalValue::Boolean(true))
+    ));
+    assert!(matches!(
+        local_fields[2],
+        ("name", JavaLocalValue::Object(_))
+    ));
+    assert!(matches!(local_fields[3], ("size", JavaLocalValue::Long(0))));
+}
+
+#[test]
+#[should_panic(expected = "Field com/example/Base.size is long, not int")]
+fn rejects_mistyped_field_values() {
+    let mut heap = HeapBuilder::new();
+    heap.class("com/example/Base", None, &[("size", FieldType::Long)]);
+    heap.instance("com/example/Base", &[("size", FieldValue::Int(7))]);
+}
+
+#[test]
+fn reads_string_layouts() {
+    let mut heap = HeapBuilder::new();
+    heap.class(
+        "java/lang/String",
+        None,
+        &[("value", FieldType::ObjectId), ("coder", FieldType::Byte)],
+    );
+    let latin1 = heap.string("café");
+    let utf16_bytes = heap.byte_array(
+        &"日本"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>(),
+    );
+    let utf16 = heap.instance(
+        "java/lang/String",
+        &[
+            ("value", FieldValue::ObjectId(Some(utf16_bytes))),
+            ("coder", FieldValue::Byte(1)),
+        ],
+    );
+    let chars = heap.char_array("pre-9");
+    let legacy = heap.instance(
+        "java/lang/String",
+        &[("value", FieldValue::ObjectId(Some(chars)))],
+    );
+    let dump = heap.build();
+    let profile = profile(&dump);
+
+    let value = |id: jvm_hprof::Id| {
+        profile
+            .get_instance(&id.into())
+            .and_then(|string| string.string_value(&profile))
+    };
+    assert_eq!(Some("café".to_string()), value(latin1));
+    assert_eq!(Some("日本".to_string()), value(utf16));
+    assert_eq!(Some("pre-9".to_string()), value(legacy));
+}
+
+#[test]
+fn computes_retained_sizes() {
+    let mut heap = HeapBuilder::new();
+    heap.class("com/example/Node", None, &[("next", FieldType::ObjectId)]);
+    let leaf = heap.instance("com/example/Node", &[]);
+    let shared = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(leaf)))],
+    );
+    let left = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(shared)))],
+    );
+    let right = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(shared)))],
+    );
+    let root = heap.object_array("com/example/Node", &[Some(left), Some(right)]);
+    heap.gc_root(root);
+    let dump = heap.build();
+    let profile = profile(&dump);
+
+    let shallow = |id: jvm_hprof::Id| profile.shallow_size(&id.into()).unwrap();
+    let retained = |id: jvm_hprof::Id| profile.retained_size(&id.into()).unwrap();
+    assert_eq!(shallow(leaf), retained(leaf));
+    assert_eq!(shallow(shared) + shallow(leaf), retained(shared));
+    // the shared node is reachable through both and retained by neither
+    assert_eq!(shallow(left), retained(left));
+    assert_eq!(
+        shallow(root) + shallow(left) + shallow(right) + shallow(shared) + shallow(leaf),
+        retained(root)
+    );
+}
+
+#[test]
+fn finds_reachable_objects() {
+    let mut heap = HeapBuilder::new();
+    heap.class("com/example/Node", None, &[("next", FieldType::ObjectId)]);
+    let holder = heap.class("com/example/Holder", None, &[]);
+    let missing = jvm_hprof::Id::from(0xdead0);
+    let tail = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(missing)))],
+    );
+    let head = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(tail)))],
+    );
+    let other = heap.instance("com/example/Node", &[]);
+    let array = heap.object_array("com/example/Node", &[Some(head), None, Some(other)]);
+    let to_class = heap.instance(
+        "com/example/Node",
+        &[("next", FieldValue::ObjectId(Some(holder)))],
+    );
+    heap.static_field(
+        "com/example/Holder",
+        "INSTANCE",
+        FieldValue::ObjectId(Some(head)),
+    );
+    let dump = heap.build();
+    let profile = profile(&dump);
+
+    let reachable = |roots: &[jvm_hprof::Id]| {
+        let roots = roots
+            .iter()
+            .map(|id| ObjectId::from(*id))
+            .collect::<Vec<_>>();
+        let mut reached = profile
+            .reachable_from(&roots)
+            .into_iter()
+            .collect::<Vec<_>>();
+        reached.sort();
+        reached
+    };
+    let ids = |ids: &[jvm_hprof::Id]| {
+        let mut ids = ids.iter().map(|id| ObjectId::from(*id)).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    // the dangling reference of the tail is skipped
+    assert_eq!(ids(&[head, tail]), reachable(&[head]));
+    assert_eq!(ids(&[array, head, tail, other]), reachable(&[array]));
+    // static fields are followed for classes among the roots
+    assert_eq!(ids(&[holder, head, tail]), reachable(&[holder]));
+    // and not for classes reached on the way
+    assert_eq!(ids(&[to_class, holder]), reachable(&[to_class]));
+    assert_eq!(ids(&[head, tail, other]), reachable(&[other, head]));
+}
@@ -1,54 +1,465 @@
+mod anonymize;
 mod elasticsearch;
 mod hprof;
+mod notify;
+mod redaction;
+mod replay;
+mod slice;
+mod verify;
+mod watch;
 
-use std::path::PathBuf;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::*;
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use elasticsearch::*;
+use redaction::Redactor;
+use serde::Serialize;
+
+/// Interval between checks of a dump still being written
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Output format, json and ndjson print one JSON object per record"
+    )]
+    format: OutputFormat,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        default_value_t = 900,
+        help = "Seconds to wait for a dump that is empty or not growing to be written, time it \
+        grows doesn't count"
+    )]
+    wait_timeout: u64,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        help = "Seconds an incomplete dump may stop growing before it's read as truncated"
+    )]
+    stall_timeout: u64,
     #[command(subcommand)]
     commands: Commands,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    /// A JSON array of records
+    Json,
+    /// One JSON record per line
+    Ndjson,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[clap(alias = "inflight_queries")]
     InflightQueries(InflightQueries),
+    #[clap(alias = "search_contexts")]
+    SearchContexts(SearchContexts),
+    #[clap(alias = "cluster_state")]
+    ClusterState(ClusterState),
+    Mappings(Mappings),
+    Caches(Caches),
+    Segments(Segments),
+    #[clap(alias = "thread_pools")]
+    ThreadPools(ThreadPools),
+    #[clap(alias = "transport_requests")]
+    TransportRequests(TransportRequests),
+    Replay(Replay),
+    Anonymize(Anonymize),
+    Slice(Slice),
+    Watch(Watch),
+    Verify(Verify),
+    Summary(Summary),
 }
 
 #[derive(Debug, Args)]
 #[command(about = "Read queries that was inflight in the time of crash\n\
-    At least one of --print or --save is required")]
+    At least one of --print, --save or --group is required")]
 struct InflightQueries {
     #[arg(
-        required_unless_present("save"),
+        required_unless_present_any(["save", "group"]),
         long,
         help = "Print queries to console"
     )]
     print: bool,
     #[arg(
-        required_unless_present("print"),
+        required_unless_present_any(["print", "group"]),
         long,
         help = "Save queries to files, one per query, directory named <hprof_filename>.prof will be created"
     )]
     save: bool,
+    #[arg(
+        long,
+        help = "Include requests already released but not yet garbage collected, they show what the node had just processed"
+    )]
+    include_released: bool,
+    #[arg(
+        long,
+        help = "Group queries by shape, literal values replaced, printing each shape once with its count"
+    )]
+    group: bool,
+    #[arg(
+        long,
+        value_name = "RULES",
+        help = "Redact customer data with rules from a YAML or JSON file before printing or saving"
+    )]
+    redact: Option<PathBuf>,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "List search contexts that were open in the time of crash\n\
+    Includes ongoing searches, scrolls and point in time readers, leaked scrolls show up here")]
+struct SearchContexts {
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Summarize the cluster state applied on the node\n\
+    Reports nodes, index and shard counts and the largest index mappings")]
+struct ClusterState {
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Number of largest index mappings to report"
+    )]
+    top: usize,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Dump settings and mappings of all indices in the cluster state\n\
+    Saved to <hprof_filename>.prof/indices/<index>/mapping.json"
+)]
+struct Mappings {
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Report contents of the request, query and fielddata caches\n\
+    Shows sizes per index and shard and the largest cache entries")]
+struct Caches {
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Number of largest entries to report for each cache"
+    )]
+    top: usize,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Report heap held by Lucene segments of each shard\n\
+    Covers terms index, norms, doc values and points, grouped by index and shard")]
+struct Segments {
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Report thread pools with their queued tasks\n\
+    Queued tasks are decoded to the transport action that submitted them")]
+struct ThreadPools {
+    #[arg(long, help = "Print reconstructed search source of queued searches")]
+    queries: bool,
+    #[arg(
+        long,
+        value_name = "RULES",
+        help = "Redact customer data with rules from a YAML or JSON file before printing or saving"
+    )]
+    redact: Option<PathBuf>,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "List pending transport requests per remote node and action\n\
+    Covers requests waiting for a response, requests executed for other nodes and \
+    messages being received. Sizes of the requests are not available, handler is the retained \
+    size of the response handler of requests waiting for a response")]
+struct TransportRequests {
+    #[arg(long, help = "List every request instead of grouping them")]
+    list: bool,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Replay queries saved by inflight-queries --save against a cluster\n\
+    Records status and latency of each request, saved to <hprof_filename>.prof/replay.json"
+)]
+struct Replay {
+    #[arg(
+        long,
+        help = "Base url of the cluster to send queries to, e.g. http://localhost:9200"
+    )]
+    target: String,
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Number of requests sent in parallel"
+    )]
+    concurrency: usize,
+    #[arg(long, help = "Maximum requests per second, unlimited by default")]
+    rate: Option<f64>,
+    #[arg(long, default_value_t = 1, help = "Number of times each query is sent")]
+    repeat: usize,
+    #[arg(long, default_value_t = 60, help = "Request timeout in seconds")]
+    timeout: u64,
+    #[arg(
+        long = "header",
+        value_name = "NAME:VALUE",
+        help = "Header added to every request, e.g. credentials, may be repeated"
+    )]
+    headers: Vec<String>,
+    #[arg(
+        long,
+        help = "Also send requests that may change the cluster, e.g. indexing, _bulk or deletes. \
+        Only searches and other reads are sent by default"
+    )]
+    allow_writes: bool,
+    #[arg(help = "Directory with saved queries, <hprof_filename>.prof")]
+    queries: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Write a copy of the dump without customer data\n\
+    Contents of byte[] and char[] arrays, and so of all strings, are replaced with placeholders \
+    of the same length. Sizes and references are kept")]
+struct Anonymize {
+    #[arg(long, help = "Keep names of classes, fields and methods")]
+    keep_names: bool,
+    #[arg(
+        long,
+        help = "Keep strings of Elasticsearch metadata: cluster state, index settings and mappings, nodes"
+    )]
+    keep_metadata: bool,
+    #[arg(
+        short,
+        long,
+        help = "Location of the anonymized dump, <hprof_filename>.anonymized.hprof by default"
+    )]
+    output: Option<PathBuf>,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Write the objects reachable from root objects as a dump of its own\n\
+    The small dump can be attached to bug reports and opened in Eclipse MAT"
+)]
+struct Slice {
+    #[arg(
+        long = "object",
+        value_name = "ID",
+        required_unless_present = "class",
+        help = "Id of a root object, e.g. 0x7F0A1C2D8, may be repeated"
+    )]
+    objects: Vec<hprof::ObjectId>,
+    #[arg(
+        long,
+        help = "Use all instances of the class and its subclasses as roots, \
+        e.g. org.elasticsearch.http.netty4.Netty4HttpRequest"
+    )]
+    class: Option<String>,
+    #[arg(
+        short,
+        long,
+        help = "Location of the slice, <hprof_filename>.slice.hprof by default"
+    )]
+    output: Option<PathBuf>,
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(
+    about = "Watch a directory for heap dumps and write a report next to each of them\n\
+    Reports are saved to <hprof_filename>.report.json once the dump is completely written, \
+    dumps without a report are picked up on start"
+)]
+struct Watch {
+    #[arg(
+        long = "extractor",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = watch::Extractor::ALL,
+        help = "Analysis included in reports, may be repeated or comma separated"
+    )]
+    extractors: Vec<watch::Extractor>,
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "Seconds between checks of a dump being written"
+    )]
+    interval: u64,
+    #[arg(
+        long,
+        value_name = "RULES",
+        help = "Redact customer data with rules from a YAML or JSON file before printing or saving"
+    )]
+    redact: Option<PathBuf>,
+    #[command(flatten)]
+    notify: Notify,
+    #[arg(help = "Directory heap dumps are written to, -XX:HeapDumpPath")]
+    dir: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Check whether a dump was written completely\n\
+    Reports record counts, the offset up to which records are valid and whether the dump ends \
+    with its end record")]
+struct Verify {
+    #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
+    hprof: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Summarize a dump for the incident channel\n\
+    Lists the node name and version, the inflight queries retaining the most memory and the \
+    classes taking the most heap, optionally sent as a Slack message")]
+struct Summary {
+    #[arg(
+        long,
+        value_name = "RULES",
+        help = "Redact customer data with rules from a YAML or JSON file before printing or sending"
+    )]
+    redact: Option<PathBuf>,
+    #[command(flatten)]
+    notify: Notify,
     #[arg(help = "Location of .hprof file from elasticsearch OOM dump")]
     hprof: PathBuf,
 }
 
+#[derive(Debug, Args)]
+struct Notify {
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "Post a dump summary to a Slack compatible webhook"
+    )]
+    notify_url: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write a dump summary to a file as a Slack message payload"
+    )]
+    notify_file: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Number of inflight queries and classes in summaries"
+    )]
+    top: usize,
+}
+
+impl Notify {
+    fn options(&self) -> notify::NotifyOptions {
+        notify::NotifyOptions {
+            url: self.notify_url.clone(),
+            file: self.notify_file.clone(),
+            top: self.top,
+        }
+    }
+}
+
 fn main() {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     let cli = Cli::parse();
+    let wait = verify::WaitOptions {
+        interval: WAIT_INTERVAL,
+        stall_timeout: Duration::from_secs(cli.stall_timeout),
+        timeout: Duration::from_secs(cli.wait_timeout),
+    };
 
     match &cli.commands {
         Commands::InflightQueries(inflight) => {
-            if let Err(err) = inflight_queries(inflight) {
+            if let Err(err) = inflight_queries(inflight, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::SearchContexts(contexts) => {
+            if let Err(err) = search_contexts(contexts, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::ClusterState(state) => {
+            if let Err(err) = cluster_state(state, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Mappings(mappings) => {
+            if let Err(err) = dump_mappings(mappings, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Caches(caches_opts) => {
+            if let Err(err) = caches(caches_opts, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Segments(segments_opts) => {
+            if let Err(err) = segments(segments_opts, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::ThreadPools(pools) => {
+            if let Err(err) = thread_pools(pools, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::TransportRequests(requests) => {
+            if let Err(err) = transport_requests(requests, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Replay(replay_opts) => {
+            if let Err(err) = replay_queries(replay_opts, cli.format) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Anonymize(anonymize_opts) => {
+            if let Err(err) = anonymize_hprof(anonymize_opts, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Slice(slice_opts) => {
+            if let Err(err) = slice_hprof(slice_opts, cli.format, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Watch(watch_opts) => {
+            if let Err(err) = watch_dumps(watch_opts, &wait) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Verify(verify_opts) => {
+            if let Err(err) = verify_hprof(verify_opts, cli.format) {
+                eprintln!("ERROR: {err:#}");
+            }
+        }
+        Commands::Summary(summary_opts) => {
+            if let Err(err) = summarize_hprof(summary_opts, cli.format, &wait) {
                 eprintln!("ERROR: {err:#}");
             }
         }
@@ -71,81 +482,932 @@ fn main() {
     // }
 }
 
-fn open_hprof_file(path: &PathBuf) -> Result<std::fs::File> {
-    let metadata = std::fs::metadata(path)
-        .with_context(|| format!("Failed to access file at {:?}", path))?;
-
-    if metadata.len() > 0 {
-        return std::fs::File::open(path)
-            .with_context(|| format!("Failed to open file at {:?}", path));
+/// Opens the dump once the JVM finished writing it, along with the number of bytes that can be read
+fn open_hprof_file(path: &PathBuf, wait: &verify::WaitOptions) -> Result<(std::fs::File, u64)> {
+    let verification = verify::wait_for_dump(path, wait)?;
+    if verification.last_valid_offset == 0 {
+        bail!(
+            "File at {:?} is not a heap dump: {}",
+            path,
+            verification.problem.as_deref().unwrap_or("no records")
+        );
     }
-    log::info!(
-            "File at {:?} is empty, waiting for content...",
-            path
+    if !verification.complete {
+        log::warn!(
+            "File at {:?} is incomplete ({}), reading the first {} bytes",
+            path,
+            verification
+                .problem
+                .as_deref()
+                .unwrap_or("no heap dump end record"),
+            verification.last_valid_offset
         );
+    }
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open file at {:?}", path))?;
+    Ok((file, verification.last_valid_offset))
+}
 
-    let start_time = Instant::now();
-    let mut last_size = 0;
+fn map_hprof_file(path: &PathBuf, wait: &verify::WaitOptions) -> Result<memmap::Mmap> {
+    let (file, len) = open_hprof_file(path, wait)?;
+    unsafe { memmap::MmapOptions::new().len(len as usize).map(&file) }
+        .context("Failed to mmap file")
+}
 
-    loop {
-        thread::sleep(Duration::from_secs(30));
+/// Directory named `<hprof_filename>.prof` next to the dump, created when missing
+fn results_dir(hprof: &Path) -> Result<PathBuf> {
+    let mut results_path = hprof.canonicalize().context("Failed to locate file")?;
+    let mut filename = results_path
+        .file_name()
+        .context("Failed to prepare results dir")?
+        .to_os_string();
+    results_path.pop();
+    filename.push(".prof");
+    results_path.push(filename);
+    if !results_path.exists() {
+        std::fs::create_dir(&results_path).context("Failed to create results directory")?;
+    }
+    Ok(results_path)
+}
 
-        let metadata = std::fs::metadata(path)
-            .with_context(|| format!("Failed to access file at {:?}", path))?;
-        let current_size = metadata.len();
-        let elapsed = start_time.elapsed();
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
 
-        if current_size == 0 && elapsed > Duration::from_secs(15 * 60) {
-            return Err(anyhow::anyhow!(
-                "Timeout after {:?} waiting for file to have content",
-                elapsed
-            ));
-        }
-        if current_size != last_size {
-            log::info!("File size changed: {} -> {} bytes", last_size, current_size);
-            last_size = current_size;
-            continue;
-        }
-        if current_size > 0{
-            return std::fs::File::open(path)
-                .with_context(|| format!("Failed to open file at {:?}", path));
+fn format_optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Prints records as a JSON array or one per line
+fn print_records<T: Serialize>(format: OutputFormat, records: &[T]) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        OutputFormat::Ndjson => {
+            for record in records {
+                println!("{}", serde_json::to_string(record)?);
+            }
         }
+        OutputFormat::Text => bail!("Text output is specific to each subcommand"),
     }
+    Ok(())
 }
 
-fn inflight_queries(opts: &InflightQueries) -> Result<()> {
-    let file = open_hprof_file(&opts.hprof)?;
-    let memmap = unsafe { memmap::MmapOptions::new().map(&file) }.context("Failed to mmap file")?;
+fn inflight_queries(
+    opts: &InflightQueries,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let redactor = opts.redact.as_deref().map(Redactor::load).transpose()?;
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
     log::info!("Loading hprof file...");
     let elastic = ElasticsearchMemory::new(&memmap);
     log::info!("Extracting inflight queries...");
     let results_path = if opts.save {
-        let mut results_path = opts.hprof.canonicalize().context("Failed to locate file")?;
-        let mut filename = results_path
-            .file_name()
-            .context("Failed to prepare results dir")?
-            .to_os_string();
-        results_path.pop();
-        filename.push(".prof");
-        results_path.push(filename);
-        if !results_path.exists() {
-            std::fs::create_dir(&results_path).context("Failed to create results directory")?;
-        }
-        Some(results_path)
+        Some(results_dir(&opts.hprof)?)
     } else {
         None
     };
-    for (i, query) in elastic.read_inflight_queries().iter().enumerate() {
-        eprintln!("query {i}");
-        if opts.print {
+    let mut queries = elastic.read_inflight_queries(opts.include_released);
+    if let Some(redactor) = &redactor {
+        for query in &mut queries {
+            redactor.redact_request(query);
+        }
+    }
+    if opts.print && format != OutputFormat::Text {
+        let records = queries
+            .iter()
+            .enumerate()
+            .map(|(i, query)| query.to_json(i))
+            .collect::<Vec<_>>();
+        print_records(format, &records)?;
+    }
+    if opts.group {
+        let groups = group_by_shape(&queries);
+        if format != OutputFormat::Text {
+            print_records(format, &groups)?;
+        } else {
+            for group in &groups {
+                println!(
+                    "# {} x {} ({})",
+                    group.count,
+                    group.fingerprint,
+                    group.endpoints.join(", ")
+                );
+                println!(
+                    "# queries: {}",
+                    group
+                        .ids
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                if let Some(risk) = group.risk {
+                    println!("# max risk: {risk}");
+                }
+                println!("{}", serde_json::to_string_pretty(&group.normalized)?);
+                println!();
+            }
+        }
+    }
+    for (i, query) in queries.iter().enumerate() {
+        if format == OutputFormat::Text {
+            eprintln!("query {i}");
+        }
+        if opts.print && format == OutputFormat::Text {
             println!("{query}");
             println!();
         }
         if let Some(results_path) = &results_path {
-            let mut query_filename = results_path.clone();
-            query_filename.push(format!("query_{i}.json"));
-            std::fs::write(query_filename, query).context("Failed to save query file")?;
+            if query.body_unavailable.is_none() {
+                let mut query_filename = results_path.clone();
+                query_filename.push(format!("query_{i}.json"));
+                std::fs::write(query_filename, &query.body).context("Failed to save query file")?;
+            }
+            let mut metadata_filename = results_path.clone();
+            metadata_filename.push(format!("query_{i}.meta.json"));
+            std::fs::write(
+                metadata_filename,
+                serde_json::to_string_pretty(&query.metadata())?,
+            )
+            .context("Failed to save query metadata file")?;
+        }
+    }
+    Ok(())
+}
+
+fn search_contexts(
+    opts: &SearchContexts,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting search contexts...");
+    let mut contexts = elastic.read_search_contexts();
+    contexts.sort_by_key(|c| std::cmp::Reverse(c.retained_size));
+    if format != OutputFormat::Text {
+        return print_records(format, &contexts);
+    }
+
+    println!(
+        "{:<32} {:<8} {:<40} {:>12} {:>16} {:>12}",
+        "id", "type", "shard", "keep alive", "last accessed", "retained"
+    );
+    for context in &contexts {
+        println!(
+            "{:<32} {:<8} {:<40} {:>12} {:>16} {:>12}",
+            match (&context.session_id, context.id) {
+                (Some(session_id), Some(id)) => format!("[{session_id}][{id}]"),
+                (_, id) => format_optional(id),
+            },
+            context.kind,
+            format_optional(context.shard.as_ref()),
+            format_optional(context.keep_alive_ms.map(|ms| format!("{}s", ms / 1000))),
+            format_optional(context.last_accessed_ms),
+            format_optional(context.retained_size.map(format_size)),
+        );
+    }
+    println!();
+    for kind in [
+        SearchContextKind::Search,
+        SearchContextKind::Scroll,
+        SearchContextKind::PointInTime,
+    ] {
+        let matching = contexts.iter().filter(|c| c.kind == kind);
+        println!(
+            "{kind}: {} contexts, {} retained",
+            matching.clone().count(),
+            format_size(matching.filter_map(|c| c.retained_size).sum())
+        );
+    }
+    Ok(())
+}
+
+fn cluster_state(
+    opts: &ClusterState,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting cluster state...");
+    let state = elastic
+        .read_cluster_state()
+        .context("Cluster state not found in heap")?;
+    if format != OutputFormat::Text {
+        return print_records(format, &[state]);
+    }
+
+    let node_name = |id: &Option<String>| {
+        state
+            .nodes
+            .iter()
+            .find(|node| node.id.is_some() && &node.id == id)
+            .and_then(|node| node.name.clone())
+            .or_else(|| id.clone())
+    };
+    println!(
+        "cluster name:  {}",
+        format_optional(state.cluster_name.as_ref())
+    );
+    println!(
+        "state version: {} ({})",
+        format_optional(state.version),
+        format_optional(state.state_uuid.as_ref())
+    );
+    println!(
+        "master node:   {}",
+        format_optional(node_name(&state.master_node_id))
+    );
+    println!(
+        "local node:    {}",
+        format_optional(node_name(&state.local_node_id))
+    );
+    println!("nodes:         {}", state.nodes.len());
+    for node in &state.nodes {
+        println!(
+            "  {:<24} {:<32} {:<24} {}",
+            format_optional(node.id.as_ref()),
+            format_optional(node.name.as_ref()),
+            format_optional(node.address.as_ref()),
+            node.roles.join(",")
+        );
+    }
+    println!("indices:       {}", state.index_count);
+    println!(
+        "shards:        {} ({} primaries, {} replicas)",
+        state.shards.primaries + state.shards.replicas,
+        state.shards.primaries,
+        state.shards.replicas
+    );
+    for (shard_state, count) in &state.shards.states {
+        println!("  {shard_state:<24} {count}");
+    }
+    println!("largest mappings:");
+    for mapping in state.mappings.iter().take(opts.top) {
+        println!(
+            "  {:>12} {}",
+            format_size(mapping.serialized_size as u64),
+            mapping.index
+        );
+    }
+    Ok(())
+}
+
+fn dump_mappings(opts: &Mappings, format: OutputFormat, wait: &verify::WaitOptions) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting index metadata...");
+    let indices = elastic.read_index_metadata();
+    if indices.is_empty() {
+        bail!("No index metadata found in heap");
+    }
+    let mut results_path = results_dir(&opts.hprof)?;
+    results_path.push("indices");
+
+    if format == OutputFormat::Text {
+        println!("{:<48} {:>8} {:>8}", "index", "fields", "settings");
+    } else {
+        let records = indices
+            .iter()
+            .map(|index| {
+                serde_json::json!({
+                    "index": index.index,
+                    "fields": index.field_count(),
+                    "settings": index.settings,
+                    "mappings": index.mappings,
+                })
+            })
+            .collect::<Vec<_>>();
+        print_records(format, &records)?;
+    }
+    for index in &indices {
+        if format == OutputFormat::Text {
+            println!(
+                "{:<48} {:>8} {:>8}",
+                index.index,
+                index.field_count(),
+                index.settings.len()
+            );
+        }
+        let mut index_path = results_path.join(&index.index);
+        std::fs::create_dir_all(&index_path).context("Failed to create index directory")?;
+        index_path.push("mapping.json");
+        let json = serde_json::to_string_pretty(&index.to_json())?;
+        std::fs::write(index_path, json).context("Failed to save mapping file")?;
+    }
+    log::info!("Saved {} indices to {:?}", indices.len(), results_path);
+    Ok(())
+}
+
+fn caches(opts: &Caches, format: OutputFormat, wait: &verify::WaitOptions) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting caches...");
+    let caches = elastic.read_caches();
+    if format != OutputFormat::Text {
+        return print_records(format, &caches);
+    }
+    for cache in caches {
+        println!(
+            "{} cache: {} entries, {} retained",
+            cache.kind,
+            cache.entries.len(),
+            format_size(cache.total_size())
+        );
+        let mut indices = cache.size_by_index().into_iter().collect::<Vec<_>>();
+        indices.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        println!("  by index:");
+        for (index, size) in indices {
+            println!("    {:>12} {}", format_size(size), format_optional(index));
+        }
+        let mut shards = cache.size_by_shard().into_iter().collect::<Vec<_>>();
+        shards.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        println!("  by shard:");
+        for (shard, size) in shards {
+            println!("    {:>12} {}", format_size(size), format_optional(shard));
+        }
+        println!("  largest entries:");
+        for entry in cache.entries.iter().take(opts.top) {
+            println!(
+                "    {:>12} {:<40} {}",
+                format_size(entry.size),
+                format_optional(entry.shard.as_ref()),
+                entry.description
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn segments(opts: &Segments, format: OutputFormat, wait: &verify::WaitOptions) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting segment memory...");
+    let mut shards = elastic.read_segment_memory();
+    shards.sort_by(|a, b| a.shard.cmp(&b.shard));
+    if format != OutputFormat::Text {
+        return print_records(format, &shards);
+    }
+
+    let print_row = |name: &str, memory: &SegmentMemory| {
+        println!(
+            "{:<40} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12}",
+            name,
+            memory.segments,
+            format_size(memory.terms),
+            format_size(memory.norms),
+            format_size(memory.doc_values),
+            format_size(memory.points),
+            format_size(memory.total()),
+        );
+    };
+    println!(
+        "{:<40} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "shard", "segments", "terms", "norms", "doc values", "points", "total"
+    );
+    let mut total = SegmentMemory::default();
+    let mut index_total: Option<SegmentMemory> = None;
+    for shard in &shards {
+        let index = shard.shard.as_ref().map(|s| &s.index);
+        if let Some(memory) = &index_total {
+            if memory.shard.as_ref().map(|s| &s.index) != index {
+                print_row(
+                    &format!(
+                        "{} total",
+                        format_optional(memory.shard.as_ref().map(|s| &s.index))
+                    ),
+                    memory,
+                );
+                index_total = None;
+            }
+        }
+        print_row(&format_optional(shard.shard.as_ref()), shard);
+        let memory = index_total.get_or_insert_with(|| SegmentMemory {
+            shard: shard.shard.clone(),
+            ..Default::default()
+        });
+        for memory in [memory, &mut total] {
+            memory.segments += shard.segments;
+            memory.terms += shard.terms;
+            memory.norms += shard.norms;
+            memory.doc_values += shard.doc_values;
+            memory.points += shard.points;
+        }
+    }
+    if let Some(memory) = &index_total {
+        print_row(
+            &format!(
+                "{} total",
+                format_optional(memory.shard.as_ref().map(|s| &s.index))
+            ),
+            memory,
+        );
+    }
+    print_row("total", &total);
+    Ok(())
+}
+
+fn thread_pools(
+    opts: &ThreadPools,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let redactor = opts.redact.as_deref().map(Redactor::load).transpose()?;
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting thread pools...");
+    let mut pools = elastic.read_thread_pools();
+    if let Some(redactor) = &redactor {
+        for task in pools.iter_mut().flat_map(|pool| pool.queued.iter_mut()) {
+            if let Some(query) = &mut task.query {
+                redactor.redact_value(query);
+            }
+        }
+    }
+    if format != OutputFormat::Text {
+        return print_records(format, &pools);
+    }
+
+    println!(
+        "{:<32} {:>8} {:>8} {:>8} {:>10} {:>12}",
+        "pool", "threads", "active", "queued", "capacity", "retained"
+    );
+    for pool in &pools {
+        println!(
+            "{:<32} {:>8} {:>8} {:>8} {:>10} {:>12}",
+            pool.name,
+            pool.threads,
+            pool.active,
+            pool.queued.len(),
+            format_optional(pool.queue_capacity),
+            format_size(pool.queued.iter().filter_map(|t| t.retained_size).sum()),
+        );
+    }
+    for pool in pools.iter().filter(|pool| !pool.queued.is_empty()) {
+        println!();
+        println!("{} queue:", pool.name);
+        let mut actions = std::collections::BTreeMap::new();
+        for task in &pool.queued {
+            *actions
+                .entry(task.action.as_deref().unwrap_or(task.task_class.as_str()))
+                .or_insert(0) += 1;
+        }
+        let mut actions = actions.into_iter().collect::<Vec<_>>();
+        actions.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        for (action, count) in actions {
+            println!("  {count:>8} {action}");
+        }
+        if opts.queries {
+            for (i, task) in pool.queued.iter().enumerate() {
+                if let Some(query) = &task.query {
+                    println!("task {i}: {}", serde_json::to_string_pretty(query)?);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn transport_requests(
+    opts: &TransportRequests,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    log::info!("Extracting transport requests...");
+    let requests = elastic.read_pending_transport_requests();
+
+    if opts.list && format != OutputFormat::Text {
+        return print_records(format, &requests);
+    }
+    if opts.list {
+        println!(
+            "{:<10} {:>12} {:>12} {:<24} {:<56} {:>12} {:>12}",
+            "direction", "id", "task", "node", "action", "handler", "message"
+        );
+        for request in &requests {
+            println!(
+                "{:<10} {:>12} {:>12} {:<24} {:<56} {:>12} {:>12}",
+                request.direction,
+                format_optional(request.request_id),
+                format_optional(request.task_id),
+                format_optional(request.node.as_ref()),
+                format_optional(request.action.as_ref()),
+                format_optional(request.handler_retained_size.map(format_size)),
+                format_optional(request.message_size.map(format_size)),
+            );
+        }
+        return Ok(());
+    }
+
+    let mut groups = std::collections::BTreeMap::new();
+    for request in &requests {
+        let (count, handler_retained_size, message_size) = groups
+            .entry((
+                request.direction,
+                request.node.as_deref(),
+                request.action.as_deref(),
+            ))
+            .or_insert((0, None, None));
+        *count += 1;
+        add_size(handler_retained_size, request.handler_retained_size);
+        add_size(message_size, request.message_size);
+    }
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups
+        .sort_by_key(|((direction, _, _), (count, _, _))| (*direction, std::cmp::Reverse(*count)));
+    if format != OutputFormat::Text {
+        let records = groups
+            .iter()
+            .map(
+                |((direction, node, action), (count, handler_retained_size, message_size))| {
+                    serde_json::json!({
+                        "direction": direction,
+                        "node": node,
+                        "action": action,
+                        "count": count,
+                        "handler_retained_size": handler_retained_size,
+                        "message_size": message_size,
+                    })
+                },
+            )
+            .collect::<Vec<_>>();
+        return print_records(format, &records);
+    }
+
+    println!(
+        "{:<10} {:<24} {:<56} {:>8} {:>12} {:>12}",
+        "direction", "node", "action", "count", "handler", "message"
+    );
+    for ((direction, node, action), (count, handler_retained_size, message_size)) in groups {
+        println!(
+            "{:<10} {:<24} {:<56} {:>8} {:>12} {:>12}",
+            direction,
+            format_optional(node),
+            format_optional(action),
+            count,
+            format_optional(handler_retained_size.map(format_size)),
+            format_optional(message_size.map(format_size)),
+        );
+    }
+    println!();
+    println!("{} pending transport requests", requests.len());
+    Ok(())
+}
+
+/// Adds a size to a total, the total stays `None` until a size is known
+fn add_size(total: &mut Option<u64>, size: Option<u64>) {
+    if let Some(size) = size {
+        *total = Some(total.unwrap_or(0) + size);
+    }
+}
+
+fn replay_queries(opts: &Replay, format: OutputFormat) -> Result<()> {
+    let headers = opts
+        .headers
+        .iter()
+        .map(|header| match header.split_once(':') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(anyhow!("Invalid header {header:?}, expected NAME:VALUE")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let requests = replay::load_requests(&opts.queries, opts.allow_writes)?;
+    if requests.is_empty() {
+        bail!(
+            "No saved queries to replay in {:?}, writes are skipped without --allow-writes",
+            opts.queries
+        );
+    }
+    log::info!(
+        "Replaying {} queries against {}...",
+        requests.len(),
+        opts.target
+    );
+    let results = replay::replay(
+        &requests,
+        &replay::ReplayOptions {
+            target: opts.target.clone(),
+            concurrency: opts.concurrency,
+            rate: opts.rate,
+            repeat: opts.repeat,
+            timeout: Duration::from_secs(opts.timeout),
+            headers,
+        },
+    )?;
+    std::fs::write(
+        replay::results_file(&opts.queries),
+        serde_json::to_string_pretty(&results)?,
+    )
+    .context("Failed to save replay results")?;
+
+    if format != OutputFormat::Text {
+        return print_records(format, &results);
+    }
+    for result in &results {
+        match (&result.status, &result.error) {
+            (_, Some(error)) => println!(
+                "{} {} {} failed after {} ms: {error}",
+                result.name, result.method, result.uri, result.latency_ms
+            ),
+            (status, None) => println!(
+                "{} {} {} {} in {} ms, {}",
+                result.name,
+                result.method,
+                result.uri,
+                format_optional(*status),
+                result.latency_ms,
+                format_size(result.response_bytes)
+            ),
+        }
+    }
+    let failed = results
+        .iter()
+        .filter(|result| result.status.is_none_or(|status| status >= 400))
+        .count();
+    println!();
+    println!("Requests: {}, failed: {failed}", results.len());
+    println!(
+        "Latency ms: p50 {}, p90 {}, p99 {}, max {}",
+        format_optional(replay::percentile(&results, 50.0)),
+        format_optional(replay::percentile(&results, 90.0)),
+        format_optional(replay::percentile(&results, 99.0)),
+        format_optional(replay::percentile(&results, 100.0))
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AnonymizeSummary {
+    output: PathBuf,
+    records: u64,
+    arrays: u64,
+    array_bytes: u64,
+    kept_arrays: u64,
+    names: u64,
+}
+
+fn anonymize_hprof(
+    opts: &Anonymize,
+    format: OutputFormat,
+    wait: &verify::WaitOptions,
+) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    let keep_arrays = if opts.keep_metadata {
+        log::info!("Loading hprof file...");
+        let elastic = ElasticsearchMemory::new(&memmap);
+        log::info!("Collecting metadata strings...");
+        elastic.read_metadata_arrays()
+    } else {
+        Default::default()
+    };
+    let output = match &opts.output {
+        Some(output) => output.clone(),
+        None => {
+            let mut output = opts.hprof.clone().into_os_string();
+            output.push(".anonymized.hprof");
+            PathBuf::from(output)
+        }
+    };
+    if output.exists() && output.canonicalize()? == opts.hprof.canonicalize()? {
+        bail!("Output {output:?} would overwrite the dump");
+    }
+    let file = std::fs::File::create(&output)
+        .with_context(|| format!("Failed to create file at {output:?}"))?;
+    log::info!("Writing anonymized dump to {output:?}...");
+    let stats = anonymize::anonymize(
+        &memmap,
+        std::io::BufWriter::new(file),
+        &anonymize::AnonymizeOptions {
+            keep_names: opts.keep_names,
+            keep_arrays,
+        },
+    )?;
+
+    let summary = AnonymizeSummary {
+        output,
+        records: stats.records,
+        arrays: stats.arrays,
+        array_bytes: stats.array_bytes,
+        kept_arrays: stats.kept_arrays,
+        names: stats.names,
+    };
+    if format != OutputFormat::Text {
+        return print_records(format, &[summary]);
+    }
+    println!("written:      {:?}", summary.output);
+    println!("records:      {}", summary.records);
+    println!(
+        "arrays:       {} replaced ({}), {} kept",
+        summary.arrays,
+        format_size(summary.array_bytes),
+        summary.kept_arrays
+    );
+    println!("names:        {} replaced", summary.names);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SliceSummary {
+    output: PathBuf,
+    roots: usize,
+    objects: u64,
+    classes: u64,
+    names: u64,
+    bytes: u64,
+}
+
+fn slice_hprof(opts: &Slice, format: OutputFormat, wait: &verify::WaitOptions) -> Result<()> {
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let mut profile = hprof::JavaProfile::new(&memmap);
+    profile.process();
+
+    let mut roots = opts.objects.clone();
+    for root in &roots {
+        if profile.get_object(root).is_none() && profile.get_class_by_id(&(*root).into()).is_none()
+        {
+            bail!("Object {root} not found in the dump");
+        }
+    }
+    if let Some(class) = &opts.class {
+        let class = class.replace('.', "/");
+        if profile.get_class_by_name(&class).is_none() {
+            bail!("Class {class} not found in the dump");
+        }
+        roots.extend(
+            profile
+                .get_instances_by_class_name(&class)
+                .iter()
+                .map(|instance| instance.id()),
+        );
+    }
+    roots.sort_unstable();
+    roots.dedup();
+    if roots.is_empty() {
+        bail!("No roots to slice from");
+    }
+
+    let output = match &opts.output {
+        Some(output) => output.clone(),
+        None => {
+            let mut output = opts.hprof.clone().into_os_string();
+            output.push(".slice.hprof");
+            PathBuf::from(output)
         }
+    };
+    if output.exists() && output.canonicalize()? == opts.hprof.canonicalize()? {
+        bail!("Output {output:?} would overwrite the dump");
+    }
+    log::info!("Collecting objects reachable from {} roots...", roots.len());
+    let selection = slice::Selection::new(&profile, roots.clone());
+    let file = std::fs::File::create(&output)
+        .with_context(|| format!("Failed to create file at {output:?}"))?;
+    log::info!("Writing {} objects to {output:?}...", selection.objects());
+    let stats = slice::slice(&memmap, std::io::BufWriter::new(file), &selection)?;
+
+    let summary = SliceSummary {
+        bytes: std::fs::metadata(&output)?.len(),
+        output,
+        roots: roots.len(),
+        objects: stats.objects,
+        classes: stats.classes,
+        names: stats.names,
+    };
+    if format != OutputFormat::Text {
+        return print_records(format, &[summary]);
     }
+    println!(
+        "written:      {:?} ({})",
+        summary.output,
+        format_size(summary.bytes)
+    );
+    println!("roots:        {}", summary.roots);
+    println!("objects:      {}", summary.objects);
+    println!("classes:      {}", summary.classes);
+    println!("names:        {}", summary.names);
     Ok(())
 }
+
+fn watch_dumps(opts: &Watch, wait: &verify::WaitOptions) -> Result<()> {
+    let redactor = opts.redact.as_deref().map(Redactor::load).transpose()?;
+    if !opts.dir.is_dir() {
+        bail!("{:?} is not a directory", opts.dir);
+    }
+    watch::watch(
+        &opts.dir,
+        &watch::WatchOptions {
+            extractors: opts.extractors.clone(),
+            wait: verify::WaitOptions {
+                interval: Duration::from_secs(opts.interval),
+                ..*wait
+            },
+            redactor,
+            notify: opts.notify.options(),
+        },
+    )
+}
+
+fn verify_hprof(opts: &Verify, format: OutputFormat) -> Result<()> {
+    let verification = verify::verify_file(&opts.hprof)?;
+    if format != OutputFormat::Text {
+        return print_records(format, &[verification]);
+    }
+    println!(
+        "size:              {} ({} bytes)",
+        format_size(verification.size),
+        verification.size
+    );
+    println!(
+        "id size:           {}",
+        format_optional(verification.id_size)
+    );
+    println!("records:           {}", verification.records);
+    for (tag, count) in &verification.record_counts {
+        println!("  {tag:<24} {count}");
+    }
+    println!("last valid offset: {}", verification.last_valid_offset);
+    println!(
+        "heap dump end:     {}",
+        if verification.heap_dump_end {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    println!(
+        "complete:          {}",
+        if verification.complete { "yes" } else { "no" }
+    );
+    if let Some(problem) = &verification.problem {
+        println!("problem:           {problem}");
+    }
+    Ok(())
+}
+
+fn summarize_hprof(opts: &Summary, format: OutputFormat, wait: &verify::WaitOptions) -> Result<()> {
+    let redactor = opts.redact.as_deref().map(Redactor::load).transpose()?;
+    let memmap = map_hprof_file(&opts.hprof, wait)?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    let notify_opts = opts.notify.options();
+    let summary = notify::summarize(
+        &opts.hprof,
+        memmap.len() as u64,
+        &elastic,
+        redactor.as_ref(),
+        notify_opts.top,
+    );
+    if format != OutputFormat::Text {
+        print_records(format, &[&summary])?;
+    } else {
+        println!("node:    {}", format_optional(summary.node.name.as_ref()));
+        println!(
+            "version: {}",
+            format_optional(summary.node.version.as_ref())
+        );
+        println!("inflight queries by retained size:");
+        for query in &summary.queries {
+            println!(
+                "  {:>10}  {} {}",
+                format_optional(query.retained_size.map(format_size)),
+                query.method.as_deref().unwrap_or("-"),
+                query.uri.as_deref().unwrap_or("-")
+            );
+        }
+        println!("classes by shallow size:");
+        println!("  {:>10}  {:>10}  class", "size", "objects");
+        for class in &summary.classes {
+            println!(
+                "  {:>10}  {:>10}  {}",
+                format_size(class.shallow_size),
+                class.instances,
+                class.name
+            );
+        }
+    }
+    notify::notify(&summary, &notify_opts)
+}
@@ -0,0 +1,464 @@
+//! Sends a short summary of a dump to the incident channel.
+//!
+//! The summary names the node and its version and lists the inflight queries and classes holding
+//! the most memory. It's posted to a webhook or written to a file as a Slack message payload,
+//! which Slack incoming webhooks and most chat tools with a Slack compatible endpoint accept.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::elasticsearch::{ElasticsearchMemory, NodeInfo};
+use crate::format_size;
+use crate::hprof::ClassUsage;
+use crate::redaction::Redactor;
+
+/// Characters of a query body included in the summary
+const BODY_PREVIEW_LENGTH: usize = 300;
+/// Slack rejects messages with longer header and section texts
+const HEADER_LENGTH: usize = 150;
+const SECTION_LENGTH: usize = 3000;
+/// Characters of escaped values in sections, so that a section stays within `SECTION_LENGTH`
+/// whatever the values, e.g. a node name or a class name
+const NAME_LENGTH: usize = 200;
+const URI_LENGTH: usize = 1000;
+const BODY_LENGTH: usize = 1500;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub hprof: PathBuf,
+    pub hprof_size: u64,
+    pub node: NodeInfo,
+    /// Inflight queries retaining the most memory, largest first
+    pub queries: Vec<QuerySummary>,
+    /// Classes taking the most heap, largest first
+    pub classes: Vec<ClassUsage>,
+}
+
+#[derive(Serialize)]
+pub struct QuerySummary {
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub retained_size: Option<u64>,
+    /// Start of the body, up to `BODY_PREVIEW_LENGTH` characters
+    pub body: String,
+}
+
+/// Where summaries are sent, nowhere when both are `None`
+pub struct NotifyOptions {
+    /// Webhook the payload is posted to
+    pub url: Option<String>,
+    /// File the payload is written to
+    pub file: Option<PathBuf>,
+    /// Number of queries and classes listed
+    pub top: usize,
+}
+
+impl NotifyOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.url.is_some() || self.file.is_some()
+    }
+}
+
+/// Summarizes the dump, query bodies are redacted with `redactor` when given
+pub fn summarize(
+    hprof: &Path,
+    hprof_size: u64,
+    elastic: &ElasticsearchMemory,
+    redactor: Option<&Redactor>,
+    top: usize,
+) -> Summary {
+    log::info!("Summarizing {hprof:?}...");
+    let mut queries = elastic
+        .read_inflight_queries(false)
+        .into_iter()
+        .map(|query| (elastic.retained_size(&query.object_id), query))
+        .collect::<Vec<_>>();
+    queries.sort_by_key(|(retained_size, _)| std::cmp::Reverse(*retained_size));
+    let queries = queries
+        .into_iter()
+        .take(top)
+        .map(|(retained_size, mut query)| {
+            if let Some(redactor) = redactor {
+                redactor.redact_request(&mut query);
+            }
+            QuerySummary {
+                method: query.method,
+                uri: query.uri,
+                retained_size,
+                body: preview(&query.body),
+            }
+        })
+        .collect();
+    let mut classes = elastic.read_class_histogram();
+    classes.truncate(top);
+    Summary {
+        hprof: hprof.to_path_buf(),
+        hprof_size,
+        node: elastic.read_node_info(),
+        queries,
+        classes,
+    }
+}
+
+fn preview(body: &str) -> String {
+    match body.char_indices().nth(BODY_PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+impl Summary {
+    /// Message in the Slack incoming webhook format, `text` is shown where blocks are not
+    /// supported, e.g. in notifications
+    pub fn slack_payload(&self) -> Value {
+        let node = self.node.name.as_deref().unwrap_or("unknown node");
+        let version = self.node.version.as_deref().unwrap_or("unknown version");
+        let text = truncate(
+            &format!("Heap dump of {node} ({version}) analyzed"),
+            HEADER_LENGTH,
+        );
+        let mut blocks = vec![
+            json!({
+                "type": "header",
+                "text": {"type": "plain_text", "text": text},
+            }),
+            section(format!(
+                "*Node:* {}\n*Version:* {}\n*Dump:* `{}` ({})",
+                escape(node, NAME_LENGTH),
+                escape(version, NAME_LENGTH),
+                escape(&self.hprof.display().to_string(), URI_LENGTH),
+                format_size(self.hprof_size)
+            )),
+        ];
+
+        if self.queries.is_empty() {
+            blocks.push(section("*No inflight queries*".to_string()));
+        } else {
+            blocks.push(section("*Inflight queries by retained size*".to_string()));
+        }
+        // a section per query, each within the section length
+        for query in &self.queries {
+            let mut text = format!(
+                "`{} {}` {}",
+                escape(query.method.as_deref().unwrap_or("-"), NAME_LENGTH),
+                escape(query.uri.as_deref().unwrap_or("-"), URI_LENGTH),
+                query
+                    .retained_size
+                    .map(format_size)
+                    .unwrap_or_else(|| "-".to_string())
+            );
+            if !query.body.is_empty() {
+                text.push_str(&format!("\n```{}```", escape(&query.body, BODY_LENGTH)));
+            }
+            blocks.push(section(text));
+        }
+
+        let mut classes = format!(
+            "*Classes by shallow size*\n```{:>10} {:>10} class",
+            "size", "objects"
+        );
+        for class in &self.classes {
+            let line = format!(
+                "\n{:>10} {:>10} {}",
+                format_size(class.shallow_size),
+                class.instances,
+                escape(&class.name, NAME_LENGTH)
+            );
+            // room for the closing backticks
+            if length(&classes) + length(&line) + 3 > SECTION_LENGTH {
+                break;
+            }
+            classes.push_str(&line);
+        }
+        classes.push_str("```");
+        blocks.push(section(classes));
+        json!({ "text": text, "blocks": blocks })
+    }
+}
+
+fn section(text: String) -> Value {
+    json!({
+        "type": "section",
+        "text": {"type": "mrkdwn", "text": text},
+    })
+}
+
+/// Length of a text as Slack counts it
+fn length(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Cuts a text to at most `max` characters, ending with `...` when it's cut
+fn truncate(text: &str, max: usize) -> String {
+    if length(text) <= max {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max - 3).collect::<String>();
+    truncated.push_str("...");
+    truncated
+}
+
+/// Escapes the characters Slack reads as markup in message text, the escaped text is cut to at
+/// most `max` characters without splitting an escape sequence
+fn escape(text: &str, max: usize) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    if length(&escaped) <= max {
+        return escaped;
+    }
+    let mut truncated = String::new();
+    let mut truncated_length = 0;
+    let mut buffer = [0; 4];
+    for c in text.chars() {
+        let escaped_char = match c {
+            '&' => "&amp;",
+            '<' => "&lt;",
+            '>' => "&gt;",
+            c => c.encode_utf8(&mut buffer),
+        };
+        let escaped_length = length(escaped_char);
+        if truncated_length + escaped_length + 3 > max {
+            break;
+        }
+        truncated.push_str(escaped_char);
+        truncated_length += escaped_length;
+    }
+    truncated.push_str("...");
+    truncated
+}
+
+/// Writes the summary payload to the file and posts it to the webhook in `opts`
+pub fn notify(summary: &Summary, opts: &NotifyOptions) -> Result<()> {
+    let payload = serde_json::to_string_pretty(&summary.slack_payload())?;
+    if let Some(file) = &opts.file {
+        std::fs::write(file, &payload)
+            .with_context(|| format!("Failed to write summary to {file:?}"))?;
+        log::info!("Summary written to {file:?}");
+    }
+    if let Some(url) = &opts.url {
+        let agent = ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).build();
+        // webhook urls hold a secret, errors of ureq include the url
+        let response = agent
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&payload);
+        match response {
+            Ok(_) => log::info!("Summary posted to webhook"),
+            Err(ureq::Error::Status(status, _)) => {
+                bail!("Failed to post summary, webhook returned {status}")
+            }
+            Err(ureq::Error::Transport(transport)) => bail!(
+                "Failed to post summary: {}: {}",
+                transport.kind(),
+                transport.message().unwrap_or("no details")
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    /// Answers a single request with `status` and reports its request line and body
+    fn mock_webhook(status: u16) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {status} Mock\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+            )
+            .unwrap();
+            sender
+                .send((
+                    request_line.trim().to_string(),
+                    String::from_utf8(body).unwrap(),
+                ))
+                .unwrap();
+        });
+        (address, receiver)
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            hprof: PathBuf::from("/dumps/java_pid1.hprof"),
+            hprof_size: 2048,
+            node: NodeInfo {
+                name: Some("node-1".to_string()),
+                id: Some("abc".to_string()),
+                version: Some("7.17.9".to_string()),
+            },
+            queries: vec![
+                QuerySummary {
+                    method: Some("POST".to_string()),
+                    uri: Some("/logs/_search".to_string()),
+                    retained_size: Some(1024),
+                    body: r#"{"query":{"match":{"message":"a<b"}}}"#.to_string(),
+                },
+                QuerySummary {
+                    method: None,
+                    uri: None,
+                    retained_size: None,
+                    body: String::new(),
+                },
+            ],
+            classes: vec![ClassUsage {
+                name: "byte[]".to_string(),
+                instances: 3,
+                shallow_size: 1024,
+            }],
+        }
+    }
+
+    fn options(url: Option<String>, file: Option<PathBuf>) -> NotifyOptions {
+        NotifyOptions { url, file, top: 5 }
+    }
+
+    fn section_texts(payload: &Value) -> Vec<&str> {
+        payload["blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|block| block["type"] == "section")
+            .map(|block| block["text"]["text"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn posts_summary_to_webhook() {
+        let (address, received) = mock_webhook(200);
+        let url = format!("{address}/services/T000/B000/secret");
+        notify(&summary(), &options(Some(url), None)).unwrap();
+        let (request_line, body) = received.recv().unwrap();
+        assert_eq!(request_line, "POST /services/T000/B000/secret HTTP/1.1");
+
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["text"], "Heap dump of node-1 (7.17.9) analyzed");
+        let blocks = payload["blocks"].as_array().unwrap();
+        assert_eq!(blocks[0]["type"], "header");
+        assert_eq!(blocks[0]["text"]["type"], "plain_text");
+        assert_eq!(blocks[0]["text"]["text"], payload["text"]);
+        assert!(blocks[1..]
+            .iter()
+            .all(|block| block["type"] == "section" && block["text"]["type"] == "mrkdwn"));
+        assert_eq!(
+            section_texts(&payload),
+            [
+                "*Node:* node-1\n*Version:* 7.17.9\n*Dump:* `/dumps/java_pid1.hprof` (2.0 KiB)",
+                "*Inflight queries by retained size*",
+                "`POST /logs/_search` 1.0 KiB\n```{\"query\":{\"match\":{\"message\":\"a&lt;b\"}}}```",
+                "`- -` -",
+                "*Classes by shallow size*\n```      size    objects class\n   1.0 KiB          3 byte[]```",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_summary_to_file() {
+        let file = std::env::temp_dir().join(format!("notify-{}.json", std::process::id()));
+        let mut summary = summary();
+        summary.queries.clear();
+        notify(&summary, &options(None, Some(file.clone()))).unwrap();
+        let written = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let payload: Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(payload, summary.slack_payload());
+        assert_eq!(section_texts(&payload)[1], "*No inflight queries*");
+    }
+
+    #[test]
+    fn reports_webhook_error_status() {
+        let (address, received) = mock_webhook(404);
+        let url = format!("{address}/services/T000/B000/secret");
+        let err = notify(&summary(), &options(Some(url), None)).unwrap_err();
+        received.recv().unwrap();
+        let message = format!("{err:#}");
+        assert_eq!(message, "Failed to post summary, webhook returned 404");
+        // webhook urls hold a secret
+        assert!(!message.contains("secret"));
+    }
+
+    #[test]
+    fn truncates_long_fields() {
+        let long = "<&>".repeat(5000);
+        let mut summary = summary();
+        summary.node.name = Some(format!("node-{long}"));
+        summary.node.version = Some(long.clone());
+        summary.hprof = PathBuf::from(format!("/dumps/{long}.hprof"));
+        summary.queries[0].method = Some(long.clone());
+        summary.queries[0].uri = Some(format!("/{long}/_search"));
+        summary.queries[0].body = preview(&long);
+        summary.classes = (0..100)
+            .map(|i| ClassUsage {
+                name: format!("com/example/{long}{i}"),
+                instances: i,
+                shallow_size: i * 1024,
+            })
+            .collect();
+        let payload = summary.slack_payload();
+
+        let header = payload["blocks"][0]["text"]["text"].as_str().unwrap();
+        assert_eq!(length(header), HEADER_LENGTH);
+        assert!(header.starts_with("Heap dump of node-<&>"));
+        assert!(header.ends_with("..."));
+        for text in section_texts(&payload) {
+            assert!(length(text) <= SECTION_LENGTH, "{}", length(text));
+            // nothing escaped is left half
+            let unescaped = text
+                .replace("&amp;", "")
+                .replace("&lt;", "")
+                .replace("&gt;", "");
+            assert!(!unescaped.contains(['&', '<', '>']), "{}", text);
+        }
+        let classes = *section_texts(&payload).last().unwrap();
+        assert!(classes.ends_with("```"));
+        assert!(classes.lines().count() > 5);
+    }
+
+    #[test]
+    fn escapes_within_length() {
+        assert_eq!(escape("a<b>&c", 100), "a&lt;b&gt;&amp;c");
+        assert_eq!(escape("a<b>&c", 16), "a&lt;b&gt;&amp;c");
+        assert_eq!(escape("a<b>&c", 15), "a&lt;b&gt;...");
+        assert_eq!(escape("&&&&", 8), "&amp;...");
+        assert_eq!(escape("&&&&", 7), "...");
+        assert_eq!(escape("héllo wörld", 8), "héllo...");
+        assert_eq!(truncate("héllo", 5), "héllo");
+        assert_eq!(truncate("héllo wörld", 8), "héllo...");
+    }
+}
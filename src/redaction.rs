@@ -0,0 +1,640 @@
+//! Redaction of customer data in extracted queries.
+//!
+//! Rules are read from a YAML (or JSON) file:
+//!
+//! ```yaml
+//! salt: some-secret
+//! action: hash
+//! paths:
+//!   - $..email
+//!   - path: $.query.bool.must[*].match.message
+//!     action: mask
+//! patterns:
+//!   - '[\w.+-]+@[\w-]+(\.[\w-]+)+'
+//!   - regex: '\b\d{3}-\d{2}-\d{4}\b'
+//!     action: mask
+//! ```
+//!
+//! `paths` select values in JSON bodies with a subset of JSONPath: `.key`, `['key']`, `[n]`,
+//! wildcards `.*` and `[*]` and recursive descent `..key`. Keys also match inside arrays, so
+//! `$.query.terms.user` covers every user of a terms list. All literals below a selected value are
+//! redacted. `patterns` are regular expressions replacing matches in every remaining string, the
+//! uri and header values.
+//!
+//! `hash` replaces a value with a token derived from the salted SHA-256 of the value, equal values
+//! get equal tokens so queries can still be correlated. Leading and trailing wildcards are kept
+//! outside the token. `mask` replaces values with `[REDACTED]`.
+
+use std::path::Path;
+
+use anyhow::*;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::elasticsearch::InflightRequest;
+
+const MASK: &str = "[REDACTED]";
+/// Hex characters of the hash kept in tokens
+const TOKEN_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Hash,
+    Mask,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedactionRules {
+    #[serde(default)]
+    salt: String,
+    #[serde(default)]
+    action: Action,
+    #[serde(default)]
+    paths: Vec<PathRule>,
+    #[serde(default)]
+    patterns: Vec<PatternRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathRule {
+    Path(String),
+    WithAction { path: String, action: Action },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PatternRule {
+    Regex(String),
+    WithAction { regex: String, action: Action },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    selector: Selector,
+    /// Matches at any depth, `..`
+    descendant: bool,
+}
+
+pub struct Redactor {
+    salt: String,
+    paths: Vec<(Vec<Segment>, Action)>,
+    patterns: Vec<(Regex, Action)>,
+}
+
+impl Redactor {
+    /// Reads rules from a YAML or JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        let rules = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read redaction rules at {path:?}"))?;
+        Self::parse(&rules).with_context(|| format!("Invalid redaction rules at {path:?}"))
+    }
+
+    pub fn parse(rules: &str) -> Result<Self> {
+        let rules: RedactionRules = serde_yaml::from_str(rules)?;
+        let default_action = rules.action;
+        let paths = rules
+            .paths
+            .into_iter()
+            .map(|rule| match rule {
+                PathRule::Path(path) => Ok((parse_path(&path)?, default_action)),
+                PathRule::WithAction { path, action } => Ok((parse_path(&path)?, action)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let patterns = rules
+            .patterns
+            .into_iter()
+            .map(|rule| {
+                let (regex, action) = match rule {
+                    PatternRule::Regex(regex) => (regex, default_action),
+                    PatternRule::WithAction { regex, action } => (regex, action),
+                };
+                Regex::new(&regex)
+                    .map(|regex| (regex, action))
+                    .with_context(|| format!("Invalid pattern {regex:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if rules.salt.is_empty() && !(paths.is_empty() && patterns.is_empty()) {
+            log::warn!(
+                "Redaction rules have no salt, hashed tokens of short values can be guessed"
+            );
+        }
+        Ok(Self {
+            salt: rules.salt,
+            paths,
+            patterns,
+        })
+    }
+
+    /// Redacts body, uri and header values of a request
+    pub fn redact_request(&self, request: &mut InflightRequest) {
+        request.body = self.redact_body(&request.body);
+        request.uri = request.uri.as_deref().map(|uri| self.redact_text(uri));
+        for (_, value) in &mut request.headers {
+            *value = self.redact_text(value);
+        }
+    }
+
+    /// Redacts a pretty printed JSON body, newline delimited bodies line by line and other
+    /// bodies with patterns only
+    pub fn redact_body(&self, body: &str) -> String {
+        if let Result::Ok(mut json) = serde_json::from_str::<Value>(body) {
+            self.redact_value(&mut json);
+            return serde_json::to_string_pretty(&json).unwrap_or_default();
+        }
+        let mut redacted = body
+            .lines()
+            .map(|line| match serde_json::from_str::<Value>(line) {
+                Result::Ok(mut json) => {
+                    self.redact_value(&mut json);
+                    json.to_string()
+                }
+                Err(_) => self.redact_text(line),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if body.ends_with('\n') {
+            redacted.push('\n');
+        }
+        redacted
+    }
+
+    pub fn redact_value(&self, value: &mut Value) {
+        for (path, action) in &self.paths {
+            self.redact_path(value, path, *action);
+        }
+        if !self.patterns.is_empty() {
+            self.redact_strings(value);
+        }
+    }
+
+    /// Replaces pattern matches
+    pub fn redact_text(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (regex, action) in &self.patterns {
+            if regex.is_match(&text) {
+                text = regex
+                    .replace_all(&text, |captures: &Captures<'_>| {
+                        self.replacement(&captures[0], *action)
+                    })
+                    .into_owned();
+            }
+        }
+        text
+    }
+
+    fn redact_path(&self, value: &mut Value, path: &[Segment], action: Action) {
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return self.redact_all(value, action),
+        };
+        if segment.descendant {
+            // elements of arrays are visited below, matching keys through the array would
+            // redact them twice
+            if !value.is_array() {
+                let mut here = path.to_vec();
+                here[0].descendant = false;
+                self.redact_path(value, &here, action);
+            }
+            match value {
+                Value::Object(object) => object
+                    .values_mut()
+                    .for_each(|child| self.redact_path(child, path, action)),
+                Value::Array(values) => values
+                    .iter_mut()
+                    .for_each(|child| self.redact_path(child, path, action)),
+                _ => {}
+            }
+            return;
+        }
+        match (&segment.selector, value) {
+            (Selector::Key(key), Value::Object(object)) => {
+                if let Some(child) = object.get_mut(key) {
+                    self.redact_path(child, rest, action);
+                }
+            }
+            // keys apply to every element, arrays are mostly lists of clauses or values
+            (Selector::Key(_), Value::Array(values)) => values
+                .iter_mut()
+                .for_each(|child| self.redact_path(child, path, action)),
+            (Selector::Index(index), Value::Array(values)) => {
+                if let Some(child) = values.get_mut(*index) {
+                    self.redact_path(child, rest, action);
+                }
+            }
+            (Selector::Wildcard, Value::Object(object)) => object
+                .values_mut()
+                .for_each(|child| self.redact_path(child, rest, action)),
+            (Selector::Wildcard, Value::Array(values)) => values
+                .iter_mut()
+                .for_each(|child| self.redact_path(child, rest, action)),
+            _ => {}
+        }
+    }
+
+    /// Redacts every literal of a value, `null` and booleans carry no data and are kept
+    fn redact_all(&self, value: &mut Value, action: Action) {
+        match value {
+            Value::String(text) => *text = self.replacement(text, action),
+            Value::Number(number) => {
+                *value = Value::String(self.replacement(&number.to_string(), action))
+            }
+            Value::Array(values) => values
+                .iter_mut()
+                .for_each(|value| self.redact_all(value, action)),
+            Value::Object(object) => object
+                .values_mut()
+                .for_each(|value| self.redact_all(value, action)),
+            Value::Null | Value::Bool(_) => {}
+        }
+    }
+
+    fn redact_strings(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.redact_text(text),
+            Value::Array(values) => values
+                .iter_mut()
+                .for_each(|value| self.redact_strings(value)),
+            Value::Object(object) => object
+                .values_mut()
+                .for_each(|value| self.redact_strings(value)),
+            _ => {}
+        }
+    }
+
+    fn replacement(&self, text: &str, action: Action) -> String {
+        match action {
+            Action::Mask => MASK.to_string(),
+            Action::Hash => {
+                let wildcard = |c: char| c == '*' || c == '?';
+                let inner = text.trim_matches(wildcard);
+                if inner.is_empty() {
+                    return text.to_string();
+                }
+                let start = text.len() - text.trim_start_matches(wildcard).len();
+                let end = start + inner.len();
+                format!("{}{}{}", &text[..start], self.token(inner), &text[end..])
+            }
+        }
+    }
+
+    /// `tok_` followed by the start of the salted hash
+    fn token(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        let hash = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        format!("tok_{}", &hash[..TOKEN_LENGTH])
+    }
+}
+
+/// Parses the JSONPath subset described in the module documentation
+fn parse_path(path: &str) -> Result<Vec<Segment>> {
+    let mut rest = match path.strip_prefix('$') {
+        Some(rest) => rest,
+        None => bail!("Path {path:?} must start with $"),
+    };
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        let (descendant, after_dots) = if let Some(after) = rest.strip_prefix("..") {
+            (true, after)
+        } else if let Some(after) = rest.strip_prefix('.') {
+            (false, after)
+        } else {
+            (false, rest)
+        };
+        let selector = if let Some(bracket) = after_dots.strip_prefix('[') {
+            let end = match bracket.find(']') {
+                Some(end) => end,
+                None => bail!("Unclosed [ in path {path:?}"),
+            };
+            let inner = &bracket[..end];
+            rest = &bracket[end + 1..];
+            let quoted = ['\'', '"'].iter().find_map(|quote| {
+                inner
+                    .strip_prefix(*quote)
+                    .and_then(|key| key.strip_suffix(*quote))
+            });
+            match (inner, quoted) {
+                ("*", _) => Selector::Wildcard,
+                (_, Some(key)) => Selector::Key(key.to_string()),
+                _ => match inner.parse() {
+                    Result::Ok(index) => Selector::Index(index),
+                    Err(_) => bail!("Invalid selector [{inner}] in path {path:?}"),
+                },
+            }
+        } else if after_dots.len() < rest.len() {
+            let end = after_dots.find(['.', '[']).unwrap_or(after_dots.len());
+            let name = &after_dots[..end];
+            rest = &after_dots[end..];
+            match name {
+                "" => bail!("Empty key in path {path:?}"),
+                "*" => Selector::Wildcard,
+                name => Selector::Key(name.to_string()),
+            }
+        } else {
+            bail!("Unexpected {rest:?} in path {path:?}");
+        };
+        segments.push(Segment {
+            selector,
+            descendant,
+        });
+    }
+    if segments.is_empty() {
+        bail!("Path {path:?} selects the whole body, use a pattern instead");
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::elasticsearch::RequestState;
+    use crate::hprof::ObjectId;
+
+    fn redactor(rules: &str) -> Redactor {
+        Redactor::parse(rules).unwrap()
+    }
+
+    fn redacted(rules: &str, mut value: Value) -> Value {
+        redactor(rules).redact_value(&mut value);
+        value
+    }
+
+    fn key(key: &str, descendant: bool) -> Segment {
+        Segment {
+            selector: Selector::Key(key.to_string()),
+            descendant,
+        }
+    }
+
+    fn selector(selector: Selector) -> Segment {
+        Segment {
+            selector,
+            descendant: false,
+        }
+    }
+
+    #[test]
+    fn parses_paths() {
+        let cases = [
+            ("$.a", vec![key("a", false)]),
+            ("$..email", vec![key("email", true)]),
+            ("$[0]", vec![selector(Selector::Index(0))]),
+            (
+                "$.a[*]",
+                vec![key("a", false), selector(Selector::Wildcard)],
+            ),
+            (
+                "$.a.*.b",
+                vec![
+                    key("a", false),
+                    selector(Selector::Wildcard),
+                    key("b", false),
+                ],
+            ),
+            ("$['a.b'][\"c\"]", vec![key("a.b", false), key("c", false)]),
+            (
+                "$.a..b[2]",
+                vec![
+                    key("a", false),
+                    key("b", true),
+                    selector(Selector::Index(2)),
+                ],
+            ),
+            (
+                "$..[1]",
+                vec![Segment {
+                    selector: Selector::Index(1),
+                    descendant: true,
+                }],
+            ),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(parse_path(path).unwrap(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        for path in [
+            "a.b", "$", "$a", "$.", "$.a..", "$.a[", "$.a[x]", "$.a[-1]", "$.a[0]b",
+        ] {
+            assert!(parse_path(path).is_err(), "{}", path);
+        }
+        assert!(Redactor::parse("paths: ['$.a[']").is_err());
+        assert!(Redactor::parse("patterns: ['(']").is_err());
+        assert!(Redactor::parse("unknown: 1").is_err());
+    }
+
+    #[test]
+    fn redacts_descendants() {
+        let value = redacted(
+            "{action: mask, paths: ['$..email']}",
+            json!({
+                "email": "a",
+                "user": {"email": "b", "name": "c"},
+                "users": [{"email": "d"}, {"email": {"work": "e", "home": ["f"]}}],
+            }),
+        );
+        assert_eq!(
+            value,
+            json!({
+                "email": MASK,
+                "user": {"email": MASK, "name": "c"},
+                "users": [{"email": MASK}, {"email": {"work": MASK, "home": [MASK]}}],
+            })
+        );
+    }
+
+    #[test]
+    fn redacts_array_elements() {
+        let query = json!({"query": {"bool": {"must": [
+            {"match": {"message": "a"}},
+            {"match": {"message": "b"}},
+            {"term": {"message": "c"}},
+        ]}}});
+        let messages = |value: Value| {
+            value["query"]["bool"]["must"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|clause| {
+                    clause.as_object().unwrap().values().next().unwrap()["message"].clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        let cases = [
+            // keys apply to every element
+            (
+                "$.query.bool.must.match.message",
+                ["[REDACTED]", "[REDACTED]", "c"],
+            ),
+            (
+                "$.query.bool.must[*].match.message",
+                ["[REDACTED]", "[REDACTED]", "c"],
+            ),
+            (
+                "$.query.bool.must[1].match.message",
+                ["a", "[REDACTED]", "c"],
+            ),
+            ("$.query.bool.must[5].match.message", ["a", "b", "c"]),
+            (
+                "$.query.bool.must[*].*.message",
+                ["[REDACTED]", "[REDACTED]", "[REDACTED]"],
+            ),
+        ];
+        for (path, expected) in cases {
+            let rules = format!("{{action: mask, paths: ['{path}']}}");
+            assert_eq!(
+                messages(redacted(&rules, query.clone())),
+                expected,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn redacts_every_literal_below_selected_value() {
+        let value = redacted(
+            "{action: mask, paths: ['$.filter']}",
+            json!({"filter": {"range": {"age": {"gte": 42, "lt": 1.5}}, "exists": true, "missing": null}, "size": 10}),
+        );
+        assert_eq!(
+            value,
+            json!({"filter": {"range": {"age": {"gte": MASK, "lt": MASK}}, "exists": true, "missing": null}, "size": 10})
+        );
+    }
+
+    #[test]
+    fn hash_tokens_are_stable() {
+        let rules = "{salt: s, paths: ['$..user']}";
+        let value = redacted(
+            rules,
+            json!({"user": "alice", "users": [{"user": "alice"}, {"user": "bob"}], "id": {"user": 42}}),
+        );
+        assert_eq!(value["user"], "tok_e6cf28e16f55");
+        assert_eq!(value["users"][0]["user"], value["user"]);
+        assert_ne!(value["users"][1]["user"], value["user"]);
+        // numbers are hashed by their text
+        assert_eq!(value["id"]["user"], "tok_004f83c5cde6");
+
+        // tokens depend on the salt only, not on the redactor or the action of other rules
+        assert_eq!(
+            redactor(rules).token("alice"),
+            redactor("salt: s").token("alice")
+        );
+        assert_eq!(redactor("salt: ''").token("alice"), "tok_1255dacaa637");
+    }
+
+    #[test]
+    fn hash_keeps_wildcards() {
+        let redactor = redactor("salt: s");
+        assert_eq!(
+            redactor.replacement("*alice*", Action::Hash),
+            "*tok_e6cf28e16f55*"
+        );
+        assert_eq!(
+            redactor.replacement("alice?", Action::Hash),
+            "tok_e6cf28e16f55?"
+        );
+        assert_eq!(redactor.replacement("*", Action::Hash), "*");
+        assert_eq!(redactor.replacement("*alice*", Action::Mask), MASK);
+    }
+
+    #[test]
+    fn redacts_body_by_format() {
+        let redactor = redactor(
+            r#"
+salt: s
+paths:
+  - $..email
+patterns:
+  - regex: '[\w.+-]+@[\w-]+(\.[\w-]+)+'
+    action: mask
+"#,
+        );
+
+        // pretty printed JSON, paths take precedence over patterns
+        let body = redactor.redact_body(r#"{"email":"a@b.c","note":"from x@y.z"}"#);
+        assert_eq!(
+            body,
+            "{\n  \"email\": \"tok_6e1d967b22d9\",\n  \"note\": \"from [REDACTED]\"\n}"
+        );
+
+        // NDJSON line by line, lines that aren't JSON with patterns
+        let body = redactor.redact_body(
+            "{\"index\":\"logs\"}\n{\"query\":{\"term\":{\"email\":\"a@b.c\"}}}\nnot json a@b.c\n",
+        );
+        assert_eq!(
+            body,
+            "{\"index\":\"logs\"}\n{\"query\":{\"term\":{\"email\":\"tok_6e1d967b22d9\"}}}\nnot json [REDACTED]\n"
+        );
+
+        // text with patterns only
+        assert_eq!(
+            redactor.redact_body("email=a@b.c, other=x@y.z"),
+            "email=[REDACTED], other=[REDACTED]"
+        );
+        assert_eq!(redactor.redact_body(""), "");
+    }
+
+    #[test]
+    fn redacts_request() {
+        let redactor =
+            redactor(r#"{salt: s, paths: ['$..email'], patterns: ['[\w.+-]+@[\w-]+(\.[\w-]+)+']}"#);
+        let mut request = InflightRequest {
+            object_id: ObjectId::from(jvm_hprof::Id::from(1)),
+            class_name: "org.elasticsearch.http.netty4.Netty4HttpRequest".to_string(),
+            state: RequestState::Pending,
+            ref_count: None,
+            method: Some("POST".to_string()),
+            uri: Some("/logs/_search?q=email:a@b.c".to_string()),
+            headers: vec![
+                ("X-Opaque-Id".to_string(), "a@b.c".to_string()),
+                ("Content-Type".to_string(), "application/json".to_string()),
+            ],
+            remote_address: None,
+            body: r#"{"query":{"term":{"email":"x"}}}"#.to_string(),
+            body_length: 31,
+            body_unavailable: None,
+            body_encodings: Vec::new(),
+            expected_body_length: None,
+        };
+        redactor.redact_request(&mut request);
+
+        assert_eq!(
+            request.uri.as_deref(),
+            Some("/logs/_search?q=email:tok_6e1d967b22d9")
+        );
+        assert_eq!(
+            request.headers,
+            [
+                ("X-Opaque-Id".to_string(), "tok_6e1d967b22d9".to_string()),
+                ("Content-Type".to_string(), "application/json".to_string()),
+            ]
+        );
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["query"]["term"]["email"], redactor.token("x"));
+        assert_eq!(request.method.as_deref(), Some("POST"));
+    }
+}
@@ -0,0 +1,538 @@
+//! Replay of saved inflight queries against a cluster.
+//!
+//! Reads the `query_N.json` bodies and `query_N.meta.json` request details saved by
+//! `inflight-queries --save` and sends them with the recovered method and uri. Requests are sent
+//! by a pool of workers sharing a rate limit, each reply is recorded with its status and latency.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Saved request ready to be sent
+pub struct ReplayRequest {
+    /// Name of the saved files, `query_N`
+    pub name: String,
+    pub method: String,
+    pub uri: String,
+    pub body: Option<String>,
+}
+
+/// Outcome of a single replayed request
+#[derive(Serialize)]
+pub struct ReplayResult {
+    pub name: String,
+    pub method: String,
+    pub uri: String,
+    /// HTTP status, `None` when no response was received
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    pub response_bytes: u64,
+    pub error: Option<String>,
+}
+
+pub struct ReplayOptions {
+    /// Base url of the cluster, e.g. `http://localhost:9200`
+    pub target: String,
+    pub concurrency: usize,
+    /// Maximum requests per second across all workers, unlimited when `None`
+    pub rate: Option<f64>,
+    /// Times every request is sent
+    pub repeat: usize,
+    pub timeout: Duration,
+    /// Extra headers, e.g. credentials, the saved ones are redacted
+    pub headers: Vec<(String, String)>,
+}
+
+/// Endpoints that only read when called with `POST`, e.g. `/logs/_search`
+const READ_ENDPOINTS: &[&str] = &[
+    "_search",
+    "_msearch",
+    "_count",
+    "_explain",
+    "_validate",
+    "_field_caps",
+    "_mget",
+    "_termvectors",
+    "_mtermvectors",
+    "_rank_eval",
+];
+
+/// Whether the request only reads. `GET` and `HEAD` requests do, `POST` requests do when sent to
+/// one of `READ_ENDPOINTS`, anything else may change the cluster, e.g. `_bulk`, `_update_by_query`
+/// or index creation
+fn is_read_only(method: &str, uri: &str) -> bool {
+    match method.to_ascii_uppercase().as_str() {
+        "GET" | "HEAD" => true,
+        "POST" => {
+            let path = uri.split('?').next().unwrap_or_default();
+            // index names can't start with `_`, the first such segment is the endpoint
+            path.split('/')
+                .find(|segment| segment.starts_with('_') && *segment != "_all")
+                .is_some_and(|endpoint| READ_ENDPOINTS.contains(&endpoint))
+        }
+        _ => false,
+    }
+}
+
+/// Loads saved requests in the order they were extracted. Requests without a method or uri, with a
+/// body that could not be read or still being received when the dump was taken are skipped, as
+/// are requests that may write unless `allow_writes` is set
+pub fn load_requests(dir: &Path, allow_writes: bool) -> Result<Vec<ReplayRequest>> {
+    let mut metadata_files = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {dir:?}"))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let id = file_name
+                .strip_prefix("query_")?
+                .strip_suffix(".meta.json")?
+                .parse::<usize>()
+                .ok()?;
+            Some((id, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    metadata_files.sort_unstable();
+
+    let mut requests = Vec::new();
+    for (id, metadata_file) in metadata_files {
+        let name = format!("query_{id}");
+        let metadata = std::fs::read_to_string(&metadata_file)
+            .with_context(|| format!("Failed to read {metadata_file:?}"))?;
+        let metadata: Value = serde_json::from_str(&metadata)
+            .with_context(|| format!("Failed to parse {metadata_file:?}"))?;
+        let (method, uri) = match (
+            metadata.get("method").and_then(Value::as_str),
+            metadata.get("uri").and_then(Value::as_str),
+        ) {
+            (Some(method), Some(uri)) => (method.to_string(), uri.to_string()),
+            _ => {
+                log::warn!("Skipping {name}, method or uri was not recovered");
+                continue;
+            }
+        };
+        if !allow_writes && !is_read_only(&method, &uri) {
+            log::warn!("Skipping {name}, {method} {uri} may write, see --allow-writes");
+            continue;
+        }
+        if let Some(reason) = metadata.get("body_unavailable").and_then(Value::as_str) {
+            log::warn!("Skipping {name}, body unavailable: {reason}");
+            continue;
+        }
+        if metadata.get("incomplete") == Some(&Value::Bool(true)) {
+            log::warn!("Skipping {name}, body was still being received");
+            continue;
+        }
+        let mut body_file = dir.to_path_buf();
+        body_file.push(format!("{name}.json"));
+        let body = if body_file.exists() {
+            Some(
+                std::fs::read_to_string(&body_file)
+                    .with_context(|| format!("Failed to read {body_file:?}"))?,
+            )
+        } else {
+            None
+        };
+        requests.push(ReplayRequest {
+            name,
+            method,
+            uri,
+            body: body.filter(|body| !body.is_empty()),
+        });
+    }
+    Ok(requests)
+}
+
+/// Spaces out request starts, shared by all workers
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until the next request is allowed to start
+    fn acquire(&self) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now);
+            *next = start + self.interval;
+            start - now
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Sends all requests `repeat` times, results are returned in completion order
+pub fn replay(requests: &[ReplayRequest], opts: &ReplayOptions) -> Result<Vec<ReplayResult>> {
+    if opts.concurrency == 0 {
+        bail!("Concurrency must be at least 1");
+    }
+    let limiter = match opts.rate {
+        Some(rate) if rate > 0.0 && rate.is_finite() => Some(RateLimiter::new(rate)),
+        Some(rate) => bail!("Invalid rate {rate}, expected requests per second above 0"),
+        None => None,
+    };
+    let agent = ureq::AgentBuilder::new().timeout(opts.timeout).build();
+    let target = opts.target.trim_end_matches('/');
+    let queue = Mutex::new(
+        (0..opts.repeat)
+            .flat_map(|_| requests.iter())
+            .collect::<VecDeque<_>>(),
+    );
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..opts.concurrency {
+            scope.spawn(|| loop {
+                let request = match queue.lock().unwrap().pop_front() {
+                    Some(request) => request,
+                    None => break,
+                };
+                if let Some(limiter) = &limiter {
+                    limiter.acquire();
+                }
+                let result = send(&agent, target, request, &opts.headers);
+                match (&result.status, &result.error) {
+                    (_, Some(error)) => log::warn!("{} failed: {error}", result.name),
+                    (Some(status), None) => log::debug!(
+                        "{} returned {status} in {} ms",
+                        result.name,
+                        result.latency_ms
+                    ),
+                    (None, None) => {}
+                }
+                results.lock().unwrap().push(result);
+            });
+        }
+    });
+    Ok(results.into_inner().unwrap())
+}
+
+fn send(
+    agent: &ureq::Agent,
+    target: &str,
+    request: &ReplayRequest,
+    headers: &[(String, String)],
+) -> ReplayResult {
+    let uri = if request.uri.starts_with('/') {
+        request.uri.clone()
+    } else {
+        format!("/{}", request.uri)
+    };
+    let mut http_request = agent
+        .request(&request.method, &format!("{target}{uri}"))
+        .set(
+            "X-Opaque-Id",
+            &format!("elasticsearch-hprof-replay-{}", request.name),
+        );
+    for (name, value) in headers {
+        http_request = http_request.set(name, value);
+    }
+    let start = Instant::now();
+    let response = match &request.body {
+        Some(body) => {
+            // bodies are saved as pretty JSON, newline delimited bodies are left as they were
+            let content_type = if serde_json::from_str::<Value>(body).is_ok() {
+                "application/json"
+            } else {
+                "application/x-ndjson"
+            };
+            http_request
+                .set("Content-Type", content_type)
+                .send_string(body)
+        }
+        None => http_request.call(),
+    };
+    let (status, response, error) = match response {
+        Result::Ok(response) => (Some(response.status()), Some(response), None),
+        Err(ureq::Error::Status(status, response)) => (Some(status), Some(response), None),
+        Err(ureq::Error::Transport(transport)) => (None, None, Some(transport.to_string())),
+    };
+    let mut response_bytes = 0;
+    let mut error = error;
+    if let Some(response) = response {
+        match std::io::copy(&mut response.into_reader(), &mut std::io::sink()) {
+            Result::Ok(bytes) => response_bytes = bytes,
+            Err(err) => error = Some(format!("Failed to read response: {err}")),
+        }
+    }
+    ReplayResult {
+        name: request.name.clone(),
+        method: request.method.clone(),
+        uri,
+        status,
+        latency_ms: start.elapsed().as_millis() as u64,
+        response_bytes,
+        error,
+    }
+}
+
+/// Latency percentile of replies, nearest rank
+pub fn percentile(results: &[ReplayResult], percentile: f64) -> Option<u64> {
+    let mut latencies = results
+        .iter()
+        .filter(|result| result.status.is_some())
+        .map(|result| result.latency_ms)
+        .collect::<Vec<_>>();
+    if latencies.is_empty() {
+        return None;
+    }
+    latencies.sort_unstable();
+    let rank = ((percentile / 100.0) * latencies.len() as f64).ceil() as usize;
+    Some(latencies[rank.clamp(1, latencies.len()) - 1])
+}
+
+/// File the results are saved to, next to the replayed requests
+pub fn results_file(dir: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push("replay.json");
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Request line and body received by the mock server
+    type Received = (String, String);
+
+    /// Answers `count` requests with `status`, one connection each, and reports what it received
+    fn mock_server(count: usize, status: u16) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let response = "{\"took\":1}";
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+                sender
+                    .send((
+                        request_line.trim().to_string(),
+                        String::from_utf8(body).unwrap(),
+                    ))
+                    .unwrap();
+            }
+        });
+        (address, receiver)
+    }
+
+    fn options(target: String) -> ReplayOptions {
+        ReplayOptions {
+            target,
+            concurrency: 2,
+            rate: None,
+            repeat: 1,
+            timeout: Duration::from_secs(5),
+            headers: Vec::new(),
+        }
+    }
+
+    fn request(name: &str, method: &str, uri: &str, body: Option<&str>) -> ReplayRequest {
+        ReplayRequest {
+            name: name.to_string(),
+            method: method.to_string(),
+            uri: uri.to_string(),
+            body: body.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn sends_method_uri_and_body() {
+        let (target, received) = mock_server(2, 200);
+        let requests = [
+            request(
+                "query_0",
+                "POST",
+                "/logs/_search",
+                Some("{\"query\":{\"match_all\":{}}}"),
+            ),
+            request("query_1", "GET", "/_cat/indices", None),
+        ];
+        let results = replay(&requests, &options(target)).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|result| result.status == Some(200) && result.response_bytes == 10));
+
+        let mut received = received.iter().take(2).collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received[0].0, "GET /_cat/indices HTTP/1.1");
+        assert_eq!(received[0].1, "");
+        assert_eq!(received[1].0, "POST /logs/_search HTTP/1.1");
+        assert_eq!(received[1].1, "{\"query\":{\"match_all\":{}}}");
+    }
+
+    #[test]
+    fn records_error_status() {
+        let (target, _received) = mock_server(1, 503);
+        let requests = [request("query_0", "POST", "/_search", Some("{}"))];
+        let results = replay(&requests, &options(target)).unwrap();
+        assert_eq!(results[0].status, Some(503));
+        assert!(results[0].error.is_none());
+    }
+
+    #[test]
+    fn records_connection_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let requests = [request("query_0", "GET", "/", None)];
+        let results = replay(&requests, &options(target)).unwrap();
+        assert_eq!(results[0].status, None);
+        assert!(results[0].error.is_some());
+    }
+
+    #[test]
+    fn limits_rate_across_workers() {
+        let (target, _received) = mock_server(6, 200);
+        let requests = [request("query_0", "GET", "/", None)];
+        let opts = ReplayOptions {
+            concurrency: 3,
+            rate: Some(20.0),
+            repeat: 6,
+            ..options(target)
+        };
+        let start = Instant::now();
+        let results = replay(&requests, &opts).unwrap();
+        assert_eq!(results.len(), 6);
+        // the first request starts immediately, the other five 50 ms apart
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn loads_saved_requests() {
+        let dir = std::env::temp_dir().join(format!("replay-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "query_0.meta.json",
+                r#"{"method":"POST","uri":"/a/_search"}"#,
+            ),
+            ("query_0.json", "{}"),
+            (
+                "query_1.meta.json",
+                r#"{"method":"GET","uri":"/_cat/indices"}"#,
+            ),
+            (
+                "query_2.meta.json",
+                r#"{"method":"POST","uri":"/b/_search","body_unavailable":"off heap"}"#,
+            ),
+            (
+                "query_10.meta.json",
+                r#"{"method":"POST","uri":"/c/_search","incomplete":true}"#,
+            ),
+            ("query_3.meta.json", r#"{"uri":"/d/_search"}"#),
+            ("query_4.meta.json", r#"{"method":"PUT","uri":"/e/_doc/1"}"#),
+            ("query_4.json", "{\"a\":1}"),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        let loaded = |allow_writes| {
+            load_requests(&dir, allow_writes)
+                .unwrap()
+                .into_iter()
+                .map(|request| (request.name, request.body))
+                .collect::<Vec<_>>()
+        };
+        let read_only = loaded(false);
+        let all = loaded(true);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let query_0 = ("query_0".to_string(), Some("{}".to_string()));
+        let query_1 = ("query_1".to_string(), None);
+        let query_4 = ("query_4".to_string(), Some("{\"a\":1}".to_string()));
+        assert_eq!(read_only, [query_0.clone(), query_1.clone()]);
+        assert_eq!(all, [query_0, query_1, query_4]);
+    }
+
+    #[test]
+    fn detects_read_only_requests() {
+        let cases = [
+            ("GET", "/logs/_doc/1", true),
+            ("HEAD", "/logs", true),
+            ("get", "/_cat/indices?v", true),
+            ("POST", "/logs/_search", true),
+            ("POST", "/logs,metrics/_search?routing=a", true),
+            ("POST", "/_all/_search/template", true),
+            ("POST", "/_msearch", true),
+            ("POST", "/logs/_count", true),
+            ("POST", "/logs/_explain/1", true),
+            ("POST", "/logs/_validate/query", true),
+            ("POST", "/_search/scroll", true),
+            ("POST", "/_bulk", false),
+            ("POST", "/logs/_doc", false),
+            ("POST", "/logs/_update/1", false),
+            ("POST", "/logs/_delete_by_query", false),
+            ("POST", "/logs/_update_by_query?q=_search", false),
+            ("POST", "/logs/_refresh", false),
+            ("PUT", "/logs/_doc/1", false),
+            ("PUT", "/logs", false),
+            ("DELETE", "/logs", false),
+            ("DELETE", "/_search/scroll", false),
+        ];
+        for (method, uri, expected) in cases {
+            assert_eq!(is_read_only(method, uri), expected, "{method} {uri}");
+        }
+    }
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let results = (1..=10)
+            .map(|latency| ReplayResult {
+                name: String::new(),
+                method: String::new(),
+                uri: String::new(),
+                status: Some(200),
+                latency_ms: latency,
+                response_bytes: 0,
+                error: None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(percentile(&results, 50.0), Some(5));
+        assert_eq!(percentile(&results, 99.0), Some(10));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}
@@ -0,0 +1,423 @@
+//! Writes the part of a heap dump reachable from a few root objects as a dump of its own.
+//!
+//! The slice holds the reachable objects, the classes they need up to `java.lang.Object` and the
+//! names of those classes and their fields, small enough to attach to a bug report and open in
+//! Eclipse MAT. Roots are marked as GC roots so viewers don't discard the slice as garbage, static
+//! fields and class loaders pointing outside of it are cleared. Stack traces, threads and
+//! original GC roots are left out.
+
+use std::io::Write;
+
+use ahash::AHashSet;
+use anyhow::*;
+use jvm_hprof::heap_dump::{FieldValue, SubRecord};
+use jvm_hprof::{parse_hprof, HprofWriter, Id, RecordTag};
+
+use crate::hprof::{ClassId, JavaProfile, Object, ObjectId};
+
+/// Viewers look this class up to recognize class objects
+const CLASS_CLASS: &str = "java/lang/Class";
+
+/// Objects, classes and names making up a slice
+pub struct Selection {
+    roots: Vec<ObjectId>,
+    objects: AHashSet<ObjectId>,
+    classes: AHashSet<ObjectId>,
+    names: AHashSet<Id>,
+}
+
+impl Selection {
+    /// Selects everything reachable from `roots`
+    pub fn new(profile: &JavaProfile, roots: Vec<ObjectId>) -> Self {
+        let objects = profile.reachable_from(&roots);
+        let mut classes = AHashSet::new();
+        let mut pending = objects
+            .iter()
+            .map(|id| match profile.get_object(id) {
+                Some(Object::Instance(instance)) => instance.class_id(),
+                Some(Object::Array(array)) => array.class_id(),
+                _ => ClassId::from(*id),
+            })
+            .chain(
+                profile
+                    .get_class_by_name(CLASS_CLASS)
+                    .map(|class| class.id()),
+            )
+            .collect::<Vec<_>>();
+        let mut names = AHashSet::new();
+        while let Some(class_id) = pending.pop() {
+            let class = match profile.get_class_by_id(&class_id) {
+                Some(class) => class,
+                None => continue,
+            };
+            if !classes.insert(ObjectId::from(class_id)) {
+                continue;
+            }
+            names.extend(class.name_ids(profile));
+            pending.extend(class.parent_class());
+        }
+        Self {
+            roots,
+            objects,
+            classes,
+            names,
+        }
+    }
+
+    /// Number of objects in the slice, the classes they need not counted
+    pub fn objects(&self) -> usize {
+        self.objects.len()
+    }
+
+    fn keeps(&self, id: Option<Id>) -> Option<Id> {
+        id.filter(|id| {
+            let id = ObjectId::from(*id);
+            self.objects.contains(&id) || self.classes.contains(&id)
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct SliceStats {
+    pub objects: u64,
+    pub classes: u64,
+    pub names: u64,
+}
+
+/// Writes the dump of the objects in `selection` from the dump in `input`
+pub fn slice<W: Write>(input: &[u8], output: W, selection: &Selection) -> Result<SliceStats> {
+    let hprof = parse_hprof(input).map_err(|err| anyhow!("Failed to parse hprof: {:?}", err))?;
+    let header = hprof.header();
+    let mut writer = HprofWriter::new(
+        output,
+        header.label().unwrap_or("JAVA PROFILE 1.0.2"),
+        header.id_size(),
+        header.timestamp_millis(),
+    )?;
+    let mut stats = SliceStats::default();
+    let mut roots_written = false;
+    for record in hprof.records_iter() {
+        let record = record.map_err(|err| anyhow!("Failed to parse record: {:?}", err))?;
+        let micros = record.micros_since_header_ts();
+        match record.tag() {
+            RecordTag::Utf8 => {
+                let utf8 = record
+                    .as_utf_8()
+                    .context("Utf8 record expected")?
+                    .map_err(|err| anyhow!("Failed to parse Utf8 record: {:?}", err))?;
+                if selection.names.contains(&utf8.name_id()) {
+                    writer.write_record(RecordTag::Utf8, micros, record.body())?;
+                    stats.names += 1;
+                }
+            }
+            RecordTag::LoadClass => {
+                let load_class = record
+                    .as_load_class()
+                    .context("LoadClass record expected")?
+                    .map_err(|err| anyhow!("Failed to parse LoadClass record: {:?}", err))?;
+                if selection
+                    .classes
+                    .contains(&load_class.class_obj_id().into())
+                {
+                    writer.write_record(RecordTag::LoadClass, micros, record.body())?;
+                }
+            }
+            RecordTag::HeapDump | RecordTag::HeapDumpSegment => {
+                let segment = record
+                    .as_heap_dump_segment()
+                    .context("Heap dump record expected")?
+                    .map_err(|err| anyhow!("Failed to parse heap dump segment: {:?}", err))?;
+                let mut segment_writer = writer.buffered_heap_dump_segment(record.tag(), micros)?;
+                let mut written = 0;
+                if !roots_written {
+                    for root in &selection.roots {
+                        segment_writer.write_gc_root_unknown(Id::from(*root))?;
+                        written += 1;
+                    }
+                    roots_written = true;
+                }
+                for sub_record in segment.sub_records().with_bytes() {
+                    let (sub_record, bytes) = sub_record
+                        .map_err(|err| anyhow!("Failed to parse sub record: {:?}", err))?;
+                    let object_id = match &sub_record {
+                        SubRecord::Class(class) => {
+                            if !selection.classes.contains(&class.obj_id().into()) {
+                                continue;
+                            }
+                            let static_fields = class
+                                .static_fields()
+                                .map(|field| {
+                                    let field = field.map_err(|err| {
+                                        anyhow!("Failed to parse static field: {:?}", err)
+                                    })?;
+                                    let value = match field.value() {
+                                        FieldValue::ObjectId(id) => {
+                                            FieldValue::ObjectId(selection.keeps(id))
+                                        }
+                                        value => value,
+                                    };
+                                    Ok((field.name_id(), value))
+                                })
+                                .collect::<Result<Vec<_>>>()?;
+                            let instance_fields = class
+                                .instance_field_descriptors()
+                                .map(|field| {
+                                    field
+                                        .map(|field| (field.name_id(), field.field_type()))
+                                        .map_err(|err| {
+                                            anyhow!("Failed to parse instance field: {:?}", err)
+                                        })
+                                })
+                                .collect::<Result<Vec<_>>>()?;
+                            segment_writer.write_class(
+                                class.obj_id(),
+                                class.stack_trace_serial(),
+                                class.super_class_obj_id(),
+                                selection.keeps(class.class_loader_obj_id()),
+                                selection.keeps(class.signers_obj_id()),
+                                selection.keeps(class.protection_domain_obj_id()),
+                                class.instance_size_bytes(),
+                                &static_fields,
+                                &instance_fields,
+                            )?;
+                            stats.classes += 1;
+                            written += 1;
+                            continue;
+                        }
+                        SubRecord::Instance(instance) => instance.obj_id(),
+                        SubRecord::ObjectArray(array) => array.obj_id(),
+                        SubRecord::PrimitiveArray(array) => array.obj_id(),
+                        // original roots would refer to threads and objects left out
+                        _ => continue,
+                    };
+                    if selection.objects.contains(&object_id.into()) {
+                        segment_writer.write_raw(bytes)?;
+                        stats.objects += 1;
+                        written += 1;
+                    }
+                }
+                if written > 0 {
+                    segment_writer.finish()?;
+                }
+            }
+            RecordTag::HeapDumpEnd => writer.write_record(RecordTag::HeapDumpEnd, micros, &[])?,
+            _ => {}
+        }
+    }
+    writer.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashMap;
+    use jvm_hprof::heap_dump::FieldType;
+
+    use super::*;
+    use crate::hprof::fixture::HeapBuilder;
+    use crate::hprof::JavaInstance;
+
+    struct SlicedClass {
+        class_loader: Option<Id>,
+        protection_domain: Option<Id>,
+        statics: Vec<(String, FieldValue)>,
+    }
+
+    /// Records of a sliced dump
+    #[derive(Default)]
+    struct Sliced {
+        names: AHashMap<Id, String>,
+        classes: AHashMap<String, SlicedClass>,
+        /// Ids of instances and arrays
+        objects: Vec<ObjectId>,
+        gc_roots: Vec<Id>,
+    }
+
+    fn read(dump: &[u8]) -> Sliced {
+        let hprof = parse_hprof(dump).unwrap();
+        let mut sliced = Sliced::default();
+        let mut class_names = AHashMap::new();
+        for record in hprof.records_iter() {
+            let record = record.unwrap();
+            match record.tag() {
+                RecordTag::Utf8 => {
+                    let utf8 = record.as_utf_8().unwrap().unwrap();
+                    let text = utf8.text_as_str().unwrap().to_string();
+                    sliced.names.insert(utf8.name_id(), text);
+                }
+                RecordTag::LoadClass => {
+                    let load_class = record.as_load_class().unwrap().unwrap();
+                    class_names.insert(load_class.class_obj_id(), load_class.class_name_id());
+                }
+                RecordTag::HeapDumpSegment => {
+                    let segment = record.as_heap_dump_segment().unwrap().unwrap();
+                    for sub_record in segment.sub_records() {
+                        match sub_record.unwrap() {
+                            SubRecord::GcRootUnknown(root) => sliced.gc_roots.push(root.obj_id()),
+                            SubRecord::Class(class) => {
+                                let statics = class
+                                    .static_fields()
+                                    .map(|field| field.unwrap())
+                                    .map(|field| {
+                                        (sliced.names[&field.name_id()].clone(), field.value())
+                                    })
+                                    .collect();
+                                let name = sliced.names[&class_names[&class.obj_id()]].clone();
+                                sliced.classes.insert(
+                                    name,
+                                    SlicedClass {
+                                        class_loader: class.class_loader_obj_id(),
+                                        protection_domain: class.protection_domain_obj_id(),
+                                        statics,
+                                    },
+                                );
+                            }
+                            SubRecord::Instance(instance) => {
+                                sliced.objects.push(instance.obj_id().into())
+                            }
+                            SubRecord::ObjectArray(array) => {
+                                sliced.objects.push(array.obj_id().into())
+                            }
+                            SubRecord::PrimitiveArray(array) => {
+                                sliced.objects.push(array.obj_id().into())
+                            }
+                            sub_record => panic!("Unexpected sub record {:?}", sub_record),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        sliced
+    }
+
+    #[test]
+    fn writes_reachable_objects() {
+        let mut heap = HeapBuilder::new();
+        heap.class(
+            "com/example/Base",
+            None,
+            &[("payload", FieldType::ObjectId)],
+        );
+        heap.class(
+            "com/example/Request",
+            Some("com/example/Base"),
+            &[("next", FieldType::ObjectId)],
+        );
+        heap.class("com/example/Loader", None, &[]);
+        heap.class("com/example/Unrelated", None, &[]);
+        let body = heap.byte_array(b"body");
+        let tail = heap.instance(
+            "com/example/Request",
+            &[("payload", FieldValue::ObjectId(Some(body)))],
+        );
+        let root = heap.instance(
+            "com/example/Request",
+            &[("next", FieldValue::ObjectId(Some(tail)))],
+        );
+        let loader = heap.instance("com/example/Loader", &[]);
+        let domain = heap.instance("com/example/Unrelated", &[]);
+        let cached = heap.instance("com/example/Unrelated", &[]);
+        heap.static_field(
+            "com/example/Request",
+            "LAST",
+            FieldValue::ObjectId(Some(root)),
+        );
+        heap.static_field(
+            "com/example/Request",
+            "CACHE",
+            FieldValue::ObjectId(Some(cached)),
+        );
+        heap.static_field("com/example/Request", "COUNT", FieldValue::Int(2));
+        heap.class_loader("com/example/Request", loader);
+        heap.protection_domain("com/example/Request", domain);
+        heap.gc_root(loader);
+        let dump = heap.build();
+        let mut profile = JavaProfile::new(&dump);
+        profile.process();
+
+        let selection = Selection::new(&profile, vec![root.into()]);
+        assert_eq!(selection.objects(), 3);
+        let mut output = Vec::new();
+        let stats = slice(&dump, &mut output, &selection).unwrap();
+        let sliced = read(&output);
+
+        // only reachable objects and their class chain
+        let mut objects = sliced.objects.clone();
+        objects.sort();
+        let mut expected = vec![root.into(), tail.into(), body.into()];
+        expected.sort();
+        assert_eq!(objects, expected);
+        let mut classes = sliced.classes.keys().cloned().collect::<Vec<_>>();
+        classes.sort();
+        assert_eq!(
+            classes,
+            [
+                "com/example/Base",
+                "com/example/Request",
+                "java/lang/Object"
+            ]
+        );
+        let names = sliced
+            .names
+            .values()
+            .map(String::as_str)
+            .collect::<AHashSet<_>>();
+        for name in [
+            "com/example/Request",
+            "payload",
+            "next",
+            "LAST",
+            "CACHE",
+            "COUNT",
+        ] {
+            assert!(names.contains(name), "{}", name);
+        }
+        assert!(!names.contains("com/example/Loader"));
+        assert!(!names.contains("com/example/Unrelated"));
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.classes, 3);
+        assert_eq!(stats.names, sliced.names.len() as u64);
+
+        // references leaving the slice are cleared
+        let request = &sliced.classes["com/example/Request"];
+        assert_eq!(request.class_loader, None);
+        assert_eq!(request.protection_domain, None);
+        let statics = request
+            .statics
+            .iter()
+            .map(|(name, value)| match value {
+                FieldValue::ObjectId(id) => (name.as_str(), id.map(|id| id.id())),
+                FieldValue::Int(value) => (name.as_str(), Some(*value as u64)),
+                value => panic!("Unexpected static {:?}", value),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statics,
+            [
+                ("LAST", Some(root.id())),
+                ("CACHE", None),
+                ("COUNT", Some(2))
+            ]
+        );
+
+        // roots of the slice replace the original ones
+        assert_eq!(sliced.gc_roots, [root]);
+
+        // the slice is a dump of its own
+        let mut sliced_profile = JavaProfile::new(&output);
+        sliced_profile.process();
+        let next = sliced_profile
+            .get_instance(&root.into())
+            .and_then(|root| {
+                root.fields(&sliced_profile)
+                    .value::<&JavaInstance>(&sliced_profile, "next")
+            })
+            .map(|next| next.id());
+        assert_eq!(next, Some(tail.into()));
+        assert_eq!(
+            sliced_profile.reachable_from(&[root.into()]),
+            profile.reachable_from(&[root.into()])
+        );
+    }
+}
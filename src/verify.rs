@@ -0,0 +1,463 @@
+//! Checks whether a heap dump was written completely.
+//!
+//! The JVM writes records one after the other, each with the length of its body in its header,
+//! and ends the dump with a `HeapDumpEnd` record. Walking the record headers tells how far the
+//! file is valid and whether it is complete without parsing any record, so it's cheap even for
+//! dumps of tens of gigabytes.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use jvm_hprof::RecordTag;
+use serde::Serialize;
+
+/// Tag, microseconds since the header timestamp and body length
+const RECORD_HEADER_SIZE: usize = 9;
+
+#[derive(Serialize)]
+pub struct Verification {
+    pub size: u64,
+    pub id_size: Option<u32>,
+    pub records: u64,
+    /// Number of records of each tag
+    pub record_counts: BTreeMap<String, u64>,
+    /// Offset right after the last complete record, where readers stop
+    pub last_valid_offset: u64,
+    pub heap_dump_end: bool,
+    pub complete: bool,
+    /// Why the file can't be read past `last_valid_offset`
+    pub problem: Option<String>,
+}
+
+/// Walks the records of the dump in `data`
+pub fn verify(data: &[u8]) -> Verification {
+    let mut verification = Verification {
+        size: data.len() as u64,
+        id_size: None,
+        records: 0,
+        record_counts: BTreeMap::new(),
+        last_valid_offset: 0,
+        heap_dump_end: false,
+        complete: false,
+        problem: None,
+    };
+    // https://github.com/openjdk/jdk/blob/08822b4e0526fe001c39fe08e241b849eddf481d/src/hotspot/share/services/heapDumper.cpp#L63
+    let label_end = match data.iter().position(|&b| b == 0) {
+        Some(label_end) => label_end,
+        None => {
+            verification.problem = Some("Header incomplete".to_string());
+            return verification;
+        }
+    };
+    let header_end = label_end + 1 + 4 + 8;
+    if data.len() < header_end {
+        verification.problem = Some("Header incomplete".to_string());
+        return verification;
+    }
+    let id_size = u32::from_be_bytes(data[label_end + 1..label_end + 5].try_into().unwrap());
+    verification.id_size = Some(id_size);
+    if id_size != 4 && id_size != 8 {
+        verification.problem = Some(format!("Unexpected id size {id_size}"));
+        return verification;
+    }
+
+    let mut offset = header_end;
+    verification.last_valid_offset = offset as u64;
+    let mut last_tag = None;
+    let mut segmented = false;
+    while offset < data.len() {
+        if data.len() - offset < RECORD_HEADER_SIZE {
+            verification.problem = Some(format!("Record header at offset {offset} incomplete"));
+            break;
+        }
+        let tag = match RecordTag::from_tag_byte(data[offset]) {
+            Some(tag) => tag,
+            None => {
+                verification.problem = Some(format!(
+                    "Unknown record tag {:#04X} at offset {offset}",
+                    data[offset]
+                ));
+                break;
+            }
+        };
+        let body_len = u32::from_be_bytes(
+            data[offset + 5..offset + RECORD_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let remaining = data.len() - offset - RECORD_HEADER_SIZE;
+        if body_len > remaining {
+            verification.problem = Some(format!(
+                "{tag:?} record at offset {offset} needs {body_len} bytes, {remaining} remaining"
+            ));
+            break;
+        }
+        offset += RECORD_HEADER_SIZE + body_len;
+        verification.records += 1;
+        *verification
+            .record_counts
+            .entry(format!("{tag:?}"))
+            .or_insert(0) += 1;
+        verification.last_valid_offset = offset as u64;
+        segmented |= tag == RecordTag::HeapDumpSegment;
+        last_tag = Some(tag);
+    }
+
+    verification.heap_dump_end = last_tag == Some(RecordTag::HeapDumpEnd);
+    // dumps written as a single `HeapDump` record, by old JVMs, have no end record
+    let unsegmented_dump = !segmented && last_tag == Some(RecordTag::HeapDump);
+    verification.complete =
+        verification.problem.is_none() && (verification.heap_dump_end || unsegmented_dump);
+    verification
+}
+
+/// Verifies the dump at `path`, which may still be being written
+pub fn verify_file(path: &Path) -> Result<Verification> {
+    let file = File::open(path).with_context(|| format!("Failed to open file at {path:?}"))?;
+    let size = file
+        .metadata()
+        .with_context(|| format!("Failed to access file at {path:?}"))?
+        .len();
+    // empty files can't be mapped
+    if size == 0 {
+        return Ok(verify(&[]));
+    }
+    let memmap = unsafe { memmap::MmapOptions::new().map(&file) }.context("Failed to mmap file")?;
+    Ok(verify(&memmap))
+}
+
+pub struct WaitOptions {
+    /// Interval between checks
+    pub interval: Duration,
+    /// How long an incomplete dump may stop growing before it's considered truncated
+    pub stall_timeout: Duration,
+    /// How long to wait for a dump that doesn't grow, e.g. that is still empty, at most. Time the
+    /// dump grows doesn't count, a dump written slowly is waited for as long as it grows
+    pub timeout: Duration,
+}
+
+/// Error of [`wait_for_dump`] when the dump was not complete in time
+#[derive(Debug)]
+pub struct WaitTimeout {
+    pub path: PathBuf,
+    pub size: u64,
+    pub timeout: Duration,
+}
+
+impl Display for WaitTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Timeout after {:?} waiting for {:?} to be written, {} bytes so far",
+            self.timeout, self.path, self.size
+        )
+    }
+}
+
+impl std::error::Error for WaitTimeout {}
+
+/// Waits until the dump at `path` is complete.
+///
+/// The last verification is returned incomplete when the dump stopped growing for
+/// `stall_timeout`, e.g. the JVM was killed while writing it. Fails with [`WaitTimeout`] when the
+/// dump stayed empty or didn't grow for `timeout`, however long it grew before.
+pub fn wait_for_dump(path: &Path, opts: &WaitOptions) -> Result<Verification> {
+    let mut last_change = Instant::now();
+    // unlike `last_change` not taken from the file, the timeout starts with the wait
+    let mut idle_since = Instant::now();
+    let mut last_size = None;
+    loop {
+        let verification = verify_file(path)?;
+        if verification.complete {
+            return Ok(verification);
+        }
+        if last_size != Some(verification.size) {
+            idle_since = Instant::now();
+            last_change = match last_size {
+                Some(last_size) => {
+                    log::info!(
+                        "File size changed: {} -> {} bytes",
+                        last_size,
+                        verification.size
+                    );
+                    Instant::now()
+                }
+                None => {
+                    // a dump truncated long ago, e.g. by the JVM being killed, is read right away
+                    let last_modified = last_modified(path);
+                    if last_modified.elapsed() < opts.stall_timeout {
+                        log::info!(
+                            "File at {path:?} is incomplete, waiting for it to be written..."
+                        );
+                    }
+                    last_modified
+                }
+            };
+            last_size = Some(verification.size);
+        }
+        // an empty file is not stalled, the JVM may not have started writing it
+        if verification.size > 0 && last_change.elapsed() >= opts.stall_timeout {
+            return Ok(verification);
+        }
+        if idle_since.elapsed() >= opts.timeout {
+            return Err(WaitTimeout {
+                path: path.to_path_buf(),
+                size: verification.size,
+                timeout: opts.timeout,
+            }
+            .into());
+        }
+        thread::sleep(opts.interval);
+    }
+}
+
+/// When the file was last written, now when that's unknown or in the future
+fn last_modified(path: &Path) -> Instant {
+    let now = Instant::now();
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .and_then(|elapsed| now.checked_sub(elapsed))
+        .unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use jvm_hprof::{HprofWriter, IdSize};
+
+    use super::*;
+
+    /// Label, id size and timestamp
+    const HEADER_SIZE: usize = 18 + 1 + 4 + 8;
+    /// Offset of the heap dump record with 64 bit ids, after the header and a Utf8 record
+    const HEAP_DUMP_OFFSET: usize = HEADER_SIZE + RECORD_HEADER_SIZE + 8 + 4;
+    /// Offset right after the heap dump record, holding a single root with 64 bit ids
+    const HEAP_DUMP_END_OFFSET: usize = HEAP_DUMP_OFFSET + RECORD_HEADER_SIZE + 1 + 8;
+
+    /// A dump with a name and a heap dump record of `tag` holding a root, followed by the end
+    /// record when `end` is set
+    fn dump(tag: RecordTag, end: bool) -> Vec<u8> {
+        let mut writer =
+            HprofWriter::new(Vec::new(), "JAVA PROFILE 1.0.2", IdSize::U64, 0).unwrap();
+        writer.write_utf8(0, 1.into(), b"name").unwrap();
+        let mut segment = writer.buffered_heap_dump_segment(tag, 0).unwrap();
+        segment.write_gc_root_unknown(0x10.into()).unwrap();
+        segment.finish().unwrap();
+        if end {
+            writer.write_heap_dump_end(0).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    fn counts(verification: &Verification) -> Vec<(&str, u64)> {
+        verification
+            .record_counts
+            .iter()
+            .map(|(tag, count)| (tag.as_str(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_complete_dump() {
+        let data = dump(RecordTag::HeapDumpSegment, true);
+        let verification = verify(&data);
+        assert_eq!(verification.size, data.len() as u64);
+        assert_eq!(verification.id_size, Some(8));
+        assert_eq!(verification.records, 3);
+        assert_eq!(
+            counts(&verification),
+            [("HeapDumpEnd", 1), ("HeapDumpSegment", 1), ("Utf8", 1)]
+        );
+        assert_eq!(
+            verification.last_valid_offset,
+            (HEAP_DUMP_END_OFFSET + RECORD_HEADER_SIZE) as u64
+        );
+        assert_eq!(verification.last_valid_offset, data.len() as u64);
+        assert!(verification.heap_dump_end);
+        assert!(verification.complete);
+        assert_eq!(verification.problem, None);
+    }
+
+    #[test]
+    fn accepts_legacy_heap_dump() {
+        // a single `HeapDump` record without end record
+        let data = dump(RecordTag::HeapDump, false);
+        let verification = verify(&data);
+        assert_eq!(counts(&verification), [("HeapDump", 1), ("Utf8", 1)]);
+        assert!(!verification.heap_dump_end);
+        assert!(verification.complete);
+        assert_eq!(verification.problem, None);
+    }
+
+    #[test]
+    fn detects_missing_end() {
+        let data = dump(RecordTag::HeapDumpSegment, false);
+        let verification = verify(&data);
+        assert_eq!(verification.records, 2);
+        assert_eq!(verification.last_valid_offset, data.len() as u64);
+        assert!(!verification.heap_dump_end);
+        assert!(!verification.complete);
+        assert_eq!(verification.problem, None);
+    }
+
+    #[test]
+    fn detects_truncated_record_body() {
+        let data = dump(RecordTag::HeapDumpSegment, true);
+        let verification = verify(&data[..HEAP_DUMP_END_OFFSET - 3]);
+        assert_eq!(verification.records, 1);
+        assert_eq!(verification.last_valid_offset, HEAP_DUMP_OFFSET as u64);
+        assert!(!verification.complete);
+        assert_eq!(
+            verification.problem.as_deref(),
+            Some("HeapDumpSegment record at offset 52 needs 9 bytes, 6 remaining")
+        );
+    }
+
+    #[test]
+    fn detects_partial_record_header() {
+        let data = dump(RecordTag::HeapDumpSegment, true);
+        let verification = verify(&data[..HEAP_DUMP_END_OFFSET + 4]);
+        assert_eq!(verification.records, 2);
+        assert_eq!(verification.last_valid_offset, HEAP_DUMP_END_OFFSET as u64);
+        assert!(!verification.heap_dump_end);
+        assert!(!verification.complete);
+        assert_eq!(
+            verification.problem.as_deref(),
+            Some("Record header at offset 70 incomplete")
+        );
+    }
+
+    #[test]
+    fn detects_unknown_tag() {
+        let mut data = dump(RecordTag::HeapDumpSegment, false);
+        data.extend_from_slice(&[0x7f, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let verification = verify(&data);
+        assert_eq!(verification.records, 2);
+        assert_eq!(verification.last_valid_offset, HEAP_DUMP_END_OFFSET as u64);
+        assert!(!verification.complete);
+        assert_eq!(
+            verification.problem.as_deref(),
+            Some("Unknown record tag 0x7F at offset 70")
+        );
+    }
+
+    #[test]
+    fn detects_bad_header() {
+        let data = dump(RecordTag::HeapDumpSegment, true);
+        let mut bad_id_size = data.clone();
+        bad_id_size[19..23].copy_from_slice(&2u32.to_be_bytes());
+        let verification = verify(&bad_id_size);
+        assert_eq!(verification.id_size, Some(2));
+        assert_eq!(verification.records, 0);
+        assert_eq!(verification.last_valid_offset, 0);
+        assert!(!verification.complete);
+        assert_eq!(
+            verification.problem.as_deref(),
+            Some("Unexpected id size 2")
+        );
+
+        for data in [&data[..HEADER_SIZE - 1], &data[..10], &[][..]] {
+            let verification = verify(data);
+            assert_eq!(verification.id_size, None);
+            assert!(!verification.complete);
+            assert_eq!(verification.problem.as_deref(), Some("Header incomplete"));
+        }
+
+        // a header without records is not a complete dump
+        let verification = verify(&data[..HEADER_SIZE]);
+        assert_eq!(verification.last_valid_offset, HEADER_SIZE as u64);
+        assert!(!verification.complete);
+        assert_eq!(verification.problem, None);
+    }
+
+    #[test]
+    fn returns_stale_dump_at_once() {
+        let path = std::env::temp_dir().join(format!("verify-stale-{}.hprof", std::process::id()));
+        let start = Instant::now();
+        std::fs::write(&path, dump(RecordTag::HeapDumpSegment, false)).unwrap();
+        let opts = WaitOptions {
+            interval: Duration::from_millis(10),
+            stall_timeout: Duration::from_millis(200),
+            timeout: Duration::from_secs(60),
+        };
+
+        // written just now, waits for the stall timeout
+        let verification = wait_for_dump(&path, &opts).unwrap();
+        assert!(!verification.complete);
+        // the stall timeout counts from the modification time, which has a coarse resolution
+        assert!(start.elapsed() + Duration::from_millis(50) >= opts.stall_timeout);
+
+        // untouched for longer than the stall timeout
+        let modified = std::time::SystemTime::now() - Duration::from_secs(120);
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+        let opts = WaitOptions {
+            stall_timeout: Duration::from_secs(60),
+            ..opts
+        };
+        let start = Instant::now();
+        let verification = wait_for_dump(&path, &opts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!verification.complete);
+        assert!(start.elapsed() < opts.stall_timeout);
+    }
+
+    #[test]
+    fn waits_while_dump_grows() {
+        let path =
+            std::env::temp_dir().join(format!("verify-growing-{}.hprof", std::process::id()));
+        let data = dump(RecordTag::HeapDumpSegment, true);
+        std::fs::write(&path, &data[..8]).unwrap();
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut file = File::options().append(true).open(&path).unwrap();
+                for chunk in data[8..].chunks(4) {
+                    thread::sleep(Duration::from_millis(20));
+                    std::io::Write::write_all(&mut file, chunk).unwrap();
+                }
+            })
+        };
+        // the dump takes longer than the timeout to be written, but never stops growing
+        let opts = WaitOptions {
+            interval: Duration::from_millis(5),
+            stall_timeout: Duration::from_secs(5),
+            timeout: Duration::from_millis(100),
+        };
+        let verification = wait_for_dump(&path, &opts);
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(verification.unwrap().complete);
+    }
+
+    #[test]
+    fn times_out_on_idle_dump() {
+        let path = std::env::temp_dir().join(format!("verify-idle-{}.hprof", std::process::id()));
+        let opts = WaitOptions {
+            interval: Duration::from_millis(5),
+            stall_timeout: Duration::from_secs(5),
+            timeout: Duration::from_millis(50),
+        };
+        let incomplete = dump(RecordTag::HeapDumpSegment, false);
+        for data in [&[][..], &incomplete[..]] {
+            std::fs::write(&path, data).unwrap();
+            let err = match wait_for_dump(&path, &opts) {
+                Err(err) => err,
+                Result::Ok(_) => panic!("Idle dump of {} bytes was read", data.len()),
+            };
+            let timeout = err.downcast_ref::<WaitTimeout>().unwrap();
+            assert_eq!(timeout.size, data.len() as u64);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
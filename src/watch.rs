@@ -0,0 +1,434 @@
+//! Watches a directory for heap dumps and writes a report next to each of them.
+//!
+//! Meant to run next to nodes started with `-XX:+HeapDumpOnOutOfMemoryError`, pointed at their
+//! `-XX:HeapDumpPath`. A dump is read once the JVM finished writing it and it ends with the
+//! `HeapDumpEnd` record. Dumps without a report are picked up on start too,
+//! so dumps written while the watcher was down still get one. A summary of each reported dump can
+//! be sent to the incident channel, see [`crate::notify`].
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use ahash::AHashSet;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+use crate::elasticsearch::ElasticsearchMemory;
+use crate::notify::{self, NotifyOptions};
+use crate::redaction::Redactor;
+use crate::verify::{self, WaitOptions, WaitTimeout};
+
+/// Outputs of other subcommands, also `.hprof` files
+const OUTPUT_SUFFIXES: [&str; 2] = [".anonymized.hprof", ".slice.hprof"];
+/// Directory listing interval where inotify is not available
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Analysis included in the report, named after the subcommand doing the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Extractor {
+    InflightQueries,
+    SearchContexts,
+    ClusterState,
+    Caches,
+    Segments,
+    ThreadPools,
+    TransportRequests,
+}
+
+impl Extractor {
+    pub const ALL: [Extractor; 7] = [
+        Extractor::InflightQueries,
+        Extractor::SearchContexts,
+        Extractor::ClusterState,
+        Extractor::Caches,
+        Extractor::Segments,
+        Extractor::ThreadPools,
+        Extractor::TransportRequests,
+    ];
+
+    /// Key of the results in the report
+    fn key(&self) -> &'static str {
+        match self {
+            Extractor::InflightQueries => "inflight_queries",
+            Extractor::SearchContexts => "search_contexts",
+            Extractor::ClusterState => "cluster_state",
+            Extractor::Caches => "caches",
+            Extractor::Segments => "segments",
+            Extractor::ThreadPools => "thread_pools",
+            Extractor::TransportRequests => "transport_requests",
+        }
+    }
+
+    fn extract(&self, elastic: &ElasticsearchMemory, redactor: Option<&Redactor>) -> Result<Value> {
+        let value = match self {
+            Extractor::InflightQueries => {
+                let mut queries = elastic.read_inflight_queries(false);
+                if let Some(redactor) = redactor {
+                    for query in &mut queries {
+                        redactor.redact_request(query);
+                    }
+                }
+                Value::Array(
+                    queries
+                        .iter()
+                        .enumerate()
+                        .map(|(i, query)| query.to_json(i))
+                        .collect(),
+                )
+            }
+            Extractor::SearchContexts => {
+                let mut contexts = elastic.read_search_contexts();
+                contexts.sort_by_key(|c| std::cmp::Reverse(c.retained_size));
+                serde_json::to_value(contexts)?
+            }
+            Extractor::ClusterState => serde_json::to_value(elastic.read_cluster_state())?,
+            Extractor::Caches => serde_json::to_value(elastic.read_caches())?,
+            Extractor::Segments => {
+                let mut shards = elastic.read_segment_memory();
+                shards.sort_by(|a, b| a.shard.cmp(&b.shard));
+                serde_json::to_value(shards)?
+            }
+            Extractor::ThreadPools => {
+                let mut pools = elastic.read_thread_pools();
+                if let Some(redactor) = redactor {
+                    for task in pools.iter_mut().flat_map(|pool| pool.queued.iter_mut()) {
+                        if let Some(query) = &mut task.query {
+                            redactor.redact_value(query);
+                        }
+                    }
+                }
+                serde_json::to_value(pools)?
+            }
+            Extractor::TransportRequests => {
+                serde_json::to_value(elastic.read_pending_transport_requests())?
+            }
+        };
+        Ok(value)
+    }
+}
+
+pub struct WatchOptions {
+    pub extractors: Vec<Extractor>,
+    pub wait: WaitOptions,
+    pub redactor: Option<Redactor>,
+    pub notify: NotifyOptions,
+}
+
+/// Reports dumps showing up in `dir` until stopped
+pub fn watch(dir: &Path, opts: &WatchOptions) -> Result<()> {
+    let mut events = DirectoryEvents::new(dir)?;
+    // dumps that failed or were truncated, not retried until restarted
+    let mut skipped = AHashSet::new();
+    // dumps still being written when the wait timed out, retried once they change
+    let mut waiting = AHashSet::new();
+    log::info!("Watching {dir:?} for heap dumps...");
+    loop {
+        for dump in pending_dumps(dir)? {
+            if skipped.contains(&dump) || waiting.contains(&dump) {
+                continue;
+            }
+            match report_dump(&dump, opts) {
+                Ok(Some(report)) => log::info!("Report of {dump:?} written to {report:?}"),
+                Ok(None) => {
+                    log::warn!("Dump {dump:?} is incomplete, skipping it");
+                    skipped.insert(dump);
+                }
+                Err(err) if err.is::<WaitTimeout>() => {
+                    log::warn!("{err:#}, checking it again once it changes");
+                    waiting.insert(dump);
+                }
+                Err(err) => {
+                    log::error!("Failed to report {dump:?}: {err:#}");
+                    skipped.insert(dump);
+                }
+            }
+        }
+        match events.wait()? {
+            Some(changed) => waiting.retain(|dump| !changed.contains(dump)),
+            None => waiting.clear(),
+        }
+    }
+}
+
+/// Location of the report of a dump, `<hprof_filename>.report.json`
+pub fn report_path(hprof: &Path) -> PathBuf {
+    let mut report = hprof.as_os_str().to_os_string();
+    report.push(".report.json");
+    PathBuf::from(report)
+}
+
+fn is_dump(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    name.ends_with(".hprof") && !OUTPUT_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Dumps in the directory without a report, oldest first
+fn pending_dumps(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dumps = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to list {dir:?}"))? {
+        let entry = entry.with_context(|| format!("Failed to list {dir:?}"))?;
+        let path = entry.path();
+        if is_dump(&path) && !report_path(&path).exists() {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            dumps.push((modified, path));
+        }
+    }
+    dumps.sort();
+    Ok(dumps.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Waits for the dump to be complete and writes its report, `None` when the dump is truncated.
+/// Fails with [`WaitTimeout`] when the dump is still being written after the wait timeout
+fn report_dump(hprof: &Path, opts: &WatchOptions) -> Result<Option<PathBuf>> {
+    log::info!("Found {hprof:?}, waiting for it to be written...");
+    if !verify::wait_for_dump(hprof, &opts.wait)?.complete {
+        return Ok(None);
+    }
+    let file = File::open(hprof).with_context(|| format!("Failed to open file at {hprof:?}"))?;
+    let memmap = unsafe { memmap::MmapOptions::new().map(&file) }.context("Failed to mmap file")?;
+    log::info!("Loading hprof file...");
+    let elastic = ElasticsearchMemory::new(&memmap);
+    let mut report = Map::new();
+    report.insert("hprof".to_string(), json!(hprof));
+    report.insert("hprof_size".to_string(), json!(memmap.len()));
+    for extractor in &opts.extractors {
+        log::info!("Extracting {}...", extractor.key());
+        report.insert(
+            extractor.key().to_string(),
+            extractor.extract(&elastic, opts.redactor.as_ref())?,
+        );
+    }
+
+    // written under another name first, a partial report would keep the dump from being retried
+    let path = report_path(hprof);
+    let mut partial = path.clone().into_os_string();
+    partial.push(".tmp");
+    std::fs::write(&partial, serde_json::to_string_pretty(&report)?)
+        .with_context(|| format!("Failed to write report to {partial:?}"))?;
+    std::fs::rename(&partial, &path)
+        .with_context(|| format!("Failed to write report to {path:?}"))?;
+
+    // the report is there, a failed notification doesn't get the dump retried
+    if opts.notify.is_enabled() {
+        let summary = notify::summarize(
+            hprof,
+            memmap.len() as u64,
+            &elastic,
+            opts.redactor.as_ref(),
+            opts.notify.top,
+        );
+        if let Err(err) = notify::notify(&summary, &opts.notify) {
+            log::error!("Failed to send summary of {hprof:?}: {err:#}");
+        }
+    }
+    Ok(Some(path))
+}
+
+/// Wakes the watcher up when dumps are created or written in the directory
+#[cfg(target_os = "linux")]
+struct DirectoryEvents {
+    dir: PathBuf,
+    inotify: inotify::Inotify,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectoryEvents {
+    fn new(dir: &Path) -> Result<Self> {
+        use inotify::{Inotify, WatchMask};
+
+        let inotify = Inotify::init().context("Failed to initialize inotify")?;
+        inotify
+            .watches()
+            .add(
+                dir,
+                WatchMask::CREATE | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+            )
+            .with_context(|| format!("Failed to watch {dir:?}"))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            inotify,
+            buffer: vec![0; 4096],
+        })
+    }
+
+    /// Blocks until a dump is created, written or moved into the directory, returns the dumps that
+    /// changed
+    fn wait(&mut self) -> Result<Option<AHashSet<PathBuf>>> {
+        let dir = &self.dir;
+        loop {
+            let events = self
+                .inotify
+                .read_events_blocking(&mut self.buffer)
+                .context("Failed to read inotify events")?;
+            let changed = events
+                .filter_map(|event| event.name)
+                .filter(|name| is_dump(Path::new(name)))
+                .map(|name| dir.join(name))
+                .collect::<AHashSet<_>>();
+            if !changed.is_empty() {
+                return Ok(Some(changed));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct DirectoryEvents;
+
+#[cfg(not(target_os = "linux"))]
+impl DirectoryEvents {
+    fn new(_dir: &Path) -> Result<Self> {
+        Ok(Self)
+    }
+
+    /// Sleeps for the poll interval, which dumps changed is not known
+    fn wait(&mut self) -> Result<Option<AHashSet<PathBuf>>> {
+        std::thread::sleep(POLL_INTERVAL);
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::hprof::fixture::HeapBuilder;
+
+    /// Empty directory for a test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("watch-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// Writes a file last modified `age` ago
+        fn write(&self, name: &str, content: &[u8], age: Duration) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, content).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now() - age))
+                .unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn options(extractors: Vec<Extractor>) -> WatchOptions {
+        WatchOptions {
+            extractors,
+            wait: WaitOptions {
+                interval: Duration::from_millis(10),
+                stall_timeout: Duration::from_secs(1),
+                timeout: Duration::from_secs(5),
+            },
+            redactor: None,
+            notify: NotifyOptions {
+                url: None,
+                file: None,
+                top: 5,
+            },
+        }
+    }
+
+    fn heap_dump() -> Vec<u8> {
+        let mut heap = HeapBuilder::new();
+        heap.class("com/example/Node", None, &[]);
+        let node = heap.instance("com/example/Node", &[]);
+        heap.gc_root(node);
+        heap.build()
+    }
+
+    #[test]
+    fn recognizes_dumps() {
+        let cases = [
+            ("java_pid1.hprof", true),
+            ("dumps/java_pid1.hprof", true),
+            ("java_pid1.anonymized.hprof", false),
+            ("java_pid1.slice.hprof", false),
+            ("java_pid1.hprof.report.json", false),
+            ("java_pid1.hprof.report.json.tmp", false),
+            ("java_pid1.hprof.prof", false),
+            ("hprof", false),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(is_dump(Path::new(path)), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn lists_dumps_without_report_oldest_first() {
+        let dir = TempDir::new("pending");
+        let minute = Duration::from_secs(60);
+        let newer = dir.write("newer.hprof", b"", minute);
+        let older = dir.write("older.hprof", b"", 3 * minute);
+        dir.write("reported.hprof", b"", 2 * minute);
+        dir.write("reported.hprof.report.json", b"{}", 2 * minute);
+        dir.write("older.anonymized.hprof", b"", 4 * minute);
+        dir.write("older.slice.hprof", b"", 4 * minute);
+        dir.write("newer.hprof.report.json.tmp", b"", minute);
+
+        assert_eq!(pending_dumps(&dir.0).unwrap(), [older, newer]);
+    }
+
+    #[test]
+    fn writes_report_of_selected_extractors() {
+        let dir = TempDir::new("report");
+        let dump = heap_dump();
+        let hprof = dir.write("java_pid1.hprof", &dump, Duration::ZERO);
+        let opts = options(vec![Extractor::ThreadPools, Extractor::ClusterState]);
+
+        let report = report_dump(&hprof, &opts).unwrap();
+        assert_eq!(report, Some(report_path(&hprof)));
+        let report: Value =
+            serde_json::from_str(&std::fs::read_to_string(report_path(&hprof)).unwrap()).unwrap();
+        let mut keys = report
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            ["cluster_state", "hprof", "hprof_size", "thread_pools"]
+        );
+        assert_eq!(report["hprof"], json!(hprof));
+        assert_eq!(report["hprof_size"], dump.len());
+        assert!(pending_dumps(&dir.0).unwrap().is_empty());
+        // only the dump and its report are left
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn skips_truncated_dump() {
+        let dir = TempDir::new("truncated");
+        let mut dump = heap_dump();
+        // the `HeapDumpEnd` record, a tag, a timestamp and an empty body length
+        dump.truncate(dump.len() - 9);
+        assert!(!verify::verify(&dump).heap_dump_end);
+        // not written for longer than the stall timeout
+        let hprof = dir.write("java_pid1.hprof", &dump, Duration::from_secs(120));
+
+        let report = report_dump(&hprof, &options(Extractor::ALL.to_vec())).unwrap();
+        assert_eq!(report, None);
+        assert!(!report_path(&hprof).exists());
+    }
+}